@@ -2,36 +2,369 @@ extern crate bindgen;
 
 use glob::glob;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
 use std::io::Write;
 use std::path::PathBuf;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
-struct Constant {
-    name: String,
-    value: u64,
+struct CacheAlias {
+    level: String,
+    op: String,
+    result: String,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct Event {
     name: String,
-    encoding: String,
     desc: Option<String>,
     precise: Option<bool>,
+    // Bitmask of the general-purpose counters the event is restricted to
+    counters: Option<u64>,
+    // Generic cache counter this event implements on this arch
+    cache: Option<CacheAlias>,
+    // Encoding fields, each one must be declared in the arch layout
+    #[serde(flatten)]
+    fields: BTreeMap<String, u64>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct Arch {
     name: String,
-    constants: Vec<Constant>,
+    // ProcessorFamily variants served by this table
+    families: Vec<String>,
+    // Field name to bits in the sysfs format syntax, e.g. "config:0-7,32-35"
+    layout: BTreeMap<String, String>,
+    // perfmon JSON file with more events for this table. Only the events it
+    // lists are generated, the imported files carry subsets of the vendor
+    // tables and families without one keep their hand-written events
+    import: Option<String>,
+    // "core" or "atom" for the tables of one core type of a hybrid CPU
+    core_type: Option<String>,
+    #[serde(default)]
     events: Vec<Event>,
 }
 
+// Where a layout field lands in perf_event_attr: config, config1 or config2
+struct FieldLayout {
+    config: usize,
+    bits: Vec<(u32, u32)>,
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct ArchFile {
     arch: Arch,
 }
 
+// An event in the perfmon JSON format of Intel, which our own tables follow too
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PerfmonEvent {
+    event_name: Option<String>,
+    event_code: Option<String>,
+    #[serde(rename = "UMask")]
+    umask: Option<String>,
+    counter_mask: Option<String>,
+    invert: Option<String>,
+    edge_detect: Option<String>,
+    any_thread: Option<String>,
+    #[serde(rename = "PEBS")]
+    pebs: Option<String>,
+    counter: Option<String>,
+    #[serde(rename = "MSRIndex")]
+    msr_index: Option<String>,
+    #[serde(rename = "MSRValue")]
+    msr_value: Option<String>,
+    brief_description: Option<String>,
+    deprecated: Option<String>,
+}
+
+// Every imported file records where its events come from and under which
+// license, Intel's headers carry more fields that are ignored
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PerfmonHeader {
+    source: String,
+    license: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PerfmonFile {
+    header: PerfmonHeader,
+    events: Vec<PerfmonEvent>,
+}
+
+fn parse_perfmon_number(value: &Option<String>) -> u64 {
+    let value = match value {
+        Some(value) => value.trim(),
+        None => return 0,
+    };
+    // Offcore events list one code per counter, e.g. "0xB7, 0xBB"
+    let value = value.split(',').next().unwrap().trim();
+
+    let parsed = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse::<u64>(),
+    };
+    return parsed.unwrap_or_else(|_| panic!("Invalid perfmon number {}", value));
+}
+
+fn parse_layout(arch: &str, layout: &BTreeMap<String, String>) -> BTreeMap<String, FieldLayout> {
+    let mut parsed: BTreeMap<String, FieldLayout> = BTreeMap::new();
+
+    for (name, spec) in layout {
+        let (config, ranges) = match spec.split_once(':') {
+            Some(("config", ranges)) => (0, ranges),
+            Some(("config1", ranges)) => (1, ranges),
+            Some(("config2", ranges)) => (2, ranges),
+            _ => panic!("{}: invalid layout {} for field {}", arch, spec, name),
+        };
+
+        let mut bits = vec![];
+        for range in ranges.split(',') {
+            let (lo, hi) = match range.split_once('-') {
+                Some((lo, hi)) => (lo.trim().parse::<u32>(), hi.trim().parse::<u32>()),
+                None => (range.trim().parse::<u32>(), range.trim().parse::<u32>()),
+            };
+            let (lo, hi) = match (lo, hi) {
+                (Ok(lo), Ok(hi)) if lo <= hi && hi < 64 => (lo, hi),
+                _ => panic!("{}: invalid bit range {} for field {}", arch, range, name),
+            };
+            bits.push((lo, hi));
+        }
+
+        parsed.insert(name.clone(), FieldLayout { config, bits });
+    }
+
+    return parsed;
+}
+
+// Packs the fields of an event into (config, config1, config2)
+fn pack_fields(
+    arch: &str,
+    event: &str,
+    layout: &BTreeMap<String, FieldLayout>,
+    fields: &BTreeMap<String, u64>,
+) -> [u64; 3] {
+    let mut configs = [0u64; 3];
+
+    for (name, value) in fields {
+        let field = layout.get(name).unwrap_or_else(|| {
+            panic!(
+                "{}: {} uses field {} missing from the layout",
+                arch, event, name
+            )
+        });
+
+        let width: u32 = field.bits.iter().map(|(lo, hi)| hi - lo + 1).sum();
+        if width < 64 && value >> width != 0 {
+            panic!(
+                "{}: {} value {:#x} does not fit into {} bits of {}",
+                arch, event, value, width, name
+            );
+        }
+
+        let mut remaining = *value;
+        for (lo, hi) in &field.bits {
+            let width = hi - lo + 1;
+            let mask = if width == 64 {
+                u64::MAX
+            } else {
+                (1 << width) - 1
+            };
+            let bits = (remaining & mask) << lo;
+            if configs[field.config] & bits != 0 {
+                panic!(
+                    "{}: {} field {} overlaps with another field",
+                    arch, event, name
+                );
+            }
+            configs[field.config] |= bits;
+            remaining = if width == 64 { 0 } else { remaining >> width };
+        }
+    }
+
+    return configs;
+}
+
+// Imported events use the event, umask, edge, any, inv, cmask, ldlat,
+// offcore_rsp and frontend fields, which the importing table must declare in its layout
+fn perfmon_to_event(evt: &PerfmonEvent) -> Option<Event> {
+    // Metrics and other entries without an encoding
+    let name = evt.event_name.as_ref()?;
+    evt.event_code.as_ref()?;
+
+    if parse_perfmon_number(&evt.deprecated) != 0 {
+        return None;
+    }
+    // Fixed counters are reached through the architectural events instead
+    if let Some(counter) = &evt.counter {
+        if counter.trim().starts_with("Fixed") {
+            return None;
+        }
+    }
+
+    let mut fields = BTreeMap::new();
+    let mut add_field = |name: &str, value: u64| {
+        if value != 0 {
+            fields.insert(name.to_string(), value);
+        }
+    };
+    add_field("event", parse_perfmon_number(&evt.event_code));
+    add_field("umask", parse_perfmon_number(&evt.umask));
+    add_field("edge", parse_perfmon_number(&evt.edge_detect));
+    add_field("any", parse_perfmon_number(&evt.any_thread));
+    add_field("inv", parse_perfmon_number(&evt.invert));
+    add_field("cmask", parse_perfmon_number(&evt.counter_mask));
+
+    match parse_perfmon_number(&evt.msr_index) {
+        0 => {}
+        // PEBS load latency threshold
+        0x3f6 => add_field("ldlat", parse_perfmon_number(&evt.msr_value)),
+        // OFFCORE_RSP_0 and OFFCORE_RSP_1
+        0x1a6 | 0x1a7 => add_field("offcore_rsp", parse_perfmon_number(&evt.msr_value)),
+        // MSR_PEBS_FRONTEND
+        0x3f7 => add_field("frontend", parse_perfmon_number(&evt.msr_value)),
+        other => panic!("{}: unsupported MSR {:#x}", name, other),
+    }
+
+    return Some(Event {
+        name: name.clone(),
+        desc: evt.brief_description.clone(),
+        precise: Some(parse_perfmon_number(&evt.pebs) != 0),
+        counters: parse_perfmon_counters(name, &evt.counter),
+        cache: None,
+        fields,
+    });
+}
+
+// "0,1,2,3" to a bitmask of the counters, None when any counter will do
+fn parse_perfmon_counters(event: &str, counter: &Option<String>) -> Option<u64> {
+    let counter = counter.as_ref()?;
+
+    let mut mask = 0u64;
+    for index in counter.split(',').map(|c| c.trim()) {
+        let index = index
+            .parse::<u32>()
+            .ok()
+            .filter(|index| *index < 64)
+            .unwrap_or_else(|| panic!("{}: invalid counter {}", event, index));
+        mask |= 1 << index;
+    }
+
+    return Some(mask);
+}
+
+fn const_name(event_name: &str) -> String {
+    return event_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+}
+
+fn emit_arch(archs: &mut String, arch: &Arch) {
+    let layout = parse_layout(&arch.name, &arch.layout);
+    let kind = match arch.core_type.as_deref() {
+        None => "SystemCounterKind::Hardware",
+        Some("core") => "SystemCounterKind::Hybrid(crate::HybridCoreType::Core)",
+        Some("atom") => "SystemCounterKind::Hybrid(crate::HybridCoreType::Atom)",
+        Some(other) => panic!("{}: unknown core type {}", &arch.name, other),
+    };
+
+    archs.push_str(&format!("mod {} {{\n", &arch.name));
+    *archs += "  use crate::{SystemCounter, SystemCounterKind};\n";
+    let mut evt_names = vec![];
+    let mut cache_aliases = vec![];
+    for evt in &arch.events {
+        let const_name = const_name(&evt.name);
+        evt_names.push(const_name.clone());
+        if let Some(alias) = &evt.cache {
+            cache_aliases.push((cache_counter_expr(alias), const_name.clone()));
+        }
+        let desc = match &evt.desc {
+            Some(desc) => &desc,
+            None => "TBD",
+        };
+        if evt.fields.is_empty() {
+            panic!("{}: {} has no encoding fields", &arch.name, &evt.name);
+        }
+        let configs = pack_fields(&arch.name, &evt.name, &layout, &evt.fields);
+        archs.push_str(&format!(
+            "  const {}: SystemCounter = SystemCounter {{
+    kind: {},
+    name: std::borrow::Cow::Borrowed({:?}),
+    desc: std::borrow::Cow::Borrowed({:?}),
+    precise: {:?},
+    counters: {:?},
+    encoding: {:#x},
+    config1: {:#x},
+    config2: {:#x},
+  }};\n",
+            &const_name,
+            kind,
+            &evt.name,
+            &desc,
+            &evt.precise,
+            &evt.counters,
+            configs[0],
+            configs[1],
+            configs[2]
+        ));
+    }
+
+    *archs += "  pub(crate) fn get() -> Vec<crate::SystemCounter> {\n    vec![\n";
+    for name in evt_names {
+        archs.push_str(&format!("      {}.clone(),\n", &name));
+    }
+    *archs += "    ]\n  }\n";
+    *archs += "  pub(crate) fn get_cache_events() -> Vec<(crate::CacheCounter, crate::SystemCounter)> {\n    vec![\n";
+    for (cache, name) in cache_aliases {
+        archs.push_str(&format!("      ({}, {}.clone()),\n", &cache, &name));
+    }
+    *archs += "    ]\n  }\n";
+    *archs += "}\n";
+}
+
+fn cache_counter_expr(alias: &CacheAlias) -> String {
+    let level = match alias.level.as_str() {
+        "l1" => "L1",
+        "l1i" => "L1I",
+        "l1d" => "L1D",
+        "l2" => "L2",
+        "l3" => "L3",
+        "last" => "Last",
+        "dtlb" => "DTLB",
+        "itlb" => "ITLB",
+        "bpu" => "BPU",
+        "node" => "Node",
+        other => panic!("Unknown cache level {}", other),
+    };
+    let op = match alias.op.as_str() {
+        "read" => "Read",
+        "write" => "Write",
+        "prefetch" => "Prefetch",
+        other => panic!("Unknown cache op {}", other),
+    };
+    let result = match alias.result.as_str() {
+        // Accesses are hits and misses alike, never hits alone
+        "access" => "Access",
+        "miss" => "Miss",
+        other => panic!("Unknown cache result {}", other),
+    };
+
+    return format!(
+        "crate::CacheCounter {{ kind: crate::CacheCounterKind::{}, level: crate::CacheLevelKind::{}, op: crate::CacheOpKind::{} }}",
+        result, level, op
+    );
+}
+
 fn main() {
     let bindings = bindgen::Builder::default()
         .header("interop/cpp/include/pmu/pmu_enums.h")
@@ -46,44 +379,45 @@ fn main() {
 
     let mut archs: String = "".to_owned();
 
+    // ProcessorFamily variant name to the tables serving it, hybrid CPUs
+    // have one table per core type
+    let mut families: BTreeMap<String, Vec<(String, Option<String>)>> = BTreeMap::new();
+
     for entry in glob("src/events/**/*.yaml").expect("Failed to glob configs") {
         match entry {
             Ok(path) => {
                 println!("cargo:rerun-if-changed={}", path.display());
-                let file_txt = std::fs::read_to_string(path).expect("Failed to read path");
-                let arch_file: ArchFile =
+                let file_txt = std::fs::read_to_string(&path).expect("Failed to read path");
+                let mut arch_file: ArchFile =
                     serde_yaml::from_str(&file_txt).expect("Failed to parse yaml");
-                let arch = &arch_file.arch;
-                archs.push_str(&format!("mod {} {{\n", &arch.name));
-                archs += "  use crate::{SystemCounter, SystemCounterKind};\n";
-                for cst in &arch.constants {
-                    archs.push_str(&format!("  const {}: u64 = {};\n", &cst.name, cst.value));
-                }
-                let mut evt_names = vec![];
-                for evt in &arch.events {
-                    let const_name = evt.name.replace(".", "_");
-                    evt_names.push(const_name.clone());
-                    let desc = match &evt.desc {
-                        Some(desc) => &desc,
-                        None => "TBD",
-                    };
-                    archs.push_str(&format!(
-                        "  const {}: SystemCounter = SystemCounter {{
-    kind: SystemCounterKind::Hardware,
-    name: \"{}\",
-    desc: \"{}\",
-    encoding: {},
-  }};\n",
-                        &const_name, &evt.name, &desc, &evt.encoding
-                    ));
+                let arch = &mut arch_file.arch;
+
+                // perfmon JSON tables, the path is relative to the YAML file
+                if let Some(import) = &arch.import {
+                    let json_path = path.parent().unwrap().join(import);
+                    println!("cargo:rerun-if-changed={}", json_path.display());
+                    let json_txt =
+                        std::fs::read_to_string(&json_path).expect("Failed to read path");
+                    let perfmon_file: PerfmonFile = serde_json::from_str(&json_txt)
+                        .unwrap_or_else(|e| panic!("{}: {}", json_path.display(), e));
+                    arch.events
+                        .extend(perfmon_file.events.iter().filter_map(perfmon_to_event));
                 }
 
-                archs += "  pub(crate) fn get() -> Vec<crate::SystemCounter> {\n    vec![\n";
-                for name in evt_names {
-                    archs.push_str(&format!("      {}.clone(),\n", &name));
+                for family in &arch.families {
+                    let tables = families.entry(family.clone()).or_default();
+                    for (other, other_core_type) in tables.iter() {
+                        if arch.core_type.is_none()
+                            || other_core_type.is_none()
+                            || *other_core_type == arch.core_type
+                        {
+                            panic!("{} is mapped to both {} and {}", family, other, arch.name);
+                        }
+                    }
+                    tables.push((arch.name.clone(), arch.core_type.clone()));
                 }
-                archs += "    ]\n  }\n";
-                archs += "}\n";
+
+                emit_arch(&mut archs, arch);
             }
             Err(_) => {
                 panic!("Failed");
@@ -91,6 +425,34 @@ fn main() {
         };
     }
 
+    // No wildcard arm, so a ProcessorFamily without a table fails to compile
+    archs += "pub(crate) fn get_family_events(family: &ProcessorFamily) -> Vec<SystemCounter> {\n  match family {\n";
+    for (family, tables) in &families {
+        let getters: Vec<String> = tables
+            .iter()
+            .map(|(arch, _)| format!("{}::get()", arch))
+            .collect();
+        let getter = match getters.len() {
+            1 => getters[0].clone(),
+            _ => format!("[{}].concat()", getters.join(", ")),
+        };
+        archs.push_str(&format!("    ProcessorFamily::{} => {},\n", family, getter));
+    }
+    archs += "    ProcessorFamily::Unknown => vec![],\n  }\n}\n";
+    archs += "pub(crate) fn get_family_cache_events(family: &ProcessorFamily) -> Vec<(CacheCounter, SystemCounter)> {\n  match family {\n";
+    for (family, tables) in &families {
+        let getters: Vec<String> = tables
+            .iter()
+            .map(|(arch, _)| format!("{}::get_cache_events()", arch))
+            .collect();
+        let getter = match getters.len() {
+            1 => getters[0].clone(),
+            _ => format!("[{}].concat()", getters.join(", ")),
+        };
+        archs.push_str(&format!("    ProcessorFamily::{} => {},\n", family, getter));
+    }
+    archs += "    ProcessorFamily::Unknown => vec![],\n  }\n}\n";
+
     let mut arch_file =
         std::fs::File::create(out_path.join("archs.rs")).expect("Failed to create a file");
     write!(arch_file, "{}", archs).expect("Failed to write to a file");
@@ -1,6 +1,6 @@
 extern crate pmu;
 
-use pmu::{Builder, CounterKind, CacheCounter};
+use pmu::{Builder, CacheCounter, CounterKind, CountersGroup};
 
 fn fib(n: usize) -> usize {
     let mut a = 1;
@@ -24,9 +24,13 @@ fn main() {
 
     let n = args[1].parse::<usize>().unwrap();
 
-    let mut builder = Builder::new();
-    builder.add_counter(CounterKind::Cycles);
-    builder.add_counter(CounterKind::Instructions);
+    let mut builder = Builder::try_new().unwrap();
+
+    let mut ipc = CountersGroup::new();
+    ipc.add_counter(CounterKind::Cycles);
+    ipc.add_counter(CounterKind::Instructions);
+    builder.add_group(ipc);
+
     builder.add_counter(CounterKind::Branches);
     builder.add_counter(CounterKind::BranchMisses);
 
@@ -48,15 +52,32 @@ fn main() {
         .find(|e| e.to_string() == "HW:RETIRED_UOPS" || e.to_string() == "HW:UOPS_RETIRED.SLOTS");
     uops.and_then(|e| Some(builder.add_counter(CounterKind::System(e.clone()))));
 
-    let mut counters = builder.build().unwrap();
+    let mut counters = match builder.build() {
+        Ok(counters) => counters,
+        Err(err) => {
+            println!("Failed to create counters: {}", err);
+            return;
+        }
+    };
 
-    counters.start();
+    counters.start().unwrap();
     let f = fib(n);
-    counters.stop();
+    counters.stop().unwrap();
 
     println!("Fibonacci for {} is {}", n, f);
 
     for c in counters.iter() {
-        println!("{} is {}", c.kind.to_string(), c.value);
+        if c.never_scheduled() {
+            println!("{} was not counted", c.kind.to_string());
+        } else if c.is_multiplexed() {
+            println!(
+                "{} is {} ({:.1}% running)",
+                c.kind.to_string(),
+                c.scaled(),
+                c.confidence() * 100.0
+            );
+        } else {
+            println!("{} is {}", c.kind.to_string(), c.scaled());
+        }
     }
 }
@@ -13,6 +13,7 @@ extern "C" {
 struct PMUBuilderHandle;
 struct PMUCountersHandle;
 
+/// Returns NULL when no backend is available or LIBPMU_PROCESSOR_FAMILY names no family
 struct PMUBuilderHandle *pmu_builder_create();
 void pmu_builder_release(struct PMUBuilderHandle *);
 /// Add a standard or a system counter
@@ -26,11 +26,13 @@ enum class CacheLevelKind {
   dTLB = PMU_CACHE_DTLB,
   iTLB = PMU_CACHE_ITLB,
   BPU = PMU_CACHE_BPU,
+  Node = PMU_CACHE_NODE,
 };
 
 enum class CacheCounterKind {
-  Hit = PMU_CACHE_HIT,
+  Access = PMU_CACHE_ACCESS,
   Miss = PMU_CACHE_MISS,
+  Hit [[deprecated("counts accesses, use Access")]] = PMU_CACHE_ACCESS,
 };
 
 enum class CacheOpKind {
@@ -147,7 +149,10 @@ detail::CountersIterator::CountersIterator(const Counters *counters, size_t id)
 
 class Builder {
 public:
-  Builder() { mHandle = pmu_builder_create(); }
+  Builder() {
+    mHandle = pmu_builder_create();
+    assert(mHandle);
+  }
 
   Builder &add_counter(CounterKindAdvanced kind) {
     if (std::holds_alternative<CounterKind>(kind)) {
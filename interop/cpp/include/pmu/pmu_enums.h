@@ -21,11 +21,14 @@ enum PMUCacheLevelKind : int {
   PMU_CACHE_DTLB = 6,
   PMU_CACHE_ITLB = 7,
   PMU_CACHE_BPU = 8,
+  PMU_CACHE_NODE = 9,
 };
 
 enum PMUCacheCounterKind : int {
-  PMU_CACHE_HIT = 0,
+  PMU_CACHE_ACCESS = 0,
   PMU_CACHE_MISS = 1,
+  // Deprecated, counts accesses like PMU_CACHE_ACCESS
+  PMU_CACHE_HIT = 0,
 };
 
 enum PMUCacheOpKind : int {
@@ -1,14 +1,12 @@
 #[cfg(target_os = "macos")]
-use crate::backends::{Backend, BackendCounters};
+use crate::backends::{Backend, BackendCounters, CpuTarget, ProcessTarget};
 #[cfg(target_os = "macos")]
-use crate::{CounterKind, CountersGroup};
+use crate::{CounterKind, CountersGroup, Error};
 use dlopen2::wrapper::{Container, WrapperApi};
 use libc::*;
 use std::ffi::CStr;
 use std::sync::Arc;
 
-const MAX_COUNTERS: usize = 6;
-
 #[allow(dead_code)]
 const KPC_CLASS_FIXED: u32 = 0;
 const KPC_CLASS_CONFIGURABLE: u32 = 1;
@@ -132,6 +130,8 @@ struct KPerfCounters {
     counter_values_before: Vec<u64>,
     counter_values_after: Vec<u64>,
     config: *mut KPepConfig,
+    started_at: Option<std::time::Instant>,
+    elapsed_ns: u64,
 }
 
 #[cfg(target_os = "macos")]
@@ -143,25 +143,27 @@ pub struct KPerfBackend {
 
 #[cfg(target_os = "macos")]
 impl KPerfBackend {
-    pub fn new() -> KPerfBackend {
+    pub fn new() -> Result<KPerfBackend, Error> {
         let kpc_dispatch: Container<KPCDispatch> =
             unsafe { Container::load("/System/Library/PrivateFrameworks/kperf.framework/kperf") }
-                .expect("Could not open library or load symbols");
+                .map_err(|e| Error::BackendUnavailable(format!("Failed to load kperf: {}", e)))?;
         let kpep_dispatch: Container<KPEPDispatch> = unsafe {
             Container::load("/System/Library/PrivateFrameworks/kperfdata.framework/kperfdata")
         }
-        .expect("Could not open library or load symbols");
+        .map_err(|e| Error::BackendUnavailable(format!("Failed to load kperfdata: {}", e)))?;
 
         let mut db: *mut KPepDB = std::ptr::null_mut();
         if unsafe { kpep_dispatch.kpep_db_create(std::ptr::null(), &mut db) } != 0 {
-            panic!("Failed to load kpep database");
+            return Err(Error::BackendUnavailable(
+                "Failed to load kpep database".to_string(),
+            ));
         }
 
-        return KPerfBackend {
+        return Ok(KPerfBackend {
             kpc_dispatch: kpc_dispatch.into(),
             kpep_dispatch: kpep_dispatch.into(),
             db,
-        };
+        });
     }
 }
 
@@ -172,6 +174,16 @@ fn event_matches_name(e: *mut KPepEvent, name: &str) -> bool {
     return str_slice == name;
 }
 
+// kpep and kpc calls return status codes of their own and leave errno alone
+#[cfg(target_os = "macos")]
+fn check(call: &'static str, code: c_int) -> Result<(), Error> {
+    if code != 0 {
+        return Err(Error::BackendCall { call, code });
+    }
+
+    return Ok(());
+}
+
 macro_rules! macos_event {
     ($m1_name:expr, $intel_name:expr, $kperf_events:ident, $cfg:ident, $dispatch:expr) => {
         let m1_event = $kperf_events
@@ -181,30 +193,73 @@ macro_rules! macos_event {
             .iter()
             .find(|e| event_matches_name(*(*e), $intel_name));
 
-        let mut event: *mut KPepEvent = m1_event.or(intel_event).unwrap().clone();
+        let mut event: *mut KPepEvent = match m1_event.or(intel_event) {
+            Some(event) => event.clone(),
+            None => return Err(Error::EventNotSupported($m1_name.to_string())),
+        };
 
         if unsafe {
             $dispatch.kpep_config_add_event($cfg, &mut event, 0, std::ptr::null_mut()) != 0
         } {
-            panic!("Failed to add an event");
+            return Err(Error::EventNotSupported($m1_name.to_string()));
         }
     };
 }
 
 #[cfg(target_os = "macos")]
 impl Backend for KPerfBackend {
+    fn counter_capacity(&self) -> Option<crate::CounterCapacity> {
+        let db = unsafe { &*self.db };
+
+        return Some(crate::CounterCapacity {
+            general_counters: db.config_counter_count,
+            fixed_counters: db.fixed_count_counter,
+        });
+    }
+
+    fn topdown_group(&self, _level: crate::TopDownLevel) -> Result<CountersGroup, Error> {
+        return Err(Error::EventNotSupported("slots".to_string()));
+    }
+
+    fn memory_sampling_group(&self, _kind: crate::MemorySampling) -> Result<CountersGroup, Error> {
+        return Err(Error::EventNotSupported("mem-loads".to_string()));
+    }
+
     fn create_counters(
         &self,
-        _pid: Option<i32>,
+        process: &ProcessTarget,
+        cpus: &CpuTarget,
         groups: &[CountersGroup],
-    ) -> Result<Box<dyn BackendCounters>, String> {
+        sampling: Option<&crate::sampling::SamplingConfig>,
+        _family: &crate::ProcessorFamily,
+    ) -> Result<Box<dyn BackendCounters>, Error> {
+        if *process != ProcessTarget::Current {
+            return Err(Error::InvalidConfiguration(
+                "Counting other processes is not supported by kperf backend".to_string(),
+            ));
+        }
+        if *cpus != CpuTarget::Any {
+            return Err(Error::InvalidConfiguration(
+                "Per-CPU counting is not supported by kperf backend".to_string(),
+            ));
+        }
+        if sampling.is_some() {
+            return Err(Error::InvalidConfiguration(
+                "Sampling is not supported by kperf backend".to_string(),
+            ));
+        }
         if groups.len() != 1 {
-            return Err(format!("Only 1 group is supported currently"));
+            return Err(Error::InvalidConfiguration(
+                "Only 1 group is supported currently".to_string(),
+            ));
         }
-        if groups.first().unwrap().counters.len() > MAX_COUNTERS {
-            return Err(format!(
-                "Only {} counters are supported right now",
-                MAX_COUNTERS
+        if groups
+            .iter()
+            .flat_map(|g| g.counters.iter())
+            .any(|c| c.modifiers.is_some())
+        {
+            return Err(Error::InvalidConfiguration(
+                "Event modifiers are not supported by kperf backend".to_string(),
             ));
         }
         let mut num_events: size_t = 0;
@@ -213,7 +268,9 @@ impl Backend for KPerfBackend {
                 .kpep_db_events_count(self.db, &mut num_events)
         } != 0
         {
-            return Err("Failed to count events".to_string());
+            return Err(Error::BackendUnavailable(
+                "Failed to count events".to_string(),
+            ));
         }
 
         let mut kperf_events: Vec<*mut KPepEvent> = Vec::with_capacity(num_events as usize);
@@ -226,20 +283,26 @@ impl Backend for KPerfBackend {
             )
         } != 0
         {
-            return Err("Failed to query events".to_string());
+            return Err(Error::BackendUnavailable(
+                "Failed to query events".to_string(),
+            ));
         }
 
         let mut db: *mut KPepDB = std::ptr::null_mut();
         if unsafe { self.kpep_dispatch.kpep_db_create(std::ptr::null(), &mut db) } != 0 {
-            panic!("Failed to load kpep database");
+            return Err(Error::BackendUnavailable(
+                "Failed to load kpep database".to_string(),
+            ));
         }
 
         let mut cfg: *mut KPepConfig = std::ptr::null_mut();
         if unsafe { self.kpep_dispatch.kpep_config_create(db, &mut cfg) } != 0 {
-            panic!("Failed to create config");
+            return Err(Error::BackendUnavailable(
+                "Failed to create config".to_string(),
+            ));
         }
         if unsafe { self.kpep_dispatch.kpep_config_force_counters(cfg) != 0 } {
-            panic!("Failed to set counters");
+            return Err(Error::PermissionDenied(None));
         }
 
         let mut native_handles = vec![];
@@ -286,7 +349,9 @@ impl Backend for KPerfBackend {
                         self.kpep_dispatch
                     );
                 }
-                _ => {}
+                _ => {
+                    return Err(Error::EventNotSupported(c.counter.to_string()));
+                }
             }
         }
 
@@ -297,44 +362,37 @@ impl Backend for KPerfBackend {
             counter_values_before: vec![0; 32],
             counter_values_after: vec![0; 32],
             config: cfg.clone(),
+            started_at: None,
+            elapsed_ns: 0,
         }));
     }
 }
 
 #[cfg(target_os = "macos")]
 impl BackendCounters for KPerfCounters {
-    fn start(&mut self) {
+    fn start(&mut self) -> Result<(), Error> {
         let mut classes: u32 = 0;
-        if unsafe {
+        check("kpep_config_kpc_classes", unsafe {
             self.kpep_dispatch
                 .kpep_config_kpc_classes(self.config, &mut classes)
-                != 0
-        } {
-            panic!("Failed to get kpc classes");
-        }
+        })?;
 
         let mut reg_count: usize = 0;
-        if unsafe {
+        check("kpep_config_kpc_count", unsafe {
             self.kpep_dispatch
                 .kpep_config_kpc_count(self.config, &mut reg_count)
-                != 0
-        } {
-            panic!("Failed to get kpc count");
-        }
+        })?;
 
         // TODO(Alex): 32 is a hardcore value here
         let mut native_reg_map = vec![];
         native_reg_map.resize(32, 0);
-        let ret_val = unsafe {
+        check("kpep_config_kpc_map", unsafe {
             self.kpep_dispatch.kpep_config_kpc_map(
                 self.config,
                 native_reg_map.as_mut_ptr(),
                 native_reg_map.len() * std::mem::size_of::<usize>(),
             )
-        };
-        if ret_val != 0 {
-            panic!("Failed to get register mapping");
-        }
+        })?;
 
         for i in 0..self.native_handles.len() {
             self.native_handles[i].reg_id = native_reg_map[i];
@@ -342,56 +400,57 @@ impl BackendCounters for KPerfCounters {
 
         let mut regs = vec![];
         regs.resize(reg_count, 0);
-        println!("Reg count is {}", reg_count);
-        if unsafe {
+        check("kpep_config_kpc", unsafe {
             self.kpep_dispatch.kpep_config_kpc(
                 self.config,
                 regs.as_mut_ptr(),
                 reg_count * std::mem::size_of::<u64>(),
-            ) != 0
-        } {
-            panic!("Failed to set kpc config");
-        }
+            )
+        })?;
 
         if unsafe { self.kpc_dispatch.kpc_force_all_ctrs_set(1) != 0 } {
-            panic!("Failed to set kpc counters");
+            return Err(Error::PermissionDenied(None));
         }
 
         if (classes & KPC_CLASS_CONFIGURABLE_MASK != 0) && reg_count != 0 {
-            if unsafe { self.kpc_dispatch.kpc_set_config(classes, regs.as_mut_ptr()) != 0 } {
-                panic!("Failed to set kpc config");
-            }
+            check("kpc_set_config", unsafe {
+                self.kpc_dispatch.kpc_set_config(classes, regs.as_mut_ptr())
+            })?;
         }
 
-        if unsafe { self.kpc_dispatch.kpc_set_counting(classes) != 0 } {
-            panic!("Failed to set counting");
-        }
-        if unsafe { self.kpc_dispatch.kpc_set_thread_counting(classes) != 0 } {
-            panic!("Failet to set thread counting");
-        }
+        check("kpc_set_counting", unsafe {
+            self.kpc_dispatch.kpc_set_counting(classes)
+        })?;
+        check("kpc_set_thread_counting", unsafe {
+            self.kpc_dispatch.kpc_set_thread_counting(classes)
+        })?;
 
-        if unsafe {
+        check("kpc_get_thread_counters", unsafe {
             self.kpc_dispatch.kpc_get_thread_counters(
                 0,
                 32,
                 self.counter_values_before.as_mut_ptr(),
-            ) != 0
-        } {
-            panic!("Failed to get counters");
-        }
+            )
+        })?;
+        self.started_at = Some(std::time::Instant::now());
+
+        return Ok(());
     }
-    fn stop(&mut self) {
-        if unsafe {
+    fn stop(&mut self) -> Result<(), Error> {
+        check("kpc_get_thread_counters", unsafe {
             self.kpc_dispatch
                 .kpc_get_thread_counters(0, 32, self.counter_values_after.as_mut_ptr())
-                != 0
-        } {
-            panic!("Failed to get counters");
+        })?;
+        // kperf counters are pinned to their registers and never multiplexed
+        if let Some(started_at) = self.started_at.take() {
+            self.elapsed_ns = started_at.elapsed().as_nanos() as u64;
         }
         unsafe {
             self.kpc_dispatch.kpc_set_counting(0);
             self.kpc_dispatch.kpc_set_thread_counting(0);
         }
+
+        return Ok(());
     }
 
     fn peek(&self, id: usize) -> Option<crate::CounterValue> {
@@ -402,10 +461,49 @@ impl BackendCounters for KPerfCounters {
         let reg_id = self.native_handles[id].reg_id;
         return Some(crate::CounterValue {
             kind: self.native_handles[id].kind.clone(),
-            value: (self.counter_values_after[reg_id] - self.counter_values_before[reg_id])
-                as usize,
+            cpu: None,
+            core_type: None,
+            raw: self.counter_values_after[reg_id] - self.counter_values_before[reg_id],
+            time_enabled: self.elapsed_ns,
+            time_running: self.elapsed_ns,
         });
     }
+
+    fn peek_cpu(&self, _id: usize, _cpu: u32) -> Option<crate::CounterValue> {
+        return None;
+    }
+
+    fn peek_core_type(
+        &self,
+        _id: usize,
+        _core_type: crate::HybridCoreType,
+    ) -> Option<crate::CounterValue> {
+        return None;
+    }
+
+    fn cpus(&self) -> Vec<u32> {
+        return vec![];
+    }
+
+    fn core_types(&self) -> Vec<crate::HybridCoreType> {
+        return vec![];
+    }
+
+    fn next_sample(&mut self) -> Option<crate::Sample> {
+        return None;
+    }
+
+    fn symbolize(&mut self, _pid: u32, _ip: u64) -> Option<crate::Symbol> {
+        return None;
+    }
+
+    fn lost_samples(&self) -> u64 {
+        return 0;
+    }
+
+    fn throttled(&self) -> u64 {
+        return 0;
+    }
 }
 
 pub(crate) fn get_software_events() -> Vec<crate::SystemCounter> {
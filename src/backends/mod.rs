@@ -1,16 +1,64 @@
 pub(crate) trait BackendCounters {
-    fn start(&mut self);
-    fn stop(&mut self);
+    fn start(&mut self) -> Result<(), crate::Error>;
+    fn stop(&mut self) -> Result<(), crate::Error>;
 
     fn peek(&self, id: usize) -> Option<crate::CounterValue>;
+    fn peek_cpu(&self, id: usize, cpu: u32) -> Option<crate::CounterValue>;
+    fn peek_core_type(
+        &self,
+        id: usize,
+        core_type: crate::HybridCoreType,
+    ) -> Option<crate::CounterValue>;
+    fn cpus(&self) -> Vec<u32>;
+    fn core_types(&self) -> Vec<crate::HybridCoreType>;
+
+    fn next_sample(&mut self) -> Option<crate::Sample>;
+
+    // None unless symbolization was enabled in the Builder
+    fn symbolize(&mut self, pid: u32, ip: u64) -> Option<crate::Symbol>;
+
+    fn lost_samples(&self) -> u64;
+    fn throttled(&self) -> u64;
 }
 
 pub(crate) trait Backend {
     fn create_counters(
         &self,
-        pid: Option<i32>,
+        process: &ProcessTarget,
+        cpus: &CpuTarget,
         groups: &[crate::CountersGroup],
-    ) -> Result<Box<dyn BackendCounters>, String>;
+        sampling: Option<&crate::sampling::SamplingConfig>,
+        family: &crate::ProcessorFamily,
+    ) -> Result<Box<dyn BackendCounters>, crate::Error>;
+
+    fn counter_capacity(&self) -> Option<crate::CounterCapacity>;
+
+    // The slots group the top-down breakdown of the level is read from
+    fn topdown_group(
+        &self,
+        level: crate::TopDownLevel,
+    ) -> Result<crate::CountersGroup, crate::Error>;
+
+    // The group sampling the given memory accesses with their data addresses
+    fn memory_sampling_group(
+        &self,
+        kind: crate::MemorySampling,
+    ) -> Result<crate::CountersGroup, crate::Error>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ProcessTarget {
+    Current,
+    Attached(i32),
+    // A process stopped right before exec, counting starts at exec
+    Launched(i32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum CpuTarget {
+    Any,
+    List(Vec<u32>),
+    Online,
 }
 
 pub enum BackendKind {
@@ -20,9 +68,23 @@ pub enum BackendKind {
 
 mod kperf;
 mod perf;
+#[cfg(target_os = "linux")]
+mod perf_pmu;
+#[cfg(target_os = "linux")]
+mod perf_probes;
+#[cfg(target_os = "linux")]
+mod perf_sampling;
 
 #[cfg(target_os = "linux")]
-pub(crate) use perf::PerfBackend;
+pub(crate) use perf::{parse_cpu_list, PerfBackend};
+#[cfg(target_os = "linux")]
+pub(crate) use perf_pmu::{
+    find_pmu, get_kernel_events, hybrid_pmus, list_pmus, memory_sampling_group, topdown_group,
+};
+#[cfg(target_os = "linux")]
+pub use perf_pmu::{Pmu, PmuConfigField, PmuEvent, PmuFormat};
+#[cfg(target_os = "linux")]
+pub(crate) use perf_probes::{kprobe_attrs, tracepoint_id, uprobe_attrs};
 
 #[cfg(target_os = "macos")]
 pub(crate) use kperf::KPerfBackend;
@@ -1,22 +1,18 @@
 #[cfg(target_os = "linux")]
-use crate::backends::{Backend, BackendCounters};
+use crate::backends::perf_sampling::{decode_sample, track_record, RecordReader, RingBuffer};
 #[cfg(target_os = "linux")]
-use crate::{CounterKind, CountersGroup};
-use crate::{SystemCounter, SystemCounterKind, CacheCounterKind, CacheOpKind, CacheLevelKind};
+use crate::backends::{Backend, BackendCounters, CpuTarget, ProcessTarget};
+use crate::{CacheCounterKind, CacheLevelKind, CacheOpKind, SystemCounter, SystemCounterKind};
 #[cfg(target_os = "linux")]
-use libc::{ptrace, read};
+use crate::{CounterKind, CountersGroup, Error, EventModifiers, HybridCoreType};
+#[cfg(target_os = "linux")]
+use libc::read;
 use perf_event_open_sys as sys;
+use std::borrow::Cow;
 
 #[cfg(target_os = "linux")]
 pub(crate) struct PerfBackend {}
 
-#[cfg(target_os = "linux")]
-#[repr(C)]
-struct RFValues {
-    value: u64,
-    id: u64,
-}
-
 #[cfg(target_os = "linux")]
 struct NativeCounterHandle {
     pub kind: CounterKind,
@@ -26,14 +22,39 @@ struct NativeCounterHandle {
 
 #[cfg(target_os = "linux")]
 struct PerfCounterGroup {
+    cpu: i32,
+    core_type: Option<HybridCoreType>,
     native_handles: Vec<NativeCounterHandle>,
     buffer: Vec<u8>,
+    ring_buffer: Option<RingBuffer>,
+}
+
+// The core PMU of one core type of a hybrid CPU
+#[cfg(target_os = "linux")]
+struct HybridTarget {
+    core_type: HybridCoreType,
+    type_id: u32,
+    cpus: Vec<u32>,
 }
 
 #[cfg(target_os = "linux")]
 struct PerfCounters {
     groups: Vec<PerfCounterGroup>,
-    pid: i32,
+    // Every group as requested by the user is opened once per CPU and core
+    // type, these are the indices of its instances in groups
+    logical_groups: Vec<Vec<usize>>,
+    // Counters are enabled by the kernel when the launched process execs
+    pending_exec: bool,
+    sample_type: u64,
+    // Only set when user stacks are sampled for DWARF unwinding
+    unwinder: Option<crate::unwind::Unwinder>,
+    // The branch stack holds the user call stack recorded by the LBR
+    lbr_callstack: bool,
+    symbolizer: Option<crate::symbolizer::Symbolizer>,
+    // Samples the kernel dropped and times it throttled sampling, as reported
+    // by the records drained so far
+    lost_samples: u64,
+    throttled: u64,
 }
 
 #[cfg(target_os = "linux")]
@@ -41,156 +62,801 @@ impl PerfBackend {
     pub fn new() -> PerfBackend {
         return PerfBackend {};
     }
-}
 
-#[cfg(target_os = "linux")]
-impl Backend for PerfBackend {
-    fn create_counters(
-        &self,
-        pid: Option<i32>,
-        groups: &[CountersGroup],
-    ) -> Result<Box<dyn BackendCounters>, String> {
-        let mut processed = 0;
+    fn open_group(
+        group: &CountersGroup,
+        pid: i32,
+        cpu: i32,
+        target: Option<&HybridTarget>,
+        enable_on_exec: bool,
+        sampling: Option<&crate::sampling::SamplingConfig>,
+        sample_type: u64,
+        family: &crate::ProcessorFamily,
+    ) -> Result<PerfCounterGroup, Error> {
+        let mut native_handles: Vec<NativeCounterHandle> = vec![];
+        let mut ring_buffer: Option<RingBuffer> = None;
 
-        let mut native_groups: Vec<PerfCounterGroup> = vec![];
+        // The kernel only accepts topdown-* metrics in a group led by slots and
+        // cannot sample them, reject that up front instead of returning EINVAL
+        if group
+            .counters
+            .iter()
+            .any(|c| crate::topdown::is_topdown_metric(&c.counter))
+        {
+            let leader = &group.counters[0].counter;
+            if !crate::topdown::is_topdown_counter(leader)
+                || crate::topdown::is_topdown_metric(leader)
+            {
+                return Err(Error::InvalidConfiguration(
+                    "Top-down metric events must be in a group led by slots".to_string(),
+                ));
+            }
+            if sampling.is_some() {
+                return Err(Error::InvalidConfiguration(
+                    "Top-down metric events cannot be sampled".to_string(),
+                ));
+            }
+        }
 
-        for g in groups {
-            let mut native_handles: Vec<NativeCounterHandle> = vec![];
-
-            for single_cntr in &g.counters {
-                let mut attrs = sys::bindings::perf_event_attr::default();
-                attrs.size = std::mem::size_of::<sys::bindings::perf_event_attr>() as u32;
-                attrs.set_disabled(1);
-                // TODO(Alex): figure out if this is a RISC-V platform limitation or a kernel bug
-                cfg_if::cfg_if! {
-                    if #[cfg(target_arch="x86_64")] {
-                        attrs.set_exclude_kernel(1);
-                        attrs.set_exclude_hv(1);
+        let sampled = sampled_counter(group);
+        if let Some(sampling) = sampling {
+            // IBS keeps the low 4 bits of the period for itself
+            if is_ibs_event(&group.counters[sampled].counter) && sampling.period & 0xf != 0 {
+                return Err(Error::InvalidConfiguration(
+                    "IBS sample periods must be multiples of 16".to_string(),
+                ));
+            }
+        }
+
+        for (idx, single_cntr) in group.counters.iter().enumerate() {
+            let mut attrs = sys::bindings::perf_event_attr::default();
+            attrs.size = std::mem::size_of::<sys::bindings::perf_event_attr>() as u32;
+            attrs.set_disabled(1);
+            if enable_on_exec {
+                attrs.set_enable_on_exec(1);
+            }
+            let modifiers = match &single_cntr.modifiers {
+                Some(modifiers) => modifiers.clone(),
+                // Non-core PMUs like msr or power reject any exclusion bits
+                None if !is_core_event(&single_cntr.counter) => EventModifiers {
+                    exclude_kernel: false,
+                    exclude_hv: false,
+                    ..Default::default()
+                },
+                None => EventModifiers::default(),
+            };
+            // The kernel only schedules whole groups, so these are leader properties
+            if idx != 0 && (modifiers.pinned || modifiers.exclusive) {
+                return Err(Error::InvalidConfiguration(format!(
+                    "{} is not a group leader and cannot be pinned or exclusive",
+                    single_cntr.counter.to_string()
+                )));
+            }
+            attrs.set_exclude_user(modifiers.exclude_user as u64);
+            attrs.set_exclude_kernel(modifiers.exclude_kernel as u64);
+            attrs.set_exclude_hv(modifiers.exclude_hv as u64);
+            attrs.set_exclude_idle(modifiers.exclude_idle as u64);
+            attrs.set_exclude_host(modifiers.exclude_host as u64);
+            attrs.set_exclude_guest(modifiers.exclude_guest as u64);
+            attrs.set_pinned(modifiers.pinned as u64);
+            attrs.set_exclusive(modifiers.exclusive as u64);
+            attrs.read_format = sys::bindings::PERF_FORMAT_GROUP as u64
+                | sys::bindings::PERF_FORMAT_ID as u64
+                | sys::bindings::PERF_FORMAT_TOTAL_TIME_ENABLED as u64
+                | sys::bindings::PERF_FORMAT_TOTAL_TIME_RUNNING as u64;
+
+            match sampling {
+                Some(sampling) => {
+                    // Only the group leader samples, the rest of the group is read
+                    // alongside it via PERF_SAMPLE_READ, which does not work with inherit.
+                    if idx == sampled {
+                        attrs.__bindgen_anon_1.sample_period = sampling.period;
+                        attrs.sample_type = sample_type;
+                        match sampling.callchain {
+                            Some(crate::CallChainMode::Dwarf { stack_size }) => {
+                                // The user part is unwound from the stack copy instead
+                                attrs.set_exclude_callchain_user(1);
+                                attrs.sample_regs_user = crate::unwind::sampled_registers_mask();
+                                attrs.sample_stack_user = stack_size;
+                            }
+                            Some(crate::CallChainMode::Lbr) => {
+                                attrs.set_exclude_callchain_user(1);
+                                attrs.branch_sample_type =
+                                    sys::bindings::PERF_SAMPLE_BRANCH_CALL_STACK as u64
+                                        | sys::bindings::PERF_SAMPLE_BRANCH_USER as u64;
+                            }
+                            _ => {}
+                        }
+                        if let Some(filter) = &sampling.branch_stack {
+                            attrs.branch_sample_type = branch_sample_type(filter);
+                        }
+                        if sampling.symbolize {
+                            // Side-band records the symbolizer follows the processes with
+                            attrs.set_mmap(1);
+                            attrs.set_mmap2(1);
+                            attrs.set_comm(1);
+                            attrs.set_comm_exec(1);
+                            attrs.set_task(1);
+                        }
                     }
                 }
-                attrs.read_format = sys::bindings::PERF_FORMAT_GROUP as u64
-                    | sys::bindings::PERF_FORMAT_ID as u64
-                    | sys::bindings::PERF_FORMAT_TOTAL_TIME_ENABLED as u64
-                    | sys::bindings::PERF_FORMAT_TOTAL_TIME_RUNNING as u64;
-                attrs.set_inherit(1);
-
-                let precision = match &single_cntr.precision {
-                    crate::SamplingPrecision::None => 0,
-                    crate::SamplingPrecision::ConstantSkid => 1,
-                    crate::SamplingPrecision::RequestNoSkid => 2,
-                    crate::SamplingPrecision::ExactIP => 3,
-                };
-                attrs.set_precise_ip(precision);
+                None => {
+                    // inherit only makes sense when following a task
+                    if pid != -1 {
+                        attrs.set_inherit(1);
+                    }
+                }
+            }
 
-                match &single_cntr.counter {
-                    CounterKind::Cycles => {
-                        attrs.type_ = sys::bindings::PERF_TYPE_HARDWARE;
-                        attrs.config = sys::bindings::PERF_COUNT_HW_CPU_CYCLES as u64;
+            let precision = match &single_cntr.precision {
+                crate::SamplingPrecision::None => 0,
+                crate::SamplingPrecision::ConstantSkid => 1,
+                crate::SamplingPrecision::RequestNoSkid => 2,
+                crate::SamplingPrecision::ExactIP => 3,
+            };
+            if precision != 0 {
+                if let CounterKind::System(counter) = &single_cntr.counter {
+                    if counter.precise == Some(false) {
+                        return Err(Error::InvalidConfiguration(format!(
+                            "{} does not support precise sampling",
+                            counter.to_string()
+                        )));
                     }
-                    CounterKind::Instructions => {
-                        attrs.type_ = sys::bindings::PERF_TYPE_HARDWARE;
-                        attrs.config = sys::bindings::PERF_COUNT_HW_INSTRUCTIONS as u64;
+                }
+            }
+            attrs.set_precise_ip(precision);
+
+            // Kept alive until perf_event_open has copied it
+            let mut probe_target: Option<std::ffi::CString> = None;
+            match &single_cntr.counter {
+                CounterKind::Cycles => {
+                    attrs.type_ = sys::bindings::PERF_TYPE_HARDWARE;
+                    attrs.config = sys::bindings::PERF_COUNT_HW_CPU_CYCLES as u64;
+                }
+                CounterKind::Instructions => {
+                    attrs.type_ = sys::bindings::PERF_TYPE_HARDWARE;
+                    attrs.config = sys::bindings::PERF_COUNT_HW_INSTRUCTIONS as u64;
+                }
+                CounterKind::Branches => {
+                    attrs.type_ = sys::bindings::PERF_TYPE_HARDWARE;
+                    attrs.config = sys::bindings::PERF_COUNT_HW_BRANCH_INSTRUCTIONS as u64;
+                }
+                CounterKind::BranchMisses => {
+                    attrs.type_ = sys::bindings::PERF_TYPE_HARDWARE;
+                    attrs.config = sys::bindings::PERF_COUNT_HW_BRANCH_MISSES as u64;
+                }
+                CounterKind::Cache(cache) => {
+                    // Generic L1 is the data cache, same as perf's L1-dcache
+                    let id = match cache.level {
+                        CacheLevelKind::L1 => Some(sys::bindings::PERF_COUNT_HW_CACHE_L1D),
+                        CacheLevelKind::L1I => Some(sys::bindings::PERF_COUNT_HW_CACHE_L1I),
+                        CacheLevelKind::L1D => Some(sys::bindings::PERF_COUNT_HW_CACHE_L1D),
+                        CacheLevelKind::Last => Some(sys::bindings::PERF_COUNT_HW_CACHE_LL),
+                        CacheLevelKind::DTLB => Some(sys::bindings::PERF_COUNT_HW_CACHE_DTLB),
+                        CacheLevelKind::ITLB => Some(sys::bindings::PERF_COUNT_HW_CACHE_ITLB),
+                        CacheLevelKind::BPU => Some(sys::bindings::PERF_COUNT_HW_CACHE_BPU),
+                        CacheLevelKind::Node => Some(sys::bindings::PERF_COUNT_HW_CACHE_NODE),
+                        CacheLevelKind::L2 | CacheLevelKind::L3 => None,
+                    };
+                    let op = match cache.op {
+                        CacheOpKind::Read => sys::bindings::PERF_COUNT_HW_CACHE_OP_READ,
+                        CacheOpKind::Write => sys::bindings::PERF_COUNT_HW_CACHE_OP_WRITE,
+                        CacheOpKind::Prefetch => sys::bindings::PERF_COUNT_HW_CACHE_OP_PREFETCH,
+                    };
+                    let result = match cache.kind {
+                        CacheCounterKind::Access => {
+                            sys::bindings::PERF_COUNT_HW_CACHE_RESULT_ACCESS
+                        }
+                        CacheCounterKind::Miss => sys::bindings::PERF_COUNT_HW_CACHE_RESULT_MISS,
+                    };
+
+                    match id {
+                        Some(id) => {
+                            attrs.type_ = sys::bindings::PERF_TYPE_HW_CACHE;
+                            attrs.config =
+                                (id as u64) | ((op as u64) << 8) | ((result as u64) << 16);
+                        }
+                        // Generic perf cache events cannot express intermediate cache
+                        // levels, fall back to the raw event from the arch tables
+                        None => match crate::events::get_cache_event(
+                            cache,
+                            target.map(|t| t.core_type),
+                            family,
+                        ) {
+                            Some(event) => {
+                                attrs.type_ = sys::bindings::PERF_TYPE_RAW;
+                                attrs.config = event.encoding;
+                                attrs.__bindgen_anon_3.config1 = event.config1;
+                                attrs.__bindgen_anon_4.config2 = event.config2;
+                            }
+                            None => {
+                                return Err(Error::EventNotSupported(
+                                    single_cntr.counter.to_string(),
+                                ));
+                            }
+                        },
                     }
-                    CounterKind::Branches => {
-                        attrs.type_ = sys::bindings::PERF_TYPE_HARDWARE;
-                        attrs.config = sys::bindings::PERF_COUNT_HW_BRANCH_INSTRUCTIONS as u64;
+                }
+                CounterKind::System(counter) => match &counter.kind {
+                    crate::SystemCounterKind::Software => {
+                        attrs.type_ = sys::bindings::PERF_TYPE_SOFTWARE;
+                        attrs.config = counter.encoding;
                     }
-                    CounterKind::BranchMisses => {
-                        attrs.type_ = sys::bindings::PERF_TYPE_HARDWARE;
-                        attrs.config = sys::bindings::PERF_COUNT_HW_BRANCH_MISSES as u64;
+                    crate::SystemCounterKind::Hardware => {
+                        attrs.type_ = sys::bindings::PERF_TYPE_RAW;
+                        attrs.config = counter.encoding;
+                        attrs.__bindgen_anon_3.config1 = counter.config1;
+                        attrs.__bindgen_anon_4.config2 = counter.config2;
                     }
-                    CounterKind::Cache(cache) => {
-                        attrs.type_ = sys::bindings::PERF_TYPE_HW_CACHE;
-                        let id = match cache.level {
-                            CacheLevelKind::L1I => sys::bindings::PERF_COUNT_HW_CACHE_L1I,
-                            CacheLevelKind::L1D => sys::bindings::PERF_COUNT_HW_CACHE_L1D,
-                            CacheLevelKind::Last => sys::bindings::PERF_COUNT_HW_CACHE_LL,
-                            CacheLevelKind::DTLB => sys::bindings::PERF_COUNT_HW_CACHE_DTLB,
-                            CacheLevelKind::ITLB => sys::bindings::PERF_COUNT_HW_CACHE_ITLB,
-                            _ => unimplemented!(),
-                        };
-                        let op = match cache.op {
-                            CacheOpKind::Read => sys::bindings::PERF_COUNT_HW_CACHE_OP_READ,
-                            CacheOpKind::Write => sys::bindings::PERF_COUNT_HW_CACHE_OP_WRITE,
-                            CacheOpKind::Prefetch => sys::bindings::PERF_COUNT_HW_CACHE_OP_PREFETCH,
+                    crate::SystemCounterKind::Hybrid(core_type) => {
+                        attrs.type_ = match target {
+                            Some(target) if target.core_type == *core_type => target.type_id,
+                            // Hybrid parts with E-cores disabled only expose a plain "cpu" PMU
+                            None if *core_type == HybridCoreType::Core => {
+                                sys::bindings::PERF_TYPE_RAW
+                            }
+                            _ => {
+                                return Err(Error::EventNotSupported(
+                                    single_cntr.counter.to_string(),
+                                ))
+                            }
                         };
-                        let result = match cache.kind {
-                            CacheCounterKind::Hit => sys::bindings::PERF_COUNT_HW_CACHE_RESULT_ACCESS,
-                            CacheCounterKind::Miss => sys::bindings::PERF_COUNT_HW_CACHE_RESULT_MISS,
-                        };
-                        attrs.config = sys::bindings::PERF_COUNT_HW_CACHE_MISSES as u64;
+                        attrs.config = counter.encoding;
+                        attrs.__bindgen_anon_3.config1 = counter.config1;
+                        attrs.__bindgen_anon_4.config2 = counter.config2;
                     }
-                    CounterKind::System(counter) => match counter.kind {
-                        crate::SystemCounterKind::Software => {
-                            attrs.type_ = sys::bindings::PERF_TYPE_SOFTWARE;
-                            attrs.config = counter.encoding;
-                        }
-                        crate::SystemCounterKind::Hardware => {
-                            attrs.type_ = sys::bindings::PERF_TYPE_RAW;
-                            attrs.config = counter.encoding;
-                        }
-                    },
-                    _ => {
-                        unimplemented!();
+                    crate::SystemCounterKind::Pmu { type_id, .. } => {
+                        attrs.type_ = *type_id;
+                        attrs.config = counter.encoding;
+                        attrs.__bindgen_anon_3.config1 = counter.config1;
+                        attrs.__bindgen_anon_4.config2 = counter.config2;
                     }
+                },
+                CounterKind::Raw(config) => {
+                    attrs.type_ = sys::bindings::PERF_TYPE_RAW;
+                    attrs.config = *config;
+                }
+                CounterKind::Tracepoint { subsystem, name } => {
+                    attrs.type_ = sys::bindings::PERF_TYPE_TRACEPOINT;
+                    attrs.config = crate::backends::tracepoint_id(subsystem, name)?;
                 }
+                CounterKind::Kprobe { function } => {
+                    let probe = crate::backends::kprobe_attrs(function)?;
+                    attrs.type_ = probe.type_id;
+                    attrs.__bindgen_anon_3.config1 =
+                        probe.target.as_ref().map_or(0, |t| t.as_ptr() as u64);
+                    attrs.__bindgen_anon_4.config2 = probe.offset;
+                    probe_target = probe.target;
+                }
+                CounterKind::Uprobe {
+                    binary,
+                    symbol_or_offset,
+                } => {
+                    let probe = crate::backends::uprobe_attrs(binary, symbol_or_offset)?;
+                    attrs.type_ = probe.type_id;
+                    attrs.__bindgen_anon_3.config1 =
+                        probe.target.as_ref().map_or(0, |t| t.as_ptr() as u64);
+                    attrs.__bindgen_anon_4.config2 = probe.offset;
+                    probe_target = probe.target;
+                }
+            }
 
-                let base_fd: i32 = if native_handles.is_empty() {
-                    -1
-                } else {
-                    native_handles.first().unwrap().fd
-                };
+            // Generic events pick the core PMU through the extended type in the
+            // upper config bits, raw ones use the type of the PMU itself
+            if let Some(target) = target {
+                match attrs.type_ {
+                    sys::bindings::PERF_TYPE_HARDWARE | sys::bindings::PERF_TYPE_HW_CACHE => {
+                        attrs.config |= (target.type_id as u64) << 32;
+                    }
+                    sys::bindings::PERF_TYPE_RAW => attrs.type_ = target.type_id,
+                    _ => {}
+                }
+            }
+
+            let base_fd: i32 = if native_handles.is_empty() {
+                -1
+            } else {
+                native_handles.first().unwrap().fd
+            };
+
+            let new_fd = unsafe { sys::perf_event_open(&mut attrs, pid, cpu, base_fd, 0) };
+            drop(probe_target);
+
+            if new_fd < 0 {
+                return Err(open_error(&single_cntr.counter));
+            }
+
+            let mut id: u64 = 0;
+
+            let result = unsafe { sys::ioctls::ID(new_fd, &mut id) };
+            if result < 0 {
+                let err = Error::last_os_error();
+                unsafe { libc::close(new_fd) };
+                return Err(err);
+            }
+
+            // Pushed first so that the handle closes new_fd if the mapping fails
+            native_handles.push(NativeCounterHandle {
+                kind: single_cntr.counter.clone(),
+                fd: new_fd,
+                id: id,
+            });
+
+            if sampling.is_some() && idx == sampled {
+                ring_buffer = Some(RingBuffer::new(new_fd)?);
+            }
+        }
+
+        return Ok(PerfCounterGroup::new(
+            cpu,
+            target.map(|t| t.core_type),
+            native_handles,
+            ring_buffer,
+        ));
+    }
+
+    // Core types a group has to be opened on. Groups without core PMU events
+    // are opened once, the rest once per core type all their events can run on.
+    fn group_targets(
+        group: &CountersGroup,
+        hybrid: &[(HybridCoreType, crate::backends::Pmu)],
+    ) -> Result<Vec<Option<HybridTarget>>, Error> {
+        if hybrid.is_empty() {
+            return Ok(vec![None]);
+        }
+
+        let mut allowed: Vec<HybridCoreType> =
+            hybrid.iter().map(|(core_type, _)| *core_type).collect();
+        let mut uses_core_pmu = false;
+
+        for single_cntr in &group.counters {
+            let only = match &single_cntr.counter {
+                CounterKind::System(counter) => match &counter.kind {
+                    SystemCounterKind::Software => continue,
+                    SystemCounterKind::Pmu { pmu, .. } => {
+                        match hybrid
+                            .iter()
+                            .find(|(_, hybrid_pmu)| &hybrid_pmu.name == pmu)
+                        {
+                            Some((core_type, _)) => Some(*core_type),
+                            None => continue,
+                        }
+                    }
+                    SystemCounterKind::Hybrid(core_type) => Some(*core_type),
+                    SystemCounterKind::Hardware => None,
+                },
+                CounterKind::Tracepoint { .. }
+                | CounterKind::Kprobe { .. }
+                | CounterKind::Uprobe { .. } => continue,
+                _ => None,
+            };
+
+            uses_core_pmu = true;
+            if let Some(only) = only {
+                allowed.retain(|core_type| *core_type == only);
+            }
+        }
+
+        if !uses_core_pmu {
+            return Ok(vec![None]);
+        }
+        if allowed.is_empty() {
+            return Err(Error::InvalidConfiguration(
+                "A group cannot mix events of different hybrid core types".to_string(),
+            ));
+        }
+
+        return Ok(hybrid
+            .iter()
+            .filter(|(core_type, _)| allowed.contains(core_type))
+            .map(|(core_type, pmu)| {
+                Some(HybridTarget {
+                    core_type: *core_type,
+                    type_id: pmu.type_id,
+                    cpus: pmu.cpus.clone(),
+                })
+            })
+            .collect());
+    }
+}
+
+// CPUID describes the core it runs on, so it runs on a thread of its own
+// pinned to the given CPU, the affinity of the caller is left alone
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn cpu_info_on(cpu: u32) -> Option<crate::CpuInfo> {
+    if cpu as usize >= libc::CPU_SETSIZE as usize {
+        return None;
+    }
+
+    let probe = std::thread::spawn(move || unsafe {
+        let mut pinned: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu as usize, &mut pinned);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &pinned) != 0 {
+            return None;
+        }
+
+        return Some(crate::CpuInfo::from_x86_cpuid(&crate::events::cpuid));
+    });
+
+    return probe.join().ok().flatten();
+}
+
+// CpuInfo of the first CPU of every hybrid core type, probed once
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn hybrid_cpu_infos() -> &'static [(HybridCoreType, crate::CpuInfo)] {
+    static INFOS: std::sync::OnceLock<Vec<(HybridCoreType, crate::CpuInfo)>> =
+        std::sync::OnceLock::new();
+
+    return INFOS.get_or_init(|| {
+        return crate::backends::hybrid_pmus()
+            .iter()
+            .filter_map(|(core_type, pmu)| Some((*core_type, cpu_info_on(*pmu.cpus.first()?)?)))
+            .collect();
+    });
+}
 
-                let new_fd =
-                    unsafe { sys::perf_event_open(&mut attrs, pid.unwrap_or(0), -1, base_fd, 0) };
+// The hard lockup detector keeps a counter of every CPU busy
+#[cfg(target_os = "linux")]
+fn nmi_watchdog_enabled() -> bool {
+    return std::fs::read_to_string("/proc/sys/kernel/nmi_watchdog")
+        .is_ok_and(|value| value.trim() == "1");
+}
 
-                if new_fd < 0 {
-                    return Err(format!(
-                        "Failed to open file descriptor for event {}",
-                        &single_cntr.counter.to_string()
+#[cfg(target_os = "linux")]
+impl Backend for PerfBackend {
+    // On hybrid CPUs a group may be opened on every core type, so it has to
+    // fit into the smallest of them
+    fn counter_capacity(&self) -> Option<crate::CounterCapacity> {
+        let info = crate::get_cpu_info();
+        let mut general_counters = info.general_counters? as usize;
+        let mut fixed_counters = info.fixed_counters.unwrap_or(0) as usize;
+
+        #[cfg(target_arch = "x86_64")]
+        for (_, core_info) in hybrid_cpu_infos() {
+            if let Some(general) = core_info.general_counters {
+                general_counters = general_counters.min(general as usize);
+            }
+            if let Some(fixed) = core_info.fixed_counters {
+                fixed_counters = fixed_counters.min(fixed as usize);
+            }
+        }
+
+        // The watchdog counts cycles, on fixed counter 1 where there is one
+        if nmi_watchdog_enabled() {
+            if fixed_counters >= 2 {
+                fixed_counters = 1;
+            } else {
+                general_counters = general_counters.saturating_sub(1);
+            }
+        }
+
+        return Some(crate::CounterCapacity {
+            general_counters,
+            fixed_counters,
+        });
+    }
+
+    fn topdown_group(&self, level: crate::TopDownLevel) -> Result<CountersGroup, Error> {
+        return super::topdown_group(level);
+    }
+
+    fn memory_sampling_group(&self, kind: crate::MemorySampling) -> Result<CountersGroup, Error> {
+        return super::memory_sampling_group(kind);
+    }
+
+    fn create_counters(
+        &self,
+        process: &ProcessTarget,
+        cpus: &CpuTarget,
+        groups: &[CountersGroup],
+        sampling: Option<&crate::sampling::SamplingConfig>,
+        family: &crate::ProcessorFamily,
+    ) -> Result<Box<dyn BackendCounters>, Error> {
+        let mut native_groups: Vec<PerfCounterGroup> = vec![];
+
+        let mut sample_type = sys::bindings::PERF_SAMPLE_IP as u64
+            | sys::bindings::PERF_SAMPLE_TID as u64
+            | sys::bindings::PERF_SAMPLE_TIME as u64
+            | sys::bindings::PERF_SAMPLE_CPU as u64
+            | sys::bindings::PERF_SAMPLE_PERIOD as u64
+            | sys::bindings::PERF_SAMPLE_READ as u64;
+        if let Some(sampling) = sampling {
+            match sampling.callchain {
+                Some(crate::CallChainMode::FramePointer) => {
+                    sample_type |= sys::bindings::PERF_SAMPLE_CALLCHAIN as u64;
+                }
+                Some(crate::CallChainMode::Dwarf { stack_size }) => {
+                    if !crate::unwind::DWARF_UNWINDING {
+                        return Err(Error::InvalidConfiguration(
+                            "DWARF unwinding is not supported on this architecture".to_string(),
+                        ));
+                    }
+                    // The whole sample has to fit into the 16-bit record size
+                    if stack_size == 0 || stack_size % 8 != 0 || stack_size > 0xf000 {
+                        return Err(Error::InvalidConfiguration(format!(
+                            "Stack size {} must be a non-zero multiple of 8 of at most 61440 bytes",
+                            stack_size
+                        )));
+                    }
+                    sample_type |= sys::bindings::PERF_SAMPLE_CALLCHAIN as u64
+                        | sys::bindings::PERF_SAMPLE_REGS_USER as u64
+                        | sys::bindings::PERF_SAMPLE_STACK_USER as u64;
+                }
+                Some(crate::CallChainMode::Lbr) => {
+                    sample_type |= sys::bindings::PERF_SAMPLE_CALLCHAIN as u64
+                        | sys::bindings::PERF_SAMPLE_BRANCH_STACK as u64;
+                }
+                None => {}
+            }
+            if sampling.memory {
+                sample_type |= sys::bindings::PERF_SAMPLE_ADDR as u64
+                    | sys::bindings::PERF_SAMPLE_WEIGHT as u64
+                    | sys::bindings::PERF_SAMPLE_DATA_SRC as u64;
+            }
+            if sampling.physical_addresses {
+                sample_type |= sys::bindings::PERF_SAMPLE_PHYS_ADDR as u64;
+            }
+            if let Some(filter) = &sampling.branch_stack {
+                // Both would need their own branch_sample_type
+                if sampling.callchain == Some(crate::CallChainMode::Lbr) {
+                    return Err(Error::InvalidConfiguration(
+                        "Branch stacks cannot be combined with LBR call chains".to_string(),
                     ));
                 }
+                if !filter.has_branch_types() {
+                    return Err(Error::InvalidConfiguration(
+                        "The branch filter does not select any branch type".to_string(),
+                    ));
+                }
+                sample_type |= sys::bindings::PERF_SAMPLE_BRANCH_STACK as u64;
+            }
+        }
 
-                let mut id: u64 = 0;
+        let cpus: Vec<i32> = match cpus {
+            CpuTarget::Any => vec![-1],
+            CpuTarget::List(cpus) => cpus.iter().map(|cpu| *cpu as i32).collect(),
+            CpuTarget::Online => online_cpus()?,
+        };
+
+        // Counting on specific CPUs without a task measures everything running on them
+        let pid = match process {
+            ProcessTarget::Current if cpus == [-1] => 0,
+            ProcessTarget::Current => -1,
+            ProcessTarget::Attached(pid) => *pid,
+            ProcessTarget::Launched(pid) => *pid,
+        };
+        let enable_on_exec = matches!(process, ProcessTarget::Launched(_));
+        let hybrid = crate::backends::hybrid_pmus();
+
+        let mut logical_groups = vec![];
+        for g in groups {
+            let mut instances = vec![];
+            for target in PerfBackend::group_targets(g, &hybrid)? {
+                for cpu in &cpus {
+                    // A core PMU of a hybrid CPU only counts on CPUs of its type
+                    if let Some(target) = &target {
+                        if *cpu >= 0
+                            && !target.cpus.is_empty()
+                            && !target.cpus.contains(&(*cpu as u32))
+                        {
+                            continue;
+                        }
+                    }
 
-                let result = unsafe { sys::ioctls::ID(new_fd, &mut id) };
-                if result < 0 {
-                    return Err("Failed to acquire event ID".to_string());
+                    instances.push(native_groups.len());
+                    native_groups.push(PerfBackend::open_group(
+                        g,
+                        pid,
+                        *cpu,
+                        target.as_ref(),
+                        enable_on_exec,
+                        sampling,
+                        sample_type,
+                        family,
+                    )?);
                 }
+            }
 
-                native_handles.push(NativeCounterHandle {
-                    kind: single_cntr.counter.clone(),
-                    fd: new_fd,
-                    id: id,
-                });
+            if instances.is_empty() {
+                return Err(Error::InvalidConfiguration(format!(
+                    "None of the selected CPUs can count {}",
+                    g.counters.first().unwrap().counter.to_string()
+                )));
             }
-            native_groups.push(PerfCounterGroup::new(native_handles));
+            logical_groups.push(instances);
         }
 
-        return Ok(Box::new(PerfCounters::new(native_groups, pid.unwrap_or(0))));
+        let symbolizer = match sampling {
+            Some(sampling) if sampling.symbolize => {
+                let mut symbolizer = crate::symbolizer::Symbolizer::new();
+                // Mappings that existed before counting started produce no records
+                match process {
+                    ProcessTarget::Current => symbolizer.add_process(std::process::id()),
+                    ProcessTarget::Attached(pid) => symbolizer.add_process(*pid as u32),
+                    // Everything is mapped anew at exec
+                    ProcessTarget::Launched(_) => {}
+                }
+                Some(symbolizer)
+            }
+            _ => None,
+        };
+
+        return Ok(Box::new(PerfCounters::new(
+            native_groups,
+            logical_groups,
+            enable_on_exec,
+            sample_type,
+            sampling.and_then(|s| s.callchain) == Some(crate::CallChainMode::Lbr),
+            symbolizer,
+        )));
     }
 }
 
 #[cfg(target_os = "linux")]
 impl PerfCounters {
-    fn new(groups: Vec<PerfCounterGroup>, pid: i32) -> PerfCounters {
-        return PerfCounters { groups, pid };
+    fn new(
+        groups: Vec<PerfCounterGroup>,
+        logical_groups: Vec<Vec<usize>>,
+        pending_exec: bool,
+        sample_type: u64,
+        lbr_callstack: bool,
+        symbolizer: Option<crate::symbolizer::Symbolizer>,
+    ) -> PerfCounters {
+        let unwinder = if sample_type & sys::bindings::PERF_SAMPLE_STACK_USER as u64 != 0 {
+            Some(crate::unwind::Unwinder::new())
+        } else {
+            None
+        };
+
+        return PerfCounters {
+            groups,
+            logical_groups,
+            pending_exec,
+            sample_type,
+            unwinder,
+            lbr_callstack,
+            symbolizer,
+            lost_samples: 0,
+            throttled: 0,
+        };
+    }
+
+    // Maps a flat counter index onto a (logical group, slot) pair
+    fn locate(&self, id: usize) -> Option<(usize, usize)> {
+        let mut first = 0;
+        for (logical_id, instances) in self.logical_groups.iter().enumerate() {
+            let len = self.groups[instances[0]].native_handles.len();
+            if id < first + len {
+                return Some((logical_id, id - first));
+            }
+            first += len;
+        }
+
+        return None;
+    }
+
+    // Sums up the instances of a counter on the given CPU and core type, the
+    // same way perf stat aggregates per-CPU and per-core-type values
+    fn combine(
+        &self,
+        id: usize,
+        cpu: Option<u32>,
+        core_type: Option<HybridCoreType>,
+    ) -> Option<crate::CounterValue> {
+        let (logical_id, slot) = self.locate(id)?;
+
+        let mut combined: Option<crate::CounterValue> = None;
+        for group_id in &self.logical_groups[logical_id] {
+            let group = &self.groups[*group_id];
+            if cpu.is_some_and(|cpu| group.cpu != cpu as i32)
+                || core_type.is_some_and(|core_type| group.core_type != Some(core_type))
+            {
+                continue;
+            }
+
+            let value = self.read_value(*group_id, slot)?;
+            combined = match combined {
+                None => Some(value),
+                Some(mut combined) => {
+                    combined.raw += value.raw;
+                    combined.time_enabled += value.time_enabled;
+                    combined.time_running += value.time_running;
+                    if combined.cpu != value.cpu {
+                        combined.cpu = None;
+                    }
+                    if combined.core_type != value.core_type {
+                        combined.core_type = None;
+                    }
+                    Some(combined)
+                }
+            };
+        }
+
+        // The counter was never opened there, e.g. an atom-only event on
+        // the P-cores, so it counted nothing
+        if combined.is_none() {
+            let first = &self.groups[self.logical_groups[logical_id][0]];
+            combined = Some(crate::CounterValue {
+                kind: first.native_handles[slot].kind.clone(),
+                cpu,
+                core_type,
+                raw: 0,
+                time_enabled: 0,
+                time_running: 0,
+            });
+        }
+
+        return combined;
+    }
+
+    fn read_value(&self, group_id: usize, slot: usize) -> Option<crate::CounterValue> {
+        let group = &self.groups[group_id];
+        let handle = &group.native_handles[slot];
+
+        // Layout of a PERF_FORMAT_GROUP read:
+        // { nr, time_enabled, time_running, { value, id } * nr }
+        let mut reader = RecordReader::new(&group.buffer);
+        let nr = reader.u64()?;
+        let time_enabled = reader.u64()?;
+        let time_running = reader.u64()?;
+
+        for _ in 0..nr {
+            let value = reader.u64()?;
+            let event_id = reader.u64()?;
+
+            if event_id == handle.id {
+                return Some(crate::CounterValue {
+                    kind: handle.kind.clone(),
+                    cpu: if group.cpu < 0 {
+                        None
+                    } else {
+                        Some(group.cpu as u32)
+                    },
+                    core_type: group.core_type,
+                    raw: value,
+                    time_enabled,
+                    time_running,
+                });
+            }
+        }
+
+        return None;
+    }
+
+    fn find_kind(&self, id: u64) -> Option<CounterKind> {
+        for g in &self.groups {
+            for c in &g.native_handles {
+                if c.id == id {
+                    return Some(c.kind.clone());
+                }
+            }
+        }
+
+        return None;
     }
 }
 
 #[cfg(target_os = "linux")]
 impl PerfCounterGroup {
-    fn new(native_handles: Vec<NativeCounterHandle>) -> PerfCounterGroup {
+    fn new(
+        cpu: i32,
+        core_type: Option<HybridCoreType>,
+        native_handles: Vec<NativeCounterHandle>,
+        ring_buffer: Option<RingBuffer>,
+    ) -> PerfCounterGroup {
         return PerfCounterGroup {
+            cpu,
+            core_type,
             native_handles,
             buffer: vec![0; 8192],
+            ring_buffer,
         };
     }
 }
 
 #[cfg(target_os = "linux")]
 impl BackendCounters for PerfCounters {
-    fn start(&mut self) {
+    fn start(&mut self) -> Result<(), Error> {
         for g in &self.groups {
             let res = unsafe {
                 sys::ioctls::RESET(
@@ -199,9 +865,13 @@ impl BackendCounters for PerfCounters {
                 )
             };
             if res < 0 {
-                panic!("Failed to reset counters");
+                return Err(Error::last_os_error());
             }
         }
+        if self.pending_exec {
+            self.pending_exec = false;
+            return Ok(());
+        }
         for g in &self.groups {
             let res_enable = unsafe {
                 sys::ioctls::ENABLE(
@@ -210,24 +880,13 @@ impl BackendCounters for PerfCounters {
                 )
             };
             if res_enable < 0 {
-                panic!("Failed to start profiling");
-            }
-        }
-        if self.pid != 0 {
-            let res = unsafe {
-                ptrace(
-                    libc::PTRACE_CONT,
-                    self.pid,
-                    std::ptr::null_mut::<libc::c_void>(),
-                    std::ptr::null_mut::<libc::c_void>(),
-                )
-            };
-            if res < 0 {
-                panic!("Failed to continue the process");
+                return Err(Error::last_os_error());
             }
         }
+
+        return Ok(());
     }
-    fn stop(&mut self) {
+    fn stop(&mut self) -> Result<(), Error> {
         for g in &self.groups {
             let res = unsafe {
                 sys::ioctls::DISABLE(
@@ -236,7 +895,7 @@ impl BackendCounters for PerfCounters {
                 )
             };
             if res < 0 {
-                panic!("Failed to reset counters");
+                return Err(Error::last_os_error());
             }
         }
         for g in &mut self.groups {
@@ -249,68 +908,237 @@ impl BackendCounters for PerfCounters {
             };
 
             if res_read < 0 {
-                panic!("Failed to read output data");
+                return Err(Error::last_os_error());
             }
         }
+
+        return Ok(());
     }
 
     fn peek(&self, id: usize) -> Option<crate::CounterValue> {
-        let group_id = id / 1;
-        let event_id = 0;
+        return self.combine(id, None, None);
+    }
 
-        if group_id >= self.groups.len() {
-            return None;
-        }
+    fn peek_cpu(&self, id: usize, cpu: u32) -> Option<crate::CounterValue> {
+        return self.combine(id, Some(cpu), None);
+    }
+
+    fn peek_core_type(&self, id: usize, core_type: HybridCoreType) -> Option<crate::CounterValue> {
+        return self.combine(id, None, Some(core_type));
+    }
+
+    fn cpus(&self) -> Vec<u32> {
+        let mut cpus: Vec<u32> = self
+            .groups
+            .iter()
+            .filter(|g| g.cpu >= 0)
+            .map(|g| g.cpu as u32)
+            .collect();
+        cpus.sort();
+        cpus.dedup();
+
+        return cpus;
+    }
 
-        // TODO how to unwrap correctly?
-        let nr =
-            *unsafe { (self.groups[group_id].buffer.as_ptr() as *const u64).as_ref() }.unwrap();
-        let time_enabled = *unsafe {
-            (self.groups[group_id].buffer.as_ptr() as *const u64)
-                .offset(1)
-                .as_ref()
+    fn core_types(&self) -> Vec<HybridCoreType> {
+        let mut core_types: Vec<HybridCoreType> =
+            self.groups.iter().filter_map(|g| g.core_type).collect();
+        core_types.sort();
+        core_types.dedup();
+
+        return core_types;
+    }
+
+    fn next_sample(&mut self) -> Option<crate::Sample> {
+        for group_id in 0..self.groups.len() {
+            loop {
+                let record = match &mut self.groups[group_id].ring_buffer {
+                    Some(ring_buffer) => ring_buffer.next_record(),
+                    None => None,
+                };
+
+                match record {
+                    Some(record) => {
+                        if record.kind == sys::bindings::PERF_RECORD_SAMPLE as u32 {
+                            let user_regs =
+                                crate::unwind::sampled_registers_mask().count_ones() as usize;
+                            let decoded =
+                                decode_sample(&record, self.sample_type, user_regs, |id| {
+                                    self.find_kind(id)
+                                });
+                            let (mut sample, user_stack) = match decoded {
+                                Some(decoded) => decoded,
+                                None => continue,
+                            };
+                            if let (Some(unwinder), Some(user_stack)) =
+                                (&mut self.unwinder, user_stack)
+                            {
+                                sample
+                                    .callchain
+                                    .extend(unwinder.unwind(sample.pid, &user_stack));
+                            }
+                            if self.lbr_callstack {
+                                // The sources of the recorded calls are the call
+                                // sites of the user frames
+                                if sample.kernel_frames == 0 {
+                                    sample.callchain.push(sample.ip);
+                                }
+                                sample
+                                    .callchain
+                                    .extend(sample.branches.drain(..).map(|b| b.from));
+                            }
+                            return Some(sample);
+                        }
+                        if record.kind == sys::bindings::PERF_RECORD_LOST as u32 {
+                            // { id, lost }, written once the buffer has room again
+                            let mut reader = RecordReader::new(&record.data);
+                            reader.u64();
+                            self.lost_samples += reader.u64().unwrap_or(0);
+                        } else if record.kind == sys::bindings::PERF_RECORD_LOST_SAMPLES as u32 {
+                            let mut reader = RecordReader::new(&record.data);
+                            self.lost_samples += reader.u64().unwrap_or(0);
+                        } else if record.kind == sys::bindings::PERF_RECORD_THROTTLE as u32 {
+                            self.throttled += 1;
+                        } else if let Some(symbolizer) = &mut self.symbolizer {
+                            track_record(&record, symbolizer);
+                        }
+                    }
+                    None => break,
+                }
+            }
         }
-        .unwrap();
-        let time_running = *unsafe {
-            (self.groups[group_id].buffer.as_ptr() as *const u64)
-                .offset(2)
-                .as_ref()
+
+        return None;
+    }
+
+    fn symbolize(&mut self, pid: u32, ip: u64) -> Option<crate::Symbol> {
+        return self.symbolizer.as_mut()?.symbolize(pid, ip);
+    }
+
+    fn lost_samples(&self) -> u64 {
+        return self.lost_samples;
+    }
+
+    fn throttled(&self) -> u64 {
+        return self.throttled;
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for NativeCounterHandle {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
         }
-        .unwrap();
+    }
+}
 
-        let div = (time_enabled as f32) / (time_running as f32);
+// Parses a CPU list in the kernel format, e.g. "0-3,5,7-8"
+#[cfg(target_os = "linux")]
+pub(crate) fn parse_cpu_list(list: &str) -> Option<Vec<i32>> {
+    let mut cpus = vec![];
 
-        if event_id >= nr as usize {
-            return None;
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let first = first.parse::<i32>().ok()?;
+                let last = last.parse::<i32>().ok()?;
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(range.parse::<i32>().ok()?),
         }
+    }
 
-        let slice = unsafe {
-            std::slice::from_raw_parts(
-                self.groups[group_id]
-                    .buffer
-                    .as_ptr()
-                    .offset((3 * std::mem::size_of::<u64>()) as isize)
-                    as *const RFValues,
-                nr as usize,
-            )
-        };
+    return Some(cpus);
+}
 
-        let mut cv = crate::CounterValue {
-            kind: CounterKind::Cycles,
-            value: (slice[event_id].value as f32 / div) as usize,
-        };
+#[cfg(target_os = "linux")]
+fn online_cpus() -> Result<Vec<i32>, Error> {
+    let list = std::fs::read_to_string("/sys/devices/system/cpu/online")?;
+    return parse_cpu_list(&list).ok_or(Error::InvalidConfiguration(format!(
+        "Failed to parse online CPU list '{}'",
+        list.trim()
+    )));
+}
 
-        // TODO(Alex): use find
-        for g in &self.groups {
-            for c in &g.native_handles {
-                if slice[event_id].id == c.id {
-                    cv.kind = c.kind.clone();
-                    break;
-                }
-            }
+#[cfg(target_os = "linux")]
+fn is_core_event(counter: &CounterKind) -> bool {
+    match counter {
+        CounterKind::System(SystemCounter {
+            kind: SystemCounterKind::Pmu { pmu, .. },
+            ..
+        }) => pmu.starts_with("cpu"),
+        // Probes and tracepoints are counted by the kernel, excluding it would hide them
+        CounterKind::Tracepoint { .. }
+        | CounterKind::Kprobe { .. }
+        | CounterKind::Uprobe { .. } => false,
+        _ => true,
+    }
+}
+
+// AMD Instruction Based Sampling, ibs_op or ibs_fetch
+#[cfg(target_os = "linux")]
+fn is_ibs_event(counter: &CounterKind) -> bool {
+    match counter {
+        CounterKind::System(SystemCounter {
+            kind: SystemCounterKind::Pmu { pmu, .. },
+            ..
+        }) => pmu.starts_with("ibs_"),
+        _ => false,
+    }
+}
+
+// Index of the counter that samples, the leader unless it is the auxiliary
+// event the load latency event has to be grouped with
+#[cfg(target_os = "linux")]
+fn sampled_counter(group: &CountersGroup) -> usize {
+    match &group.counters[0].counter {
+        CounterKind::System(counter)
+            if counter.name == "mem-loads-aux" && group.counters.len() > 1 =>
+        {
+            1
         }
+        _ => 0,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn branch_sample_type(filter: &crate::BranchFilter) -> u64 {
+    let flags = [
+        (filter.any, sys::bindings::PERF_SAMPLE_BRANCH_ANY),
+        (filter.call, sys::bindings::PERF_SAMPLE_BRANCH_ANY_CALL),
+        (filter.ret, sys::bindings::PERF_SAMPLE_BRANCH_ANY_RETURN),
+        (filter.cond, sys::bindings::PERF_SAMPLE_BRANCH_COND),
+        (filter.ind_jmp, sys::bindings::PERF_SAMPLE_BRANCH_IND_JUMP),
+        (filter.user, sys::bindings::PERF_SAMPLE_BRANCH_USER),
+        (filter.kernel, sys::bindings::PERF_SAMPLE_BRANCH_KERNEL),
+    ];
+
+    return flags
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |mask, (_, flag)| mask | *flag as u64);
+}
 
-        return Some(cv);
+#[cfg(target_os = "linux")]
+fn read_paranoid_level() -> Option<i32> {
+    let level = std::fs::read_to_string("/proc/sys/kernel/perf_event_paranoid").ok()?;
+    return level.trim().parse::<i32>().ok();
+}
+
+#[cfg(target_os = "linux")]
+fn open_error(counter: &CounterKind) -> Error {
+    let err = std::io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EACCES) | Some(libc::EPERM) => Error::PermissionDenied(read_paranoid_level()),
+        Some(libc::ENOENT) | Some(libc::ENODEV) | Some(libc::EOPNOTSUPP) => {
+            Error::EventNotSupported(counter.to_string())
+        }
+        Some(libc::ENOSPC) => Error::TooManyCounters {
+            requested: 1,
+            max: None,
+        },
+        _ => Error::Io(err),
     }
 }
 
@@ -318,65 +1146,213 @@ pub(crate) fn get_software_events() -> Vec<crate::SystemCounter> {
     let events = vec![
         SystemCounter {
             kind: SystemCounterKind::Software,
-            name: "cpu_clock",
-            desc: "A high-resolution per-CPU timer",
+            name: Cow::Borrowed("cpu_clock"),
+            desc: Cow::Borrowed("A high-resolution per-CPU timer"),
+            precise: Some(false),
+            counters: None,
             encoding: sys::bindings::PERF_COUNT_SW_CPU_CLOCK as u64,
+            config1: 0,
+            config2: 0,
         },
         SystemCounter {
             kind: SystemCounterKind::Software,
-            name: "task_clock",
-            desc: "Clock count specific to the task that is running",
+            name: Cow::Borrowed("task_clock"),
+            desc: Cow::Borrowed("Clock count specific to the task that is running"),
+            precise: Some(false),
+            counters: None,
             encoding: sys::bindings::PERF_COUNT_SW_TASK_CLOCK as u64,
+            config1: 0,
+            config2: 0,
         },
         SystemCounter {
             kind: SystemCounterKind::Software,
-            name: "page_faults",
-            desc: "Number of page faults",
+            name: Cow::Borrowed("page_faults"),
+            desc: Cow::Borrowed("Number of page faults"),
+            precise: Some(false),
+            counters: None,
             encoding: sys::bindings::PERF_COUNT_SW_PAGE_FAULTS as u64,
+            config1: 0,
+            config2: 0,
         },
         SystemCounter {
             kind: SystemCounterKind::Software,
-            name: "context_switches",
-            desc: "Number of context switches",
+            name: Cow::Borrowed("context_switches"),
+            desc: Cow::Borrowed("Number of context switches"),
+            precise: Some(false),
+            counters: None,
             encoding: sys::bindings::PERF_COUNT_SW_CONTEXT_SWITCHES as u64,
+            config1: 0,
+            config2: 0,
         },
         SystemCounter {
             kind: SystemCounterKind::Software,
-            name: "cpu_migrations",
-            desc: "Number of times the process has migrated to a new CPU",
+            name: Cow::Borrowed("cpu_migrations"),
+            desc: Cow::Borrowed("Number of times the process has migrated to a new CPU"),
+            precise: Some(false),
+            counters: None,
             encoding: sys::bindings::PERF_COUNT_SW_CPU_MIGRATIONS as u64,
+            config1: 0,
+            config2: 0,
         },
         SystemCounter {
             kind: SystemCounterKind::Software,
-            name: "page_faults_min",
-            desc: "Number of minor page faults",
+            name: Cow::Borrowed("page_faults_min"),
+            desc: Cow::Borrowed("Number of minor page faults"),
+            precise: Some(false),
+            counters: None,
             encoding: sys::bindings::PERF_COUNT_SW_PAGE_FAULTS_MIN as u64,
+            config1: 0,
+            config2: 0,
         },
         SystemCounter {
             kind: SystemCounterKind::Software,
-            name: "page_faults_maj",
-            desc: "Number of major page faults",
+            name: Cow::Borrowed("page_faults_maj"),
+            desc: Cow::Borrowed("Number of major page faults"),
+            precise: Some(false),
+            counters: None,
             encoding: sys::bindings::PERF_COUNT_SW_PAGE_FAULTS_MAJ as u64,
+            config1: 0,
+            config2: 0,
         },
         SystemCounter {
             kind: SystemCounterKind::Software,
-            name: "alignment_faults",
-            desc: "Number of unaligned memory accesses",
+            name: Cow::Borrowed("alignment_faults"),
+            desc: Cow::Borrowed("Number of unaligned memory accesses"),
+            precise: Some(false),
+            counters: None,
             encoding: sys::bindings::PERF_COUNT_SW_ALIGNMENT_FAULTS as u64,
+            config1: 0,
+            config2: 0,
         },
         SystemCounter {
             kind: SystemCounterKind::Software,
-            name: "emulation_faults",
-            desc: "Number of emulation faults",
+            name: Cow::Borrowed("emulation_faults"),
+            desc: Cow::Borrowed("Number of emulation faults"),
+            precise: Some(false),
+            counters: None,
             encoding: sys::bindings::PERF_COUNT_SW_EMULATION_FAULTS as u64,
+            config1: 0,
+            config2: 0,
         },
         SystemCounter {
             kind: SystemCounterKind::Software,
-            name: "dummy",
-            desc: "A placeholder event",
+            name: Cow::Borrowed("dummy"),
+            desc: Cow::Borrowed("A placeholder event"),
+            precise: Some(false),
+            counters: None,
             encoding: sys::bindings::PERF_COUNT_SW_DUMMY as u64,
+            config1: 0,
+            config2: 0,
         },
     ];
 
     return events;
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use sys::bindings::{
+        PERF_SAMPLE_BRANCH_ANY, PERF_SAMPLE_BRANCH_ANY_CALL, PERF_SAMPLE_BRANCH_ANY_RETURN,
+        PERF_SAMPLE_BRANCH_COND, PERF_SAMPLE_BRANCH_IND_JUMP, PERF_SAMPLE_BRANCH_KERNEL,
+        PERF_SAMPLE_BRANCH_USER,
+    };
+
+    #[test]
+    fn branch_filter_sets_its_flags() {
+        let filter = crate::BranchFilter::default();
+        assert_eq!(branch_sample_type(&filter), 0);
+
+        let flags: [(fn(&mut crate::BranchFilter), u32); 7] = [
+            (|filter| filter.any = true, PERF_SAMPLE_BRANCH_ANY),
+            (|filter| filter.call = true, PERF_SAMPLE_BRANCH_ANY_CALL),
+            (|filter| filter.ret = true, PERF_SAMPLE_BRANCH_ANY_RETURN),
+            (|filter| filter.cond = true, PERF_SAMPLE_BRANCH_COND),
+            (|filter| filter.ind_jmp = true, PERF_SAMPLE_BRANCH_IND_JUMP),
+            (|filter| filter.user = true, PERF_SAMPLE_BRANCH_USER),
+            (|filter| filter.kernel = true, PERF_SAMPLE_BRANCH_KERNEL),
+        ];
+        for (set, flag) in flags {
+            let mut filter = crate::BranchFilter::default();
+            set(&mut filter);
+            assert_eq!(branch_sample_type(&filter), flag as u64, "{:?}", filter);
+        }
+
+        let filter = crate::BranchFilter {
+            call: true,
+            ret: true,
+            user: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            branch_sample_type(&filter),
+            (PERF_SAMPLE_BRANCH_ANY_CALL | PERF_SAMPLE_BRANCH_ANY_RETURN | PERF_SAMPLE_BRANCH_USER)
+                as u64
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn cpu_info_probe_keeps_the_affinity() {
+        let size = std::mem::size_of::<libc::cpu_set_t>();
+        let affinity = || unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            assert_eq!(libc::sched_getaffinity(0, size, &mut set), 0);
+            set
+        };
+
+        let before = affinity();
+        let cpu = (0..libc::CPU_SETSIZE as usize)
+            .find(|cpu| unsafe { libc::CPU_ISSET(*cpu, &before) })
+            .unwrap();
+        assert!(cpu_info_on(cpu as u32).is_some());
+        assert!(unsafe { libc::CPU_EQUAL(&before, &affinity()) });
+
+        assert!(cpu_info_on(libc::CPU_SETSIZE as u32).is_none());
+        assert!(cpu_info_on(u32::MAX).is_none());
+    }
+
+    fn hybrid_pmus() -> Vec<(HybridCoreType, crate::backends::Pmu)> {
+        let pmu = |name: &str, type_id, cpus| crate::backends::Pmu {
+            name: name.to_string(),
+            type_id,
+            formats: vec![],
+            events: vec![],
+            cpus,
+        };
+        return vec![
+            (HybridCoreType::Core, pmu("cpu_core", 4, vec![0, 1])),
+            (HybridCoreType::Atom, pmu("cpu_atom", 10, vec![2, 3])),
+        ];
+    }
+
+    #[test]
+    fn probes_are_opened_once_on_hybrid_cpus() {
+        let hybrid = hybrid_pmus();
+
+        let mut group = CountersGroup::new();
+        group.add_counter(CounterKind::Tracepoint {
+            subsystem: "sched".to_string(),
+            name: "sched_switch".to_string(),
+        });
+        group.add_counter(CounterKind::Kprobe {
+            function: "do_sys_open".to_string(),
+        });
+        group.add_counter(CounterKind::Uprobe {
+            binary: "/bin/true".to_string(),
+            symbol_or_offset: "main".to_string(),
+        });
+        let targets = PerfBackend::group_targets(&group, &hybrid).unwrap();
+        assert_eq!(targets.len(), 1);
+        assert!(targets[0].is_none());
+
+        // Core PMU events still take one group per core type
+        group.add_counter(CounterKind::Cycles);
+        let targets = PerfBackend::group_targets(&group, &hybrid).unwrap();
+        let core_types: Vec<HybridCoreType> = targets
+            .iter()
+            .map(|target| target.as_ref().unwrap().core_type)
+            .collect();
+        assert_eq!(core_types, vec![HybridCoreType::Core, HybridCoreType::Atom]);
+    }
+}
@@ -0,0 +1,508 @@
+use crate::backends::perf::parse_cpu_list;
+use crate::{CounterKind, CountersGroup, Error, HybridCoreType, MemorySampling};
+use crate::{SamplingPrecision, SystemCounter, SystemCounterKind, TopDownLevel};
+use std::borrow::Cow;
+use std::path::Path;
+use std::sync::OnceLock;
+
+const EVENT_SOURCE_DEVICES: &str = "/sys/bus/event_source/devices";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PmuConfigField {
+    Config,
+    Config1,
+    Config2,
+}
+
+// A named bit field of perf_event_attr, as described in format/
+#[derive(Debug, Clone)]
+pub struct PmuFormat {
+    pub name: String,
+    pub field: PmuConfigField,
+    // Inclusive bit ranges, filled from the lowest bits of the value up
+    pub bits: Vec<(u32, u32)>,
+}
+
+// An event alias advertised by the kernel in events/
+#[derive(Debug, Clone)]
+pub struct PmuEvent {
+    pub name: String,
+    pub terms: String,
+    pub scale: Option<f64>,
+    pub unit: Option<String>,
+    pub config: u64,
+    pub config1: u64,
+    pub config2: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Pmu {
+    pub name: String,
+    pub type_id: u32,
+    pub formats: Vec<PmuFormat>,
+    pub events: Vec<PmuEvent>,
+    // CPUs the PMU counts on, empty when it is not restricted
+    pub cpus: Vec<u32>,
+}
+
+impl PmuFormat {
+    fn parse(name: &str, spec: &str) -> Option<PmuFormat> {
+        let (field, ranges) = spec.trim().split_once(':')?;
+        let field = match field {
+            "config" => PmuConfigField::Config,
+            "config1" => PmuConfigField::Config1,
+            "config2" => PmuConfigField::Config2,
+            _ => return None,
+        };
+
+        let mut bits = vec![];
+        for range in ranges.split(',') {
+            let (lo, hi) = match range.split_once('-') {
+                Some((lo, hi)) => (lo.parse::<u32>().ok()?, hi.parse::<u32>().ok()?),
+                None => {
+                    let bit = range.parse::<u32>().ok()?;
+                    (bit, bit)
+                }
+            };
+            if lo > hi || hi > 63 {
+                return None;
+            }
+            bits.push((lo, hi));
+        }
+
+        return Some(PmuFormat {
+            name: name.to_string(),
+            field,
+            bits,
+        });
+    }
+
+    pub fn width(&self) -> u32 {
+        return self.bits.iter().map(|(lo, hi)| hi - lo + 1).sum();
+    }
+
+    // Spreads value over the bit ranges, fails if it does not fit
+    pub fn encode(&self, value: u64) -> Option<u64> {
+        if self.width() < 64 && value >> self.width() != 0 {
+            return None;
+        }
+
+        let mut encoded = 0;
+        let mut remaining = value;
+        for (lo, hi) in &self.bits {
+            let width = hi - lo + 1;
+            let mask = if width == 64 {
+                u64::MAX
+            } else {
+                (1 << width) - 1
+            };
+            encoded |= (remaining & mask) << lo;
+            remaining = if width == 64 { 0 } else { remaining >> width };
+        }
+
+        return Some(encoded);
+    }
+
+    // Every bit of the field
+    pub fn mask(&self) -> u64 {
+        let width = self.width();
+        let all = if width >= 64 {
+            u64::MAX
+        } else {
+            (1 << width) - 1
+        };
+        return self.encode(all).unwrap_or(0);
+    }
+}
+
+impl Pmu {
+    pub fn find_event(&self, name: &str) -> Option<&PmuEvent> {
+        return self.events.iter().find(|e| e.name == name);
+    }
+
+    // Encodes perf style terms, e.g. "event=0x3c,umask=1,edge" or an event
+    // alias optionally followed by more terms, into (config, config1, config2).
+    // Like in perf, a term overrides the same field set by an earlier one or
+    // by the alias, e.g. "mem-loads,ldlat=30".
+    pub fn encode(&self, terms: &str) -> Result<(u64, u64, u64), Error> {
+        let mut configs = [0u64; 3];
+        self.encode_into(terms, true, &mut configs)?;
+
+        return Ok((configs[0], configs[1], configs[2]));
+    }
+
+    fn encode_into(
+        &self,
+        terms: &str,
+        expand_aliases: bool,
+        configs: &mut [u64; 3],
+    ) -> Result<(), Error> {
+        for term in terms.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            let (name, value) = match term.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim())),
+                None => (term, None),
+            };
+
+            if value.is_none() && expand_aliases {
+                // The alias terms are applied in its place
+                if let Some(event) = self.find_event(name) {
+                    self.encode_into(&event.terms, false, configs)?;
+                    continue;
+                }
+            }
+
+            let value = match value {
+                Some(value) => parse_term_value(value)?,
+                None => 1,
+            };
+
+            match name {
+                "config" => configs[0] = value,
+                "config1" => configs[1] = value,
+                "config2" => configs[2] = value,
+                _ => {
+                    let format = self.formats.iter().find(|f| f.name == name).ok_or(
+                        Error::InvalidConfiguration(format!(
+                            "Unknown term {} for PMU {}",
+                            name, self.name
+                        )),
+                    )?;
+                    let encoded =
+                        format
+                            .encode(value)
+                            .ok_or(Error::InvalidConfiguration(format!(
+                                "Value {:#x} does not fit into {}/{}",
+                                value, self.name, name
+                            )))?;
+                    let slot = match format.field {
+                        PmuConfigField::Config => 0,
+                        PmuConfigField::Config1 => 1,
+                        PmuConfigField::Config2 => 2,
+                    };
+                    configs[slot] = (configs[slot] & !format.mask()) | encoded;
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    pub(crate) fn to_system_counter(
+        &self,
+        name: &str,
+        desc: &str,
+        (config, config1, config2): (u64, u64, u64),
+    ) -> SystemCounter {
+        return SystemCounter {
+            kind: SystemCounterKind::Pmu {
+                pmu: self.name.clone(),
+                type_id: self.type_id,
+            },
+            name: Cow::Owned(name.to_string()),
+            desc: Cow::Owned(desc.to_string()),
+            // Left to the kernel to reject
+            precise: None,
+            counters: None,
+            encoding: config,
+            config1,
+            config2,
+        };
+    }
+}
+
+fn parse_term_value(value: &str) -> Result<u64, Error> {
+    let parsed = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse::<u64>(),
+    };
+
+    return parsed.map_err(|_| Error::InvalidConfiguration(format!("Invalid number {}", value)));
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    return std::fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string());
+}
+
+fn read_pmu(path: &Path) -> Option<Pmu> {
+    let name = path.file_name()?.to_str()?.to_string();
+    let type_id = read_trimmed(&path.join("type"))?.parse::<u32>().ok()?;
+
+    let mut formats = vec![];
+    if let Ok(entries) = std::fs::read_dir(path.join("format")) {
+        for entry in entries.flatten() {
+            let format_name = entry.file_name().to_string_lossy().to_string();
+            if let Some(format) =
+                read_trimmed(&entry.path()).and_then(|spec| PmuFormat::parse(&format_name, &spec))
+            {
+                formats.push(format);
+            }
+        }
+    }
+    formats.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let cpus = read_trimmed(&path.join("cpus"))
+        .or_else(|| read_trimmed(&path.join("cpumask")))
+        .and_then(|list| parse_cpu_list(&list))
+        .map(|cpus| cpus.into_iter().map(|cpu| cpu as u32).collect())
+        .unwrap_or_default();
+
+    let mut pmu = Pmu {
+        name,
+        type_id,
+        formats,
+        events: vec![],
+        cpus,
+    };
+
+    let events_dir = path.join("events");
+    let mut events = vec![];
+    if let Ok(entries) = std::fs::read_dir(&events_dir) {
+        for entry in entries.flatten() {
+            let event_name = entry.file_name().to_string_lossy().to_string();
+            // Attributes of other events, e.g. energy-pkg.scale
+            if event_name.contains('.') {
+                continue;
+            }
+            let terms = match read_trimmed(&entry.path()) {
+                Some(terms) => terms,
+                None => continue,
+            };
+            // Events the kernel advertises in a format we cannot decode are skipped
+            let (config, config1, config2) = match pmu.encode(&terms) {
+                Ok(configs) => configs,
+                Err(_) => continue,
+            };
+
+            events.push(PmuEvent {
+                scale: read_trimmed(&events_dir.join(format!("{}.scale", event_name)))
+                    .and_then(|s| s.parse::<f64>().ok()),
+                unit: read_trimmed(&events_dir.join(format!("{}.unit", event_name))),
+                name: event_name,
+                terms,
+                config,
+                config1,
+                config2,
+            });
+        }
+    }
+    events.sort_by(|a, b| a.name.cmp(&b.name));
+    pmu.events = events;
+
+    return Some(pmu);
+}
+
+fn scan_pmus() -> Vec<Pmu> {
+    let mut pmus = vec![];
+
+    if let Ok(entries) = std::fs::read_dir(EVENT_SOURCE_DEVICES) {
+        for entry in entries.flatten() {
+            if let Some(pmu) = read_pmu(&entry.path()) {
+                pmus.push(pmu);
+            }
+        }
+    }
+    pmus.sort_by(|a, b| a.name.cmp(&b.name));
+
+    return pmus;
+}
+
+// PMUs are registered once at boot or module load, so sysfs is only read once
+fn cached_pmus() -> &'static [Pmu] {
+    static PMUS: OnceLock<Vec<Pmu>> = OnceLock::new();
+    return PMUS.get_or_init(scan_pmus);
+}
+
+pub(crate) fn list_pmus() -> Vec<Pmu> {
+    return cached_pmus().to_vec();
+}
+
+pub(crate) fn find_pmu(name: &str) -> Option<Pmu> {
+    return cached_pmus().iter().find(|pmu| pmu.name == name).cloned();
+}
+
+// Core PMUs of a hybrid CPU, empty when the CPU has a single "cpu" PMU
+pub(crate) fn hybrid_pmus() -> Vec<(HybridCoreType, Pmu)> {
+    let mut pmus = vec![];
+
+    for core_type in [HybridCoreType::Core, HybridCoreType::Atom] {
+        if let Some(pmu) = find_pmu(core_type.pmu_name()) {
+            pmus.push((core_type, pmu));
+        }
+    }
+
+    return pmus;
+}
+
+// slots followed by the metric events of the level, on hybrid CPUs they
+// only exist on the P-cores
+pub(crate) fn topdown_group(level: TopDownLevel) -> Result<CountersGroup, Error> {
+    let pmu = find_pmu(HybridCoreType::Core.pmu_name())
+        .or_else(|| find_pmu("cpu"))
+        .ok_or(Error::EventNotSupported("slots".to_string()))?;
+
+    let mut group = CountersGroup::new();
+    for name in level.event_names() {
+        // Kernels before 5.9, or 5.13 for level 2, do not know the events
+        let event = pmu
+            .find_event(name)
+            .ok_or(Error::EventNotSupported(format!("{}/{}/", pmu.name, name)))?;
+        group.add_counter(CounterKind::System(pmu.to_system_counter(
+            name,
+            "",
+            (event.config, event.config1, event.config2),
+        )));
+    }
+
+    return Ok(group);
+}
+
+// IBS op on AMD tags every kind of access, Intel has separate precise
+// events for loads above a latency threshold and for stores. Instruction
+// fetches are only sampled by IBS fetch.
+pub(crate) fn memory_sampling_group(kind: MemorySampling) -> Result<CountersGroup, Error> {
+    let mut group = CountersGroup::new();
+
+    if kind == MemorySampling::Fetches {
+        let ibs = find_pmu("ibs_fetch").ok_or(Error::EventNotSupported("ibs_fetch".to_string()))?;
+        group.add_counter(CounterKind::System(ibs.to_system_counter(
+            "ibs_fetch",
+            "",
+            ibs.encode("")?,
+        )));
+        return Ok(group);
+    }
+
+    if let Some(ibs) = find_pmu("ibs_op") {
+        // Only Zen 5 and newer kernels have a load latency filter, encode
+        // rejects the term everywhere else
+        let terms = match kind {
+            MemorySampling::Loads { latency_threshold } if latency_threshold > 0 => {
+                format!("ldlat={}", latency_threshold)
+            }
+            MemorySampling::Loads { .. } => String::new(),
+            // Every sampled op is tagged, stores cannot be picked out
+            MemorySampling::Stores | MemorySampling::Fetches => {
+                return Err(Error::EventNotSupported("ibs_op stores".to_string()))
+            }
+        };
+        group.add_counter(CounterKind::System(ibs.to_system_counter(
+            "ibs_op",
+            "",
+            ibs.encode(&terms)?,
+        )));
+        return Ok(group);
+    }
+
+    let pmu = find_pmu(HybridCoreType::Core.pmu_name())
+        .or_else(|| find_pmu("cpu"))
+        .ok_or(Error::EventNotSupported("mem-loads".to_string()))?;
+    let (name, terms) = match kind {
+        MemorySampling::Loads { latency_threshold } if latency_threshold > 0 => (
+            "mem-loads",
+            format!("mem-loads,ldlat={}", latency_threshold),
+        ),
+        MemorySampling::Loads { .. } => ("mem-loads", "mem-loads".to_string()),
+        MemorySampling::Stores => ("mem-stores", "mem-stores".to_string()),
+        MemorySampling::Fetches => return Err(Error::EventNotSupported("ibs_fetch".to_string())),
+    };
+    if pmu.find_event(name).is_none() {
+        return Err(Error::EventNotSupported(format!("{}/{}/", pmu.name, name)));
+    }
+
+    // Sapphire Rapids and the hybrid P-cores only take loads in a group
+    // led by the auxiliary event, which is counted but never sampled
+    if name == "mem-loads" {
+        if let Some(aux) = pmu.find_event("mem-loads-aux") {
+            group.add_counter(CounterKind::System(pmu.to_system_counter(
+                "mem-loads-aux",
+                "",
+                (aux.config, aux.config1, aux.config2),
+            )));
+        }
+    }
+    group.add_counter_precise(
+        CounterKind::System(pmu.to_system_counter(name, "", pmu.encode(&terms)?)),
+        SamplingPrecision::RequestNoSkid,
+    );
+
+    return Ok(group);
+}
+
+// Events advertised by the kernel, named the same way as perf does, e.g. "cpu/cache-misses/"
+pub(crate) fn get_kernel_events() -> Vec<SystemCounter> {
+    static EVENTS: OnceLock<Vec<SystemCounter>> = OnceLock::new();
+
+    let events = EVENTS.get_or_init(|| {
+        let mut events = vec![];
+        for pmu in cached_pmus() {
+            for event in &pmu.events {
+                // sysfs carries no descriptions, only the encoding
+                events.push(pmu.to_system_counter(
+                    &event.name,
+                    "",
+                    (event.config, event.config1, event.config2),
+                ));
+            }
+        }
+        events
+    });
+
+    return events.clone();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A PMU laid out like the ones in /sys/bus/event_source/devices
+    fn fake_pmu(test: &str) -> Pmu {
+        let root = std::env::temp_dir().join(format!("libpmu-{}-{}", test, std::process::id()));
+        let dir = root.join("cpu");
+        std::fs::create_dir_all(dir.join("format")).unwrap();
+        std::fs::create_dir_all(dir.join("events")).unwrap();
+
+        let write = |path: &str, contents: &str| std::fs::write(dir.join(path), contents).unwrap();
+        write("type", "4\n");
+        write("format/event", "config:0-7\n");
+        write("format/umask", "config:8-15\n");
+        write("format/edge", "config:18\n");
+        write("format/ldlat", "config1:0-15\n");
+        write("events/mem-loads", "event=0xcd,umask=0x1,ldlat=3\n");
+        write("events/mem-loads.unit", "loads\n");
+
+        let pmu = read_pmu(&dir).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        return pmu;
+    }
+
+    #[test]
+    fn reads_formats_and_aliases() {
+        let pmu = fake_pmu("read");
+        assert_eq!(pmu.name, "cpu");
+        assert_eq!(pmu.type_id, 4);
+        assert_eq!(pmu.formats.len(), 4);
+
+        let event = pmu.find_event("mem-loads").unwrap();
+        assert_eq!((event.config, event.config1), (0x1cd, 3));
+        assert_eq!(event.unit.as_deref(), Some("loads"));
+    }
+
+    #[test]
+    fn later_terms_override_earlier_ones() {
+        let pmu = fake_pmu("override");
+
+        assert_eq!(pmu.encode("mem-loads").unwrap(), (0x1cd, 3, 0));
+        assert_eq!(pmu.encode("mem-loads,ldlat=30").unwrap(), (0x1cd, 30, 0));
+        assert_eq!(
+            pmu.encode("mem-loads,umask=2,edge").unwrap(),
+            (0x402cd, 3, 0)
+        );
+        assert_eq!(pmu.encode("event=0x3c,event=0xc0").unwrap(), (0xc0, 0, 0));
+        assert_eq!(pmu.encode("ldlat=30,mem-loads").unwrap(), (0x1cd, 3, 0));
+
+        assert!(pmu.encode("umask=0x100").is_err());
+        assert!(pmu.encode("cmask=1").is_err());
+    }
+}
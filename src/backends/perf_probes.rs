@@ -0,0 +1,181 @@
+use crate::backends::find_pmu;
+use crate::maps::{address_file_offset, load_segments};
+use crate::Error;
+use object::{Object, ObjectSymbol, SymbolKind};
+use std::ffi::CString;
+
+// tracefs is mounted on its own since 4.1, older systems only have it in debugfs
+const TRACEFS_EVENTS: [&str; 2] = [
+    "/sys/kernel/tracing/events",
+    "/sys/kernel/debug/tracing/events",
+];
+
+// What perf_event_open needs to create a kprobe or uprobe on the fly. The
+// kernel copies target during the call, so it only has to outlive the call.
+pub(crate) struct ProbeAttrs {
+    pub type_id: u32,
+    // Function name of a kprobe or path of the probed binary, goes into config1
+    pub target: Option<CString>,
+    // Offset from the function or into the binary, or the kernel address, goes into config2
+    pub offset: u64,
+}
+
+fn parse_number(value: &str) -> Option<u64> {
+    return match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse::<u64>().ok(),
+    };
+}
+
+// Splits "name+offset" the way perf probe writes probe points
+fn split_offset(location: &str) -> Result<(&str, u64), Error> {
+    return match location.split_once('+') {
+        Some((name, offset)) => match parse_number(offset.trim()) {
+            Some(offset) => Ok((name.trim(), offset)),
+            None => Err(Error::InvalidConfiguration(format!(
+                "Invalid probe offset in {}",
+                location
+            ))),
+        },
+        None => Ok((location.trim(), 0)),
+    };
+}
+
+// Event ID the kernel assigned to a tracepoint, as used for config of PERF_TYPE_TRACEPOINT
+pub(crate) fn tracepoint_id(subsystem: &str, name: &str) -> Result<u64, Error> {
+    for events in TRACEFS_EVENTS {
+        match std::fs::read_to_string(format!("{}/{}/{}/id", events, subsystem, name)) {
+            Ok(id) => {
+                return id.trim().parse::<u64>().map_err(|_| {
+                    Error::InvalidConfiguration(format!(
+                        "Invalid ID of tracepoint {}:{}",
+                        subsystem, name
+                    ))
+                })
+            }
+            // tracefs is usually only readable by root
+            Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                return Err(Error::PermissionDenied(None))
+            }
+            Err(_) => continue,
+        }
+    }
+
+    return Err(Error::EventNotSupported(format!("{}:{}", subsystem, name)));
+}
+
+fn probe_pmu(name: &str, counter: &str) -> Result<u32, Error> {
+    return match find_pmu(name) {
+        Some(pmu) => Ok(pmu.type_id),
+        // The dynamic probe PMUs were added in 4.17
+        None => Err(Error::EventNotSupported(counter.to_string())),
+    };
+}
+
+// A kernel function name with an optional +offset, or a kernel address
+pub(crate) fn kprobe_attrs(function: &str) -> Result<ProbeAttrs, Error> {
+    let type_id = probe_pmu("kprobe", &format!("kprobe:{}", function))?;
+
+    let (name, offset) = kprobe_target(function)?;
+    let target = match name {
+        Some(name) => Some(CString::new(name).map_err(|_| {
+            Error::InvalidConfiguration(format!("Invalid kprobe function {}", function))
+        })?),
+        None => None,
+    };
+
+    return Ok(ProbeAttrs {
+        type_id,
+        target,
+        offset,
+    });
+}
+
+// (function, offset from it) of a kprobe, or no function and the kernel address
+fn kprobe_target(function: &str) -> Result<(Option<&str>, u64), Error> {
+    if let Some(address) = parse_number(function.trim()) {
+        return Ok((None, address));
+    }
+
+    let (name, offset) = split_offset(function)?;
+    return Ok((Some(name), offset));
+}
+
+// Offset in the binary of a symbol with an optional +offset, or a plain file offset
+pub(crate) fn uprobe_attrs(binary: &str, symbol_or_offset: &str) -> Result<ProbeAttrs, Error> {
+    let type_id = probe_pmu("uprobe", &format!("uprobe:{}:{}", binary, symbol_or_offset))?;
+    let offset = match parse_number(symbol_or_offset.trim()) {
+        Some(offset) => offset,
+        None => {
+            let (symbol, offset) = split_offset(symbol_or_offset)?;
+            symbol_file_offset(binary, symbol)? + offset
+        }
+    };
+    let target = CString::new(binary)
+        .map_err(|_| Error::InvalidConfiguration(format!("Invalid uprobe binary {}", binary)))?;
+
+    return Ok(ProbeAttrs {
+        type_id,
+        target: Some(target),
+        offset,
+    });
+}
+
+// Symbols match by their mangled or demangled name
+fn symbol_file_offset(binary: &str, symbol: &str) -> Result<u64, Error> {
+    let data = std::fs::read(binary)?;
+    let file = object::File::parse(&*data)
+        .map_err(|_| Error::InvalidConfiguration(format!("{} is not an ELF file", binary)))?;
+
+    let address = file
+        .symbols()
+        .chain(file.dynamic_symbols())
+        .filter(|s| s.kind() == SymbolKind::Text && s.address() != 0)
+        .find(|s| match s.name() {
+            Ok(name) => name == symbol || crate::symbolizer::demangle(name) == symbol,
+            Err(_) => false,
+        })
+        .map(|s| s.address())
+        .ok_or(Error::EventNotSupported(format!(
+            "uprobe:{}:{}",
+            binary, symbol
+        )))?;
+
+    return address_file_offset(&load_segments(&file), address).ok_or(Error::InvalidConfiguration(
+        format!(
+            "Symbol {} of {} is not in a loadable segment",
+            symbol, binary
+        ),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_probe_offsets() {
+        assert_eq!(split_offset("main").unwrap(), ("main", 0));
+        assert_eq!(split_offset("main+0x10").unwrap(), ("main", 0x10));
+        assert_eq!(split_offset(" main + 16 ").unwrap(), ("main", 16));
+        assert!(split_offset("main+").is_err());
+        assert!(split_offset("main+0xzz").is_err());
+    }
+
+    #[test]
+    fn parses_kprobe_targets() {
+        assert_eq!(
+            kprobe_target("0xffffffff81000000").unwrap(),
+            (None, 0xffffffff81000000)
+        );
+        assert_eq!(
+            kprobe_target("do_sys_open").unwrap(),
+            (Some("do_sys_open"), 0)
+        );
+        assert_eq!(
+            kprobe_target("do_sys_open+0x8").unwrap(),
+            (Some("do_sys_open"), 8)
+        );
+        assert!(kprobe_target("do_sys_open+x").is_err());
+    }
+}
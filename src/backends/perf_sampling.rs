@@ -0,0 +1,573 @@
+use crate::maps::MemoryMap;
+use crate::symbolizer::Symbolizer;
+use crate::unwind::UserStack;
+use crate::{BranchEntry, CounterKind, CounterValue, Error, MemoryDataSource, Sample};
+use perf_event_open_sys as sys;
+use std::sync::atomic::{fence, Ordering};
+
+// Number of data pages in a ring buffer, must be a power of two
+const RING_BUFFER_PAGES: usize = 64;
+
+pub(crate) struct RingBuffer {
+    base: *mut u8,
+    len: usize,
+    data_offset: usize,
+    data_size: usize,
+}
+
+pub(crate) struct RawRecord {
+    pub kind: u32,
+    pub misc: u16,
+    pub data: Vec<u8>,
+}
+
+pub(crate) struct RecordReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl RingBuffer {
+    pub fn new(fd: i32) -> Result<RingBuffer, Error> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let len = page_size * (RING_BUFFER_PAGES + 1);
+
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+
+        let page = base as *const sys::bindings::perf_event_mmap_page;
+        // Older kernels do not fill in data_offset and data_size
+        let (data_offset, data_size) = unsafe {
+            if (*page).data_size != 0 {
+                ((*page).data_offset as usize, (*page).data_size as usize)
+            } else {
+                (page_size, page_size * RING_BUFFER_PAGES)
+            }
+        };
+
+        return Ok(RingBuffer {
+            base: base as *mut u8,
+            len,
+            data_offset,
+            data_size,
+        });
+    }
+
+    fn page(&self) -> *mut sys::bindings::perf_event_mmap_page {
+        return self.base as *mut sys::bindings::perf_event_mmap_page;
+    }
+
+    fn copy_out(&self, offset: u64, buf: &mut [u8]) {
+        let data = unsafe { self.base.add(self.data_offset) };
+        let start = (offset % self.data_size as u64) as usize;
+        let first = std::cmp::min(buf.len(), self.data_size - start);
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.add(start), buf.as_mut_ptr(), first);
+            std::ptr::copy_nonoverlapping(data, buf.as_mut_ptr().add(first), buf.len() - first);
+        }
+    }
+
+    pub fn next_record(&mut self) -> Option<RawRecord> {
+        let page = self.page();
+        let head = unsafe { std::ptr::read_volatile(&(*page).data_head) };
+        fence(Ordering::Acquire);
+        let tail = unsafe { std::ptr::read_volatile(&(*page).data_tail) };
+
+        if tail >= head {
+            return None;
+        }
+
+        let mut header = [0u8; std::mem::size_of::<sys::bindings::perf_event_header>()];
+        self.copy_out(tail, &mut header);
+        let kind = u32::from_ne_bytes(header[0..4].try_into().unwrap());
+        let misc = u16::from_ne_bytes(header[4..6].try_into().unwrap());
+        let size = u16::from_ne_bytes(header[6..8].try_into().unwrap()) as usize;
+        // A corrupt header, drop everything written so far rather than loop on it
+        if size < header.len() {
+            fence(Ordering::Release);
+            unsafe { std::ptr::write_volatile(&mut (*page).data_tail, head) };
+            return None;
+        }
+
+        let mut data = vec![0u8; size - header.len()];
+        self.copy_out(tail + header.len() as u64, &mut data);
+
+        fence(Ordering::Release);
+        unsafe { std::ptr::write_volatile(&mut (*page).data_tail, tail + size as u64) };
+
+        return Some(RawRecord { kind, misc, data });
+    }
+}
+
+impl Drop for RingBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+impl<'a> RecordReader<'a> {
+    pub fn new(data: &'a [u8]) -> RecordReader<'a> {
+        return RecordReader { data, pos: 0 };
+    }
+
+    // None once a read runs past the end of the record
+    pub fn u64(&mut self) -> Option<u64> {
+        return Some(u64::from_ne_bytes(self.bytes(8)?.try_into().unwrap()));
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        return Some(u32::from_ne_bytes(self.bytes(4)?.try_into().unwrap()));
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(len)?;
+        let value = self.data.get(self.pos..end)?;
+        self.pos = end;
+        return Some(value);
+    }
+
+    // A NUL-terminated string padded to a multiple of 8 bytes
+    pub fn string(&mut self) -> Option<String> {
+        let rest = self.data.get(self.pos..)?;
+        let len = rest.iter().position(|b| *b == 0)?;
+        self.pos += (len + 8) & !7;
+        return Some(String::from_utf8_lossy(&rest[..len]).to_string());
+    }
+}
+
+// Decodes the body of a PERF_RECORD_SAMPLE. Fields are laid out in the order
+// documented in perf_event_open(2), each one present only if requested in sample_type.
+// The user registers and stack are returned for the unwinder, user_regs is the
+// number of registers requested in sample_regs_user. None for truncated records.
+pub(crate) fn decode_sample(
+    record: &RawRecord,
+    sample_type: u64,
+    user_regs: usize,
+    lookup: impl Fn(u64) -> Option<CounterKind>,
+) -> Option<(Sample, Option<UserStack>)> {
+    let mut reader = RecordReader::new(&record.data);
+    let mut sample = Sample {
+        ip: 0,
+        pid: 0,
+        tid: 0,
+        time: 0,
+        cpu: 0,
+        period: 0,
+        values: vec![],
+        addr: 0,
+        phys_addr: 0,
+        weight: 0,
+        data_src: None,
+        callchain: vec![],
+        kernel_frames: 0,
+        branches: vec![],
+    };
+    let mut user_stack = None;
+
+    if sample_type & sys::bindings::PERF_SAMPLE_IDENTIFIER as u64 != 0 {
+        reader.u64()?;
+    }
+    if sample_type & sys::bindings::PERF_SAMPLE_IP as u64 != 0 {
+        sample.ip = reader.u64()?;
+    }
+    if sample_type & sys::bindings::PERF_SAMPLE_TID as u64 != 0 {
+        sample.pid = reader.u32()?;
+        sample.tid = reader.u32()?;
+    }
+    if sample_type & sys::bindings::PERF_SAMPLE_TIME as u64 != 0 {
+        sample.time = reader.u64()?;
+    }
+    if sample_type & sys::bindings::PERF_SAMPLE_ADDR as u64 != 0 {
+        sample.addr = reader.u64()?;
+    }
+    if sample_type & sys::bindings::PERF_SAMPLE_ID as u64 != 0 {
+        reader.u64()?;
+    }
+    if sample_type & sys::bindings::PERF_SAMPLE_STREAM_ID as u64 != 0 {
+        reader.u64()?;
+    }
+    if sample_type & sys::bindings::PERF_SAMPLE_CPU as u64 != 0 {
+        sample.cpu = reader.u32()?;
+        reader.u32()?;
+    }
+    if sample_type & sys::bindings::PERF_SAMPLE_PERIOD as u64 != 0 {
+        sample.period = reader.u64()?;
+    }
+    if sample_type & sys::bindings::PERF_SAMPLE_READ as u64 != 0 {
+        // The read format is always PERF_FORMAT_GROUP | ID | TOTAL_TIME_ENABLED | TOTAL_TIME_RUNNING
+        let nr = reader.u64()?;
+        let time_enabled = reader.u64()?;
+        let time_running = reader.u64()?;
+        for _ in 0..nr {
+            let value = reader.u64()?;
+            let id = reader.u64()?;
+            if let Some(kind) = lookup(id) {
+                sample.values.push(CounterValue {
+                    kind,
+                    cpu: None,
+                    core_type: None,
+                    raw: value,
+                    time_enabled,
+                    time_running,
+                });
+            }
+        }
+    }
+    if sample_type & sys::bindings::PERF_SAMPLE_CALLCHAIN as u64 != 0 {
+        // Frames are preceded by a marker of the context they belong to
+        let nr = reader.u64()?;
+        let mut context = sys::bindings::PERF_CONTEXT_USER as u64;
+        for _ in 0..nr {
+            let ip = reader.u64()?;
+            if ip >= sys::bindings::PERF_CONTEXT_MAX as u64 {
+                context = ip;
+                continue;
+            }
+            if context == sys::bindings::PERF_CONTEXT_KERNEL as u64
+                || context == sys::bindings::PERF_CONTEXT_GUEST_KERNEL as u64
+            {
+                sample.kernel_frames += 1;
+            }
+            sample.callchain.push(ip);
+        }
+    }
+    if sample_type & sys::bindings::PERF_SAMPLE_BRANCH_STACK as u64 != 0 {
+        // Entries are { from, to, flags }, flags holds the bitfields
+        // mispred:1, predicted:1, in_tx:1, abort:1, cycles:16, type:4, ...
+        let nr = reader.u64()?;
+        for _ in 0..nr {
+            let from = reader.u64()?;
+            let to = reader.u64()?;
+            let flags = reader.u64()?;
+            sample.branches.push(BranchEntry {
+                from,
+                to,
+                mispredicted: flags & 0x1 != 0,
+                predicted: flags & 0x2 != 0,
+                in_tx: flags & 0x4 != 0,
+                cycles: ((flags >> 4) & 0xffff) as u16,
+            });
+        }
+    }
+    if sample_type & sys::bindings::PERF_SAMPLE_REGS_USER as u64 != 0 {
+        // PERF_SAMPLE_REGS_ABI_NONE when the sample hit a kernel thread
+        let abi = reader.u64()?;
+        if abi != 0 {
+            let regs = (0..user_regs)
+                .map(|_| reader.u64())
+                .collect::<Option<_>>()?;
+            user_stack = Some(UserStack { regs, data: vec![] });
+        }
+    }
+    if sample_type & sys::bindings::PERF_SAMPLE_STACK_USER as u64 != 0 {
+        let size = reader.u64()? as usize;
+        if size != 0 {
+            let data = reader.bytes(size)?;
+            let dyn_size = reader.u64()? as usize;
+            if let Some(user_stack) = &mut user_stack {
+                user_stack.data = data[..dyn_size.min(size)].to_vec();
+            }
+        }
+    }
+    if sample_type & sys::bindings::PERF_SAMPLE_WEIGHT as u64 != 0 {
+        sample.weight = reader.u64()?;
+    }
+    if sample_type & sys::bindings::PERF_SAMPLE_DATA_SRC as u64 != 0 {
+        sample.data_src = Some(MemoryDataSource::decode(reader.u64()?));
+    }
+    if sample_type & sys::bindings::PERF_SAMPLE_PHYS_ADDR as u64 != 0 {
+        sample.phys_addr = reader.u64()?;
+    }
+
+    return Some((sample, user_stack));
+}
+
+// Feeds the side-band records describing the sampled processes to the symbolizer,
+// their layouts are documented in perf_event_open(2). sample_id_all is not
+// requested, so the records end right after these fields. Truncated records
+// are ignored.
+pub(crate) fn track_record(record: &RawRecord, symbolizer: &mut Symbolizer) -> Option<()> {
+    let mut reader = RecordReader::new(&record.data);
+
+    if record.kind == sys::bindings::PERF_RECORD_MMAP2 as u32 {
+        let pid = reader.u32()?;
+        reader.u32()?;
+        let start = reader.u64()?;
+        let len = reader.u64()?;
+        let offset = reader.u64()?;
+        // Device and inode, or the build id
+        reader.bytes(24)?;
+        let prot = reader.u32()?;
+        reader.u32()?;
+        let path = reader.string()?;
+        // Anonymous and JIT mappings have no file to read symbols from
+        if path.starts_with('/') {
+            symbolizer.mmap(
+                pid,
+                MemoryMap {
+                    start,
+                    end: start + len,
+                    offset,
+                    executable: prot & libc::PROT_EXEC as u32 != 0,
+                    path,
+                },
+            );
+        }
+    } else if record.kind == sys::bindings::PERF_RECORD_COMM as u32 {
+        let pid = reader.u32()?;
+        let tid = reader.u32()?;
+        let comm = reader.string()?;
+        let exec = record.misc as u32 & sys::bindings::PERF_RECORD_MISC_COMM_EXEC != 0;
+        // Threads can be renamed on their own, the process keeps its name
+        if pid == tid || exec {
+            symbolizer.set_comm(pid, &comm, exec);
+        }
+    } else if record.kind == sys::bindings::PERF_RECORD_FORK as u32 {
+        let pid = reader.u32()?;
+        let ppid = reader.u32()?;
+        // New threads share the mappings of their process
+        if pid != ppid {
+            symbolizer.fork(pid, ppid);
+        }
+    } else if record.kind == sys::bindings::PERF_RECORD_EXIT as u32 {
+        let pid = reader.u32()?;
+        reader.u32()?;
+        let tid = reader.u32()?;
+        if pid == tid {
+            symbolizer.exit(pid);
+        }
+    }
+
+    return Some(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reader_stops_at_end_of_record() {
+        let data = [1u8, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0];
+        let mut reader = RecordReader::new(&data);
+        assert_eq!(reader.u64(), Some(1));
+        assert_eq!(reader.u64(), None);
+        assert_eq!(reader.u32(), Some(2));
+        assert_eq!(reader.u32(), None);
+        assert_eq!(reader.bytes(usize::MAX), None);
+    }
+
+    #[test]
+    fn truncated_sample_is_dropped() {
+        let record = RawRecord {
+            kind: sys::bindings::PERF_RECORD_SAMPLE as u32,
+            misc: 0,
+            data: vec![0; 16],
+        };
+        let sample_type = (sys::bindings::PERF_SAMPLE_IP | sys::bindings::PERF_SAMPLE_TID) as u64;
+        assert!(decode_sample(&record, sample_type, 0, |_| None).is_some());
+        let sample_type = sample_type | sys::bindings::PERF_SAMPLE_TIME as u64;
+        assert!(decode_sample(&record, sample_type, 0, |_| None).is_none());
+    }
+
+    fn sample_record(words: &[u64]) -> RawRecord {
+        return RawRecord {
+            kind: sys::bindings::PERF_RECORD_SAMPLE as u32,
+            misc: 0,
+            data: words.iter().flat_map(|word| word.to_ne_bytes()).collect(),
+        };
+    }
+
+    #[test]
+    fn callchain_markers_are_dropped() {
+        let kernel = sys::bindings::PERF_CONTEXT_KERNEL as u64;
+        let user = sys::bindings::PERF_CONTEXT_USER as u64;
+        let record = sample_record(&[6, kernel, 0xffff_1000, 0xffff_2000, user, 0x1000, 0x2000]);
+        let sample_type = sys::bindings::PERF_SAMPLE_CALLCHAIN as u64;
+
+        let (sample, _) = decode_sample(&record, sample_type, 0, |_| None).unwrap();
+        assert_eq!(
+            sample.callchain,
+            vec![0xffff_1000, 0xffff_2000, 0x1000, 0x2000]
+        );
+        assert_eq!(sample.kernel_frames, 2);
+
+        // Frames before any marker are user frames
+        let record = sample_record(&[2, 0x1000, 0x2000]);
+        let (sample, _) = decode_sample(&record, sample_type, 0, |_| None).unwrap();
+        assert_eq!(sample.callchain, vec![0x1000, 0x2000]);
+        assert_eq!(sample.kernel_frames, 0);
+    }
+
+    #[test]
+    fn user_registers_and_stack_are_decoded() {
+        let sample_type = (sys::bindings::PERF_SAMPLE_REGS_USER
+            | sys::bindings::PERF_SAMPLE_STACK_USER
+            | sys::bindings::PERF_SAMPLE_WEIGHT) as u64;
+
+        // abi, 2 registers, stack size, 16 bytes of stack, dyn_size, weight
+        let record = sample_record(&[2, 0x10, 0x20, 16, 0x1111, 0x2222, 8, 7]);
+        let (sample, user_stack) = decode_sample(&record, sample_type, 2, |_| None).unwrap();
+        let user_stack = user_stack.unwrap();
+        assert_eq!(user_stack.regs, vec![0x10, 0x20]);
+        assert_eq!(user_stack.data, 0x1111u64.to_ne_bytes().to_vec());
+        assert_eq!(sample.weight, 7);
+
+        // PERF_SAMPLE_REGS_ABI_NONE carries no registers, the stack is still skipped
+        let record = sample_record(&[0, 16, 0x1111, 0x2222, 16, 7]);
+        let (sample, user_stack) = decode_sample(&record, sample_type, 2, |_| None).unwrap();
+        assert!(user_stack.is_none());
+        assert_eq!(sample.weight, 7);
+
+        // An empty stack has no dyn_size
+        let record = sample_record(&[2, 0x10, 0x20, 0, 7]);
+        let (sample, user_stack) = decode_sample(&record, sample_type, 2, |_| None).unwrap();
+        assert!(user_stack.unwrap().data.is_empty());
+        assert_eq!(sample.weight, 7);
+    }
+
+    #[test]
+    fn branch_flags_are_decoded() {
+        let sample_type = sys::bindings::PERF_SAMPLE_BRANCH_STACK as u64;
+        // abort in bit 3 and the branch type from bit 20 on are not reported
+        let flags = [0x1, 0x2, 0x4, 0x8, 0xffff << 4, (0x1234 << 4) | (0xf << 20)];
+        let mut words = vec![flags.len() as u64];
+        for (idx, flags) in flags.iter().enumerate() {
+            words.extend([0x1000 + idx as u64, 0x2000 + idx as u64, *flags]);
+        }
+
+        let (sample, _) = decode_sample(&sample_record(&words), sample_type, 0, |_| None).unwrap();
+        let entry = |idx: u64, mispredicted, predicted, in_tx, cycles| BranchEntry {
+            from: 0x1000 + idx,
+            to: 0x2000 + idx,
+            mispredicted,
+            predicted,
+            in_tx,
+            cycles,
+        };
+        assert_eq!(
+            sample.branches,
+            vec![
+                entry(0, true, false, false, 0),
+                entry(1, false, true, false, 0),
+                entry(2, false, false, true, 0),
+                entry(3, false, false, false, 0),
+                entry(4, false, false, false, 0xffff),
+                entry(5, false, false, false, 0x1234),
+            ]
+        );
+    }
+
+    fn side_band_record(kind: u32, misc: u16, fields: &[&[u8]]) -> RawRecord {
+        return RawRecord {
+            kind,
+            misc,
+            data: fields.concat(),
+        };
+    }
+
+    // NUL-terminated and padded to a multiple of 8 bytes
+    fn record_string(value: &str) -> Vec<u8> {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.resize((bytes.len() + 8) & !7, 0);
+        return bytes;
+    }
+
+    #[test]
+    fn side_band_records_update_the_symbolizer() {
+        // A pid above pid_max, /proc knows nothing about it
+        let pid = 0x7fff_0000u32;
+        let child = pid + 1;
+        let mut symbolizer = Symbolizer::new();
+
+        let comm = side_band_record(
+            sys::bindings::PERF_RECORD_COMM as u32,
+            0,
+            &[
+                &pid.to_ne_bytes(),
+                &pid.to_ne_bytes(),
+                &record_string("worker"),
+            ],
+        );
+        assert!(track_record(&comm, &mut symbolizer).is_some());
+        assert_eq!(symbolizer.comm(pid), Some("worker"));
+
+        let fork = side_band_record(
+            sys::bindings::PERF_RECORD_FORK as u32,
+            0,
+            &[&child.to_ne_bytes(), &pid.to_ne_bytes(), &[0; 24]],
+        );
+        assert!(track_record(&fork, &mut symbolizer).is_some());
+        assert_eq!(symbolizer.comm(child), Some("worker"));
+
+        // Map the code of this test binary into the fake process at another address
+        let function = sample_record as *const () as u64;
+        let own_maps = crate::maps::read_maps(std::process::id());
+        let own = crate::maps::find_map(&own_maps, function).unwrap();
+        let start = 0x1_0000_0000u64;
+        let mmap = side_band_record(
+            sys::bindings::PERF_RECORD_MMAP2 as u32,
+            0,
+            &[
+                &pid.to_ne_bytes(),
+                &pid.to_ne_bytes(),
+                &start.to_ne_bytes(),
+                &(own.end - own.start).to_ne_bytes(),
+                &own.offset.to_ne_bytes(),
+                &[0; 24],
+                &(libc::PROT_READ as u32 | libc::PROT_EXEC as u32).to_ne_bytes(),
+                &0u32.to_ne_bytes(),
+                &record_string(&own.path),
+            ],
+        );
+        let address = start + function - own.start;
+        assert!(symbolizer.symbolize(pid, address).is_none());
+        assert!(track_record(&mmap, &mut symbolizer).is_some());
+        let symbol = symbolizer.symbolize(pid, address).unwrap();
+        assert!(symbol.name.ends_with("sample_record"), "{}", symbol.name);
+
+        // Samples may still arrive after the exit
+        let exit = side_band_record(
+            sys::bindings::PERF_RECORD_EXIT as u32,
+            0,
+            &[
+                &pid.to_ne_bytes(),
+                &pid.to_ne_bytes(),
+                &pid.to_ne_bytes(),
+                &pid.to_ne_bytes(),
+                &[0; 8],
+            ],
+        );
+        assert!(track_record(&exit, &mut symbolizer).is_some());
+        assert!(symbolizer.symbolize(pid, address).is_some());
+
+        // The mappings of the old image are gone after an exec
+        let exec = side_band_record(
+            sys::bindings::PERF_RECORD_COMM as u32,
+            sys::bindings::PERF_RECORD_MISC_COMM_EXEC as u16,
+            &[
+                &pid.to_ne_bytes(),
+                &pid.to_ne_bytes(),
+                &record_string("other"),
+            ],
+        );
+        assert!(track_record(&exec, &mut symbolizer).is_some());
+        assert_eq!(symbolizer.comm(pid), Some("other"));
+        assert!(symbolizer.symbolize(pid, address).is_none());
+
+        let truncated = side_band_record(sys::bindings::PERF_RECORD_MMAP2 as u32, 0, &[&[0; 16]]);
+        assert!(track_record(&truncated, &mut symbolizer).is_none());
+    }
+}
@@ -0,0 +1,81 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    // Carries the value of /proc/sys/kernel/perf_event_paranoid if it could be read
+    PermissionDenied(Option<i32>),
+    EventNotSupported(String),
+    TooManyCounters {
+        requested: usize,
+        max: Option<usize>,
+    },
+    BackendUnavailable(String),
+    InvalidConfiguration(String),
+    Io(std::io::Error),
+    // A framework call of the backend failed with its own status code, as the
+    // kpep and kpc calls of kperf do without setting errno
+    BackendCall {
+        call: &'static str,
+        code: i32,
+    },
+}
+
+impl Error {
+    pub(crate) fn last_os_error() -> Error {
+        return Error::Io(std::io::Error::last_os_error());
+    }
+
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            Error::Io(err) => err.raw_os_error(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::PermissionDenied(Some(paranoid)) => write!(
+                f,
+                "Permission denied (perf_event_paranoid is {}, try lowering it or running with CAP_PERFMON)",
+                paranoid
+            ),
+            Error::PermissionDenied(None) => write!(f, "Permission denied"),
+            Error::EventNotSupported(name) => {
+                write!(f, "Event {} is not supported by the PMU", name)
+            }
+            Error::TooManyCounters {
+                requested,
+                max: Some(max),
+            } => write!(
+                f,
+                "Too many counters in a group: requested {}, at most {} supported",
+                requested, max
+            ),
+            Error::TooManyCounters {
+                requested,
+                max: None,
+            } => write!(f, "Too many counters in a group: requested {}", requested),
+            Error::BackendUnavailable(reason) => write!(f, "Backend unavailable: {}", reason),
+            Error::InvalidConfiguration(reason) => write!(f, "Invalid configuration: {}", reason),
+            Error::Io(err) => write!(f, "I/O failure: {}", err),
+            Error::BackendCall { call, code } => write!(f, "{} failed with {}", call, code),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        return Error::Io(err);
+    }
+}
@@ -0,0 +1,301 @@
+use crate::ProcessorFamily;
+use std::collections::HashMap;
+
+// "key : value" fields of the first processor listed in /proc/cpuinfo
+pub(crate) struct CpuinfoFields {
+    fields: HashMap<String, String>,
+}
+
+impl CpuinfoFields {
+    pub(crate) fn parse(cpuinfo: &str) -> CpuinfoFields {
+        let mut fields = HashMap::new();
+        for line in cpuinfo.lines() {
+            if line.trim().is_empty() {
+                if fields.is_empty() {
+                    continue;
+                }
+                break;
+            }
+            if let Some((key, value)) = line.split_once(':') {
+                fields.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+
+        return CpuinfoFields { fields };
+    }
+
+    pub(crate) fn text(&self, key: &str) -> Option<&str> {
+        return self.fields.get(key).map(|value| value.as_str());
+    }
+
+    // Decimal or 0x prefixed hexadecimal
+    pub(crate) fn number(&self, key: &str) -> Option<u64> {
+        let value = self.text(key)?;
+        return match value.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => value.parse::<u64>().ok(),
+        };
+    }
+}
+
+// Socket, core and hardware thread counts of the online CPUs
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CpuTopology {
+    pub logical_cpus: u32,
+    pub cores: u32,
+    pub sockets: u32,
+}
+
+// Identification of the processor the counters run on
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuInfo {
+    // e.g. "GenuineIntel", "AuthenticAMD", "ARM"
+    pub vendor: String,
+    pub family: u32,
+    // u64 for the XLEN-wide marchid and mimpid of RISC-V
+    pub model: u64,
+    pub stepping: u64,
+    pub brand: String,
+    // Always the detected hardware
+    pub processor_family: ProcessorFamily,
+    // The family the event tables are picked by instead, from LIBPMU_PROCESSOR_FAMILY
+    pub family_override: Option<ProcessorFamily>,
+    // None when the architecture does not let user space query them
+    pub general_counters: Option<u32>,
+    pub fixed_counters: Option<u32>,
+    pub counter_width: Option<u32>,
+    pub topology: CpuTopology,
+}
+
+impl CpuTopology {
+    pub fn threads_per_core(&self) -> u32 {
+        if self.cores == 0 {
+            return 1;
+        }
+
+        return self.logical_cpus / self.cores;
+    }
+
+    // Reads topology/ of every online CPU under the given directory,
+    // normally /sys/devices/system/cpu
+    #[cfg(target_os = "linux")]
+    pub fn from_sysfs(cpu_root: &std::path::Path) -> Option<CpuTopology> {
+        let online = std::fs::read_to_string(cpu_root.join("online")).ok()?;
+        let cpus = crate::backends::parse_cpu_list(&online)?;
+
+        let mut sockets = vec![];
+        let mut cores = vec![];
+        for cpu in &cpus {
+            let topology = cpu_root.join(format!("cpu{}", cpu)).join("topology");
+            let read_id = |name: &str| -> Option<i64> {
+                return std::fs::read_to_string(topology.join(name))
+                    .ok()?
+                    .trim()
+                    .parse::<i64>()
+                    .ok();
+            };
+            let socket = read_id("physical_package_id")?;
+            let core = read_id("core_id")?;
+
+            sockets.push(socket);
+            cores.push((socket, core));
+        }
+        sockets.sort();
+        sockets.dedup();
+        cores.sort();
+        cores.dedup();
+
+        return Some(CpuTopology {
+            logical_cpus: cpus.len() as u32,
+            cores: cores.len() as u32,
+            sockets: sockets.len() as u32,
+        });
+    }
+
+    fn detect() -> CpuTopology {
+        #[cfg(target_os = "linux")]
+        if let Some(topology) =
+            CpuTopology::from_sysfs(std::path::Path::new("/sys/devices/system/cpu"))
+        {
+            return topology;
+        }
+
+        // Without a topology description every logical CPU counts as a core
+        let logical_cpus = std::thread::available_parallelism()
+            .map(|n| n.get() as u32)
+            .unwrap_or(1);
+        return CpuTopology {
+            logical_cpus,
+            cores: logical_cpus,
+            sockets: 1,
+        };
+    }
+}
+
+fn registers_to_string(registers: &[u32]) -> String {
+    let bytes: Vec<u8> = registers.iter().flat_map(|r| r.to_le_bytes()).collect();
+
+    return String::from_utf8_lossy(&bytes)
+        .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+        .to_string();
+}
+
+impl CpuInfo {
+    fn unknown() -> CpuInfo {
+        return CpuInfo {
+            vendor: String::new(),
+            family: 0,
+            model: 0,
+            stepping: 0,
+            brand: String::new(),
+            processor_family: ProcessorFamily::Unknown,
+            family_override: None,
+            general_counters: None,
+            fixed_counters: None,
+            counter_width: None,
+            topology: CpuTopology::default(),
+        };
+    }
+
+    // Decodes CPUID results, cpuid(leaf, subleaf) returns [eax, ebx, ecx, edx].
+    // The topology is left empty.
+    pub fn from_x86_cpuid(cpuid: &dyn Fn(u32, u32) -> [u32; 4]) -> CpuInfo {
+        const LEAF_VENDOR: u32 = 0x0;
+        const LEAF_SIGNATURE: u32 = 0x1;
+        const LEAF_ARCH_PERFMON: u32 = 0xa;
+        const LEAF_EXT_MAX: u32 = 0x80000000;
+        const LEAF_EXT_FEATURES: u32 = 0x80000001;
+        const LEAF_BRAND: [u32; 3] = [0x80000002, 0x80000003, 0x80000004];
+        const LEAF_AMD_PERFMON_V2: u32 = 0x80000022;
+
+        let mut info = CpuInfo::unknown();
+
+        let vendor = cpuid(LEAF_VENDOR, 0);
+        let max_leaf = vendor[0];
+        info.vendor = registers_to_string(&[vendor[1], vendor[3], vendor[2]]);
+
+        if max_leaf >= LEAF_SIGNATURE {
+            let eax = cpuid(LEAF_SIGNATURE, 0)[0];
+            let base_family = (eax >> 8) & 0xf;
+            let base_model = (eax >> 4) & 0xf;
+
+            info.stepping = (eax & 0xf) as u64;
+            info.family = if base_family == 0xf {
+                base_family + ((eax >> 20) & 0xff)
+            } else {
+                base_family
+            };
+            info.model = if base_family == 0x6 || base_family == 0xf {
+                (((eax >> 16) & 0xf) << 4) | base_model
+            } else {
+                base_model
+            } as u64;
+            info.processor_family = ProcessorFamily::from_x86_cpuid(eax);
+        }
+
+        let max_ext_leaf = cpuid(LEAF_EXT_MAX, 0)[0];
+        if max_ext_leaf >= LEAF_BRAND[2] {
+            let registers: Vec<u32> = LEAF_BRAND.iter().flat_map(|leaf| cpuid(*leaf, 0)).collect();
+            info.brand = registers_to_string(&registers);
+        }
+
+        if info.vendor == "GenuineIntel" && max_leaf >= LEAF_ARCH_PERFMON {
+            let perfmon = cpuid(LEAF_ARCH_PERFMON, 0);
+            let version = perfmon[0] & 0xff;
+            if version > 0 {
+                info.general_counters = Some((perfmon[0] >> 8) & 0xff);
+                info.counter_width = Some((perfmon[0] >> 16) & 0xff);
+                // Fixed counters are only enumerated since version 2
+                info.fixed_counters = Some(if version > 1 { perfmon[3] & 0x1f } else { 0 });
+            }
+        } else if info.vendor == "AuthenticAMD" || info.vendor == "HygonGenuine" {
+            let perfmon_v2 =
+                max_ext_leaf >= LEAF_AMD_PERFMON_V2 && cpuid(LEAF_AMD_PERFMON_V2, 0)[0] & 0x1 != 0;
+            let ext_core_counters = max_ext_leaf >= LEAF_EXT_FEATURES
+                && cpuid(LEAF_EXT_FEATURES, 0)[2] & (1 << 23) != 0;
+
+            info.general_counters = Some(if perfmon_v2 {
+                cpuid(LEAF_AMD_PERFMON_V2, 0)[1] & 0xf
+            } else if ext_core_counters {
+                6
+            } else {
+                4
+            });
+            info.fixed_counters = Some(0);
+            info.counter_width = Some(48);
+        }
+
+        return info;
+    }
+
+    // Decodes the first processor of /proc/cpuinfo. The topology is left empty.
+    pub fn from_cpuinfo(cpuinfo: &str) -> CpuInfo {
+        let fields = CpuinfoFields::parse(cpuinfo);
+        let text =
+            |key: &str| -> String { return fields.text(key).unwrap_or_default().to_string() };
+        let number = |key: &str| -> u64 { return fields.number(key).unwrap_or(0) };
+
+        let mut info = CpuInfo::unknown();
+        info.processor_family = ProcessorFamily::from_cpuinfo_fields(&fields);
+
+        if fields.text("CPU implementer").is_some() {
+            // aarch64, the MIDR_EL1 fields
+            let implementer = number("CPU implementer");
+            info.vendor = match implementer {
+                0x41 => "ARM".to_string(),
+                0x42 => "Broadcom".to_string(),
+                0x48 => "HiSilicon".to_string(),
+                0x4e => "NVIDIA".to_string(),
+                0x51 => "Qualcomm".to_string(),
+                0x61 => "Apple".to_string(),
+                0xc0 => "Ampere".to_string(),
+                _ => format!("{:#x}", implementer),
+            };
+            info.family = number("CPU architecture") as u32;
+            info.model = number("CPU part");
+            info.stepping = (number("CPU variant") << 4) | number("CPU revision");
+            info.brand = text("model name");
+        } else if fields.text("marchid").is_some() {
+            // riscv64, the machine vendor, architecture and implementation IDs
+            info.vendor = match text("mvendorid").as_str() {
+                "0x489" => "SiFive".to_string(),
+                "" => String::new(),
+                other => other.to_string(),
+            };
+            // The top bit of marchid only tells commercial designs from open source ones
+            info.model = number("marchid");
+            info.stepping = number("mimpid");
+            info.brand = text("uarch");
+        } else {
+            info.vendor = text("vendor_id");
+            info.family = number("cpu family") as u32;
+            info.model = number("model");
+            info.stepping = number("stepping");
+            info.brand = text("model name");
+        }
+
+        return info;
+    }
+
+    pub fn detect() -> CpuInfo {
+        let mut info;
+
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "x86_64")] {
+                info = CpuInfo::from_x86_cpuid(&super::cpuid);
+            } else {
+                info = match std::fs::read_to_string("/proc/cpuinfo") {
+                    Ok(cpuinfo) => CpuInfo::from_cpuinfo(&cpuinfo),
+                    Err(_) => CpuInfo::unknown(),
+                };
+            }
+        }
+
+        // On aarch64 the family of the biggest cores, not the first one listed
+        info.processor_family = super::detect_processor_family();
+        info.family_override = super::processor_family_override();
+        info.topology = CpuTopology::detect();
+
+        return info;
+    }
+}
@@ -1,14 +1,21 @@
-use crate::SystemCounter;
+use crate::{CacheCounter, Error, HybridCoreType, SystemCounter, SystemCounterKind};
+
+mod cpu_info;
+
+use cpu_info::CpuinfoFields;
+pub use cpu_info::{CpuInfo, CpuTopology};
 
 include!(concat!(env!("OUT_DIR"), "/archs.rs"));
 
-#[cfg(target_arch = "x86_64")]
+// Overrides the detected processor family, e.g. LIBPMU_PROCESSOR_FAMILY=IntelSkylake
+const PROCESSOR_FAMILY_ENV: &str = "LIBPMU_PROCESSOR_FAMILY";
+
 #[repr(C)]
 struct X86VendorInfo {
     eax: u32,
 }
 
-#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProcessorFamily {
     Unknown,
     AmdZen1,
@@ -27,14 +34,123 @@ pub enum ProcessorFamily {
     IntelAlderLake,
     IntelRaptorLake,
     SiFiveU7,
+    ArmCortexA72,
+    ArmCortexA73,
+    ArmCortexA75,
+    ArmCortexA76,
+    ArmCortexA77,
+    ArmCortexA78,
+    ArmNeoverseN1,
+    ArmNeoverseN2,
+    ArmNeoverseV1,
+    ArmNeoverseV2,
+    AmpereOne,
 }
 
-#[cfg(target_arch = "x86_64")]
-impl X86VendorInfo {
-    fn new(result: core::arch::x86_64::CpuidResult) -> X86VendorInfo {
-        X86VendorInfo { eax: result.eax }
+const FAMILY_NAMES: [(&str, ProcessorFamily); 28] = [
+    ("Unknown", ProcessorFamily::Unknown),
+    ("AmdZen1", ProcessorFamily::AmdZen1),
+    ("AmdZen2", ProcessorFamily::AmdZen2),
+    ("AmdZen3", ProcessorFamily::AmdZen3),
+    ("AmdZen4", ProcessorFamily::AmdZen4),
+    ("IntelBroadwell", ProcessorFamily::IntelBroadwell),
+    ("IntelHaswell", ProcessorFamily::IntelHaswell),
+    ("IntelSkylake", ProcessorFamily::IntelSkylake),
+    ("IntelKabyLake", ProcessorFamily::IntelKabyLake),
+    ("IntelCometLake", ProcessorFamily::IntelCometLake),
+    ("IntelIceLakeClient", ProcessorFamily::IntelIceLakeClient),
+    ("IntelIceLakeServer", ProcessorFamily::IntelIceLakeServer),
+    ("IntelTigerLake", ProcessorFamily::IntelTigerLake),
+    ("IntelRocketLake", ProcessorFamily::IntelRocketLake),
+    ("IntelAlderLake", ProcessorFamily::IntelAlderLake),
+    ("IntelRaptorLake", ProcessorFamily::IntelRaptorLake),
+    ("SiFiveU7", ProcessorFamily::SiFiveU7),
+    ("ArmCortexA72", ProcessorFamily::ArmCortexA72),
+    ("ArmCortexA73", ProcessorFamily::ArmCortexA73),
+    ("ArmCortexA75", ProcessorFamily::ArmCortexA75),
+    ("ArmCortexA76", ProcessorFamily::ArmCortexA76),
+    ("ArmCortexA77", ProcessorFamily::ArmCortexA77),
+    ("ArmCortexA78", ProcessorFamily::ArmCortexA78),
+    ("ArmNeoverseN1", ProcessorFamily::ArmNeoverseN1),
+    ("ArmNeoverseN2", ProcessorFamily::ArmNeoverseN2),
+    ("ArmNeoverseV1", ProcessorFamily::ArmNeoverseV1),
+    ("ArmNeoverseV2", ProcessorFamily::ArmNeoverseV2),
+    ("AmpereOne", ProcessorFamily::AmpereOne),
+];
+
+impl ProcessorFamily {
+    pub fn all() -> Vec<ProcessorFamily> {
+        return FAMILY_NAMES.iter().map(|(_, family)| *family).collect();
+    }
+
+    pub fn name(&self) -> &'static str {
+        return FAMILY_NAMES
+            .iter()
+            .find(|(_, family)| family == self)
+            .map(|(name, _)| *name)
+            .unwrap();
+    }
+
+    pub fn from_name(name: &str) -> Option<ProcessorFamily> {
+        return FAMILY_NAMES
+            .iter()
+            .find(|(family_name, _)| family_name.eq_ignore_ascii_case(name.trim()))
+            .map(|(_, family)| *family);
+    }
+
+    // EAX of CPUID leaf 1, the family, model and stepping signature
+    pub fn from_x86_cpuid(eax: u32) -> ProcessorFamily {
+        return X86VendorInfo { eax }.as_host_arch();
+    }
+
+    // Value of the aarch64 MIDR_EL1 register
+    pub fn from_midr(midr: u64) -> ProcessorFamily {
+        return Aarch64Midr { midr }.as_host_arch();
+    }
+
+    pub fn from_riscv_marchid(marchid: u64) -> ProcessorFamily {
+        // TODO(Alex): technically speaking this also includes E7 and S7
+        if marchid == 0x8000000000000007 {
+            return ProcessorFamily::SiFiveU7;
+        }
+
+        return ProcessorFamily::Unknown;
     }
 
+    // Contents of /proc/cpuinfo on x86_64, aarch64 or riscv64, only the
+    // first processor is looked at
+    pub fn from_cpuinfo(cpuinfo: &str) -> ProcessorFamily {
+        return ProcessorFamily::from_cpuinfo_fields(&CpuinfoFields::parse(cpuinfo));
+    }
+
+    fn from_cpuinfo_fields(fields: &CpuinfoFields) -> ProcessorFamily {
+        if let Some(marchid) = fields.number("marchid") {
+            return ProcessorFamily::from_riscv_marchid(marchid);
+        }
+        if let (Some(implementer), Some(part)) =
+            (fields.number("CPU implementer"), fields.number("CPU part"))
+        {
+            return ProcessorFamily::from_midr((implementer << 24) | (part << 4));
+        }
+        if let (Some(family), Some(model)) = (fields.number("cpu family"), fields.number("model")) {
+            // Reassemble the CPUID signature the kernel has split into display values
+            let (family, extended_family) = if family >= 0xf {
+                (0xf, family - 0xf)
+            } else {
+                (family, 0)
+            };
+            let eax = ((extended_family & 0xff) << 20)
+                | (((model >> 4) & 0xf) << 16)
+                | ((family & 0xf) << 8)
+                | ((model & 0xf) << 4);
+            return ProcessorFamily::from_x86_cpuid(eax as u32);
+        }
+
+        return ProcessorFamily::Unknown;
+    }
+}
+
+impl X86VendorInfo {
     fn as_host_arch(&self) -> ProcessorFamily {
         let model = (self.eax >> 4) & 0xf;
         let family = (self.eax >> 8) & 0xf;
@@ -103,55 +219,219 @@ impl X86VendorInfo {
     }
 }
 
+// [eax, ebx, ecx, edx] of a CPUID leaf
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn cpuid(leaf: u32, subleaf: u32) -> [u32; 4] {
+    let result = unsafe { core::arch::x86_64::__cpuid_count(leaf, subleaf) };
+
+    return [result.eax, result.ebx, result.ecx, result.edx];
+}
+
 #[cfg(target_arch = "x86_64")]
 fn get_x86_64_family() -> ProcessorFamily {
     const EAX_VENDOR_INFO: u32 = 0x1;
 
-    let vendor_result = unsafe { core::arch::x86_64::__cpuid(EAX_VENDOR_INFO) };
-    let vendor_info = X86VendorInfo::new(vendor_result);
+    let vendor_info = X86VendorInfo {
+        eax: cpuid(EAX_VENDOR_INFO, 0)[0],
+    };
 
     return vendor_info.as_host_arch();
 }
 
 #[cfg(target_arch = "riscv64")]
 fn get_riscv64_family() -> ProcessorFamily {
-    use proc_getter::cpuinfo::cpuinfo;
+    return match std::fs::read_to_string("/proc/cpuinfo") {
+        Ok(info) => ProcessorFamily::from_cpuinfo(&info),
+        Err(_) => ProcessorFamily::Unknown,
+    };
+}
 
-    let info = cpuinfo().expect("/proc/cpuinfo is inaccessible");
-    let marchid = info[0].get("marchid");
+struct Aarch64Midr {
+    midr: u64,
+}
 
-    match marchid {
-        Some(marchid) => {
-            if marchid == "0x8000000000000007" {
-                // TODO(Alex): technically speaking this also includes E7 and S7
-                ProcessorFamily::SiFiveU7
-            } else {
-                ProcessorFamily::Unknown
-            }
+impl Aarch64Midr {
+    fn as_host_arch(&self) -> ProcessorFamily {
+        let implementer = (self.midr >> 24) & 0xff;
+        let part = (self.midr >> 4) & 0xfff;
+
+        match (implementer, part) {
+            // Arm Ltd.
+            (0x41, 0xd08) => ProcessorFamily::ArmCortexA72,
+            (0x41, 0xd09) => ProcessorFamily::ArmCortexA73,
+            (0x41, 0xd0a) => ProcessorFamily::ArmCortexA75,
+            (0x41, 0xd0b) => ProcessorFamily::ArmCortexA76,
+            (0x41, 0xd0c) => ProcessorFamily::ArmNeoverseN1,
+            (0x41, 0xd0d) => ProcessorFamily::ArmCortexA77,
+            (0x41, 0xd40) => ProcessorFamily::ArmNeoverseV1,
+            (0x41, 0xd41) => ProcessorFamily::ArmCortexA78,
+            (0x41, 0xd49) => ProcessorFamily::ArmNeoverseN2,
+            (0x41, 0xd4f) => ProcessorFamily::ArmNeoverseV2,
+            // Ampere Computing, Altra reports itself as a Neoverse N1
+            (0xc0, 0xac3) | (0xc0, 0xac4) => ProcessorFamily::AmpereOne,
+            _ => ProcessorFamily::Unknown,
         }
-        None => ProcessorFamily::Unknown,
     }
 }
 
-pub fn get_processor_family() -> ProcessorFamily {
+// big.LITTLE systems mix core families, the biggest core by capacity wins
+// and cores the tables know about win over unknown ones of the same capacity
+#[cfg(any(target_arch = "aarch64", test))]
+fn big_core_family(cores: &[(u64, u64)]) -> ProcessorFamily {
+    return cores
+        .iter()
+        .map(|(midr, capacity)| (*capacity, ProcessorFamily::from_midr(*midr)))
+        .max_by_key(|(capacity, family)| (*capacity, *family != ProcessorFamily::Unknown))
+        .map(|(_, family)| family)
+        .unwrap_or(ProcessorFamily::Unknown);
+}
+
+// MIDR_EL1 and cpu_capacity of every online CPU, the kernel only creates
+// regs/ for online CPUs
+#[cfg(target_arch = "aarch64")]
+fn read_aarch64_cores() -> Vec<(u64, u64)> {
+    let mut cores = vec![];
+
+    let entries = match std::fs::read_dir("/sys/devices/system/cpu") {
+        Ok(entries) => entries,
+        Err(_) => return cores,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name
+            .strip_prefix("cpu")
+            .is_some_and(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()))
+        {
+            continue;
+        }
+
+        let path = entry.path();
+        let midr = std::fs::read_to_string(path.join("regs/identification/midr_el1"))
+            .ok()
+            .and_then(|midr| u64::from_str_radix(midr.trim().trim_start_matches("0x"), 16).ok());
+        // Same default as the kernel when the firmware describes no capacities
+        let capacity = std::fs::read_to_string(path.join("cpu_capacity"))
+            .ok()
+            .and_then(|capacity| capacity.trim().parse::<u64>().ok())
+            .unwrap_or(1024);
+        if let Some(midr) = midr {
+            cores.push((midr, capacity));
+        }
+    }
+
+    return cores;
+}
+
+#[cfg(target_arch = "aarch64")]
+fn get_aarch64_family() -> ProcessorFamily {
+    let cores = read_aarch64_cores();
+    if !cores.is_empty() {
+        return big_core_family(&cores);
+    }
+
+    return match std::fs::read_to_string("/proc/cpuinfo") {
+        Ok(info) => ProcessorFamily::from_cpuinfo(&info),
+        Err(_) => ProcessorFamily::Unknown,
+    };
+}
+
+fn detect_processor_family() -> ProcessorFamily {
     cfg_if::cfg_if! {
         if #[cfg(target_arch="x86_64")] {
             get_x86_64_family()
         } else if #[cfg(target_arch="riscv64")] {
             get_riscv64_family()
+        } else if #[cfg(target_arch="aarch64")] {
+            get_aarch64_family()
         } else {
             ProcessorFamily::Unknown
         }
     }
 }
 
-pub fn get_hardware_events() -> Vec<SystemCounter> {
-    let arch = get_processor_family();
+// The family LIBPMU_PROCESSOR_FAMILY names, None without a valid override
+fn processor_family_override() -> Option<ProcessorFamily> {
+    let name = std::env::var_os(PROCESSOR_FAMILY_ENV)?;
+    return ProcessorFamily::from_name(&name.to_string_lossy());
+}
 
-    match arch {
-        ProcessorFamily::AmdZen1 => amd_fam17h_zen1::get(),
-        ProcessorFamily::IntelTigerLake => intel_icl::get(),
-        ProcessorFamily::SiFiveU7 => sifive_u7::get(),
-        _ => vec![],
+// The running processor, unless overridden through LIBPMU_PROCESSOR_FAMILY.
+// An override that names no family is an error
+pub fn try_get_processor_family() -> Result<ProcessorFamily, Error> {
+    if let Some(name) = std::env::var_os(PROCESSOR_FAMILY_ENV) {
+        let name = name.to_string_lossy();
+        return ProcessorFamily::from_name(&name).ok_or(Error::InvalidConfiguration(format!(
+            "Unknown processor family {} in {}",
+            name, PROCESSOR_FAMILY_ENV
+        )));
+    }
+
+    return Ok(detect_processor_family());
+}
+
+// Same as try_get_processor_family, falls back to the detected processor
+// when the override is invalid, Builder::try_new reports it instead
+pub fn get_processor_family() -> ProcessorFamily {
+    return processor_family_override().unwrap_or_else(detect_processor_family);
+}
+
+pub fn get_cpu_info() -> CpuInfo {
+    return CpuInfo::detect();
+}
+
+pub(crate) fn get_hardware_events(family: &ProcessorFamily) -> Vec<SystemCounter> {
+    return get_family_events(family);
+}
+
+// Raw event implementing a generic cache counter the kernel has no encoding for
+pub(crate) fn get_cache_event(
+    cache: &CacheCounter,
+    core_type: Option<HybridCoreType>,
+    family: &ProcessorFamily,
+) -> Option<SystemCounter> {
+    // On hybrid CPUs only events of the requested core type are usable,
+    // otherwise the P-core tables stand in for the core PMU
+    return get_family_cache_events(family)
+        .into_iter()
+        .filter(|(_, event)| match &event.kind {
+            SystemCounterKind::Hybrid(event_core_type) => {
+                *event_core_type == core_type.unwrap_or(HybridCoreType::Core)
+            }
+            _ => true,
+        })
+        .find(|(counter, _)| counter == cache)
+        .map(|(_, event)| event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CORTEX_A55: u64 = 0x412f_d050;
+    const CORTEX_A76: u64 = 0x414f_d0b0;
+    const NEOVERSE_N1: u64 = 0x413f_d0c1;
+
+    #[test]
+    fn big_core_decides_the_family() {
+        // cpu0 is a little core on most big.LITTLE systems
+        let cores = [
+            (CORTEX_A55, 446),
+            (CORTEX_A55, 446),
+            (CORTEX_A76, 1024),
+            (CORTEX_A76, 1024),
+        ];
+        assert_eq!(big_core_family(&cores), ProcessorFamily::ArmCortexA76);
+    }
+
+    #[test]
+    fn known_core_wins_without_capacities() {
+        let cores = [(CORTEX_A55, 1024), (NEOVERSE_N1, 1024)];
+        assert_eq!(big_core_family(&cores), ProcessorFamily::ArmNeoverseN1);
+
+        assert_eq!(
+            big_core_family(&[(CORTEX_A55, 1024)]),
+            ProcessorFamily::Unknown
+        );
+        assert_eq!(big_core_family(&[]), ProcessorFamily::Unknown);
     }
 }
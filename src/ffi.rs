@@ -17,7 +17,10 @@ pub struct FFICounters {
 
 #[no_mangle]
 pub extern "C" fn pmu_builder_create() -> *mut FFIBuilder {
-    let builder = crate::Builder::new();
+    let builder = match crate::Builder::try_new() {
+        Ok(builder) => builder,
+        Err(_) => return std::ptr::null_mut(),
+    };
 
     let managed_builder = Box::new(FFIBuilder { builder });
     return Box::leak(managed_builder);
@@ -107,23 +110,29 @@ pub extern "C" fn pmu_builder_build(builder_raw: *mut FFIBuilder) -> *mut FFICou
 }
 
 #[no_mangle]
-pub extern "C" fn pmu_counters_start(counters_raw: *mut FFICounters) {
+pub extern "C" fn pmu_counters_start(counters_raw: *mut FFICounters) -> c_int {
     if counters_raw == std::ptr::null_mut() {
-        return;
+        return 1;
     }
 
     let counters = unsafe { counters_raw.as_mut() }.unwrap();
-    counters.counters.start()
+    match counters.counters.start() {
+        Ok(_) => 0,
+        Err(_) => 1,
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn pmu_counters_stop(counters_raw: *mut FFICounters) {
+pub extern "C" fn pmu_counters_stop(counters_raw: *mut FFICounters) -> c_int {
     if counters_raw == std::ptr::null_mut() {
-        return;
+        return 1;
     }
 
     let counters = unsafe { counters_raw.as_mut() }.unwrap();
-    counters.counters.stop()
+    match counters.counters.stop() {
+        Ok(_) => 0,
+        Err(_) => 1,
+    }
 }
 
 #[no_mangle]
@@ -195,12 +204,17 @@ pub extern "C" fn pmu_builder_add_cache_counter(
         Some(crate::CacheLevelKind::ITLB)
     } else if clevel == PMUCacheLevelKind_PMU_CACHE_BPU {
         Some(crate::CacheLevelKind::BPU)
+    } else if clevel == PMUCacheLevelKind_PMU_CACHE_NODE {
+        Some(crate::CacheLevelKind::Node)
     } else {
         None
     };
 
-    let kind = if ckind == PMUCacheCounterKind_PMU_CACHE_HIT {
-        Some(crate::CacheCounterKind::Hit)
+    // PMU_CACHE_HIT is the deprecated name of PMU_CACHE_ACCESS
+    let kind = if ckind == PMUCacheCounterKind_PMU_CACHE_ACCESS
+        || ckind == PMUCacheCounterKind_PMU_CACHE_HIT
+    {
+        Some(crate::CacheCounterKind::Access)
     } else if ckind == PMUCacheCounterKind_PMU_CACHE_MISS {
         Some(crate::CacheCounterKind::Miss)
     } else {
@@ -209,9 +223,9 @@ pub extern "C" fn pmu_builder_add_cache_counter(
 
     let op = if cop == PMUCacheOpKind_PMU_CACHE_READ {
         Some(crate::CacheOpKind::Read)
-    } else if ckind == PMUCacheOpKind_PMU_CACHE_WRITE {
+    } else if cop == PMUCacheOpKind_PMU_CACHE_WRITE {
         Some(crate::CacheOpKind::Write)
-    } else if ckind == PMUCacheOpKind_PMU_CACHE_PREFETCH {
+    } else if cop == PMUCacheOpKind_PMU_CACHE_PREFETCH {
         Some(crate::CacheOpKind::Prefetch)
     } else {
         None
@@ -248,7 +262,7 @@ pub extern "C" fn pmu_counters_peek_value(
 
     match result {
         Some(result) => {
-            *value = result.value as u64;
+            *value = result.scaled();
             return 0;
         }
         None => {
@@ -1,69 +1,144 @@
 mod backends;
+mod error;
 mod events;
 mod ffi;
+#[cfg(target_os = "linux")]
+mod maps;
+mod memory;
+mod metrics;
+mod parser;
+mod process;
+mod sampling;
+#[cfg(target_os = "linux")]
+mod symbolizer;
+mod topdown;
+#[cfg(target_os = "linux")]
+mod unwind;
+
+#[cfg(target_os = "linux")]
+pub use backends::{Pmu, PmuConfigField, PmuEvent, PmuFormat};
+pub use error::Error;
+pub use events::{
+    get_cpu_info, get_processor_family, try_get_processor_family, CpuInfo, CpuTopology,
+    ProcessorFamily,
+};
+pub use memory::{MemoryDataSource, MemoryLevel, MemoryOp, MemorySampling, SnoopResult, TlbAccess};
+pub use metrics::{list_metrics, list_metrics_for_family, MetricKind, MetricValue};
+pub use parser::parse_events;
+pub use sampling::{BranchEntry, BranchFilter, CallChainMode, Sample, SamplesIterator, Symbol};
+#[cfg(target_os = "linux")]
+pub use symbolizer::Symbolizer;
+pub use topdown::{max_topdown_level, TopDown, TopDownLevel, TopDownLevel2};
+
+use std::borrow::Cow;
+use std::rc::Rc;
+
+// Core types of hybrid CPUs, named after their kernel PMUs cpu_core and cpu_atom
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HybridCoreType {
+    Core,
+    Atom,
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SystemCounterKind {
     Software,
     Hardware,
+    // Raw hardware event only valid on one core type of a hybrid CPU
+    Hybrid(HybridCoreType),
+    // An event of a PMU discovered at runtime
+    Pmu { pmu: String, type_id: u32 },
 }
 
 #[derive(Debug, Clone)]
 pub struct SystemCounter {
     pub kind: SystemCounterKind,
-    pub name: &'static str,
-    pub desc: &'static str,
+    pub name: Cow<'static, str>,
+    pub desc: Cow<'static, str>,
+    // Whether the event supports precise sampling, None when the event tables do not say
+    pub precise: Option<bool>,
+    // Bitmask of the general-purpose counters the event can be scheduled on,
+    // None when it is not restricted or the event tables do not say
+    pub counters: Option<u64>,
     pub(crate) encoding: u64,
+    // Extra event configuration, e.g. offcore response or load latency threshold
+    pub(crate) config1: u64,
+    pub(crate) config2: u64,
 }
 
-fn create_backend(kind: backends::BackendKind) -> Result<Box<dyn backends::Backend>, String> {
+fn create_backend(kind: backends::BackendKind) -> Result<Box<dyn backends::Backend>, Error> {
     match kind {
         backends::BackendKind::Perf => {
             cfg_if::cfg_if! {
                 if #[cfg(target_os = "linux")] {
                    Ok(Box::new(backends::PerfBackend::new()))
                 } else {
-                    Err("Backend not supported for current OS".to_string())
+                    Err(Error::BackendUnavailable("Backend not supported for current OS".to_string()))
                 }
             }
         }
         backends::BackendKind::KPerf => {
             cfg_if::cfg_if! {
                 if #[cfg(target_os = "macos")] {
-                   Ok(Box::new(backends::KPerfBackend::new()))
+                   Ok(Box::new(backends::KPerfBackend::new()?))
                 } else {
-                    Err("Backend not supported for current OS".to_string())
+                    Err(Error::BackendUnavailable("Backend not supported for current OS".to_string()))
                 }
             }
         }
     }
 }
 
-fn create_default_backend() -> Result<Box<dyn backends::Backend>, String> {
+fn create_default_backend() -> Result<Box<dyn backends::Backend>, Error> {
     cfg_if::cfg_if! {
         if #[cfg(target_os = "linux")] {
             create_backend(backends::BackendKind::Perf)
         } else if #[cfg(target_os = "macos")] {
             create_backend(backends::BackendKind::KPerf)
         } else {
-            Err("Unsupported OS".to_string())
+            Err(Error::BackendUnavailable("Unsupported OS".to_string()))
         }
     }
 }
 
 pub fn list_events_for_backend(kind: backends::BackendKind) -> Vec<SystemCounter> {
+    return list_backend_events(kind, &events::get_processor_family());
+}
+
+fn list_backend_events(
+    kind: backends::BackendKind,
+    family: &ProcessorFamily,
+) -> Vec<SystemCounter> {
+    #[cfg(target_os = "linux")]
+    let is_perf = matches!(kind, backends::BackendKind::Perf);
     let mut events = backends::get_software_events(kind);
 
-    let hw_events = events::get_hardware_events();
+    let hw_events = events::get_hardware_events(family);
     events.extend(hw_events);
 
+    #[cfg(target_os = "linux")]
+    if is_perf {
+        events.extend(backends::get_kernel_events());
+    }
+
     return events;
 }
 
+// PMUs advertised by the kernel in /sys/bus/event_source/devices
+#[cfg(target_os = "linux")]
+pub fn list_pmus() -> Vec<Pmu> {
+    return backends::list_pmus();
+}
+
 pub fn list_events() -> Vec<SystemCounter> {
     return list_events_for_backend(backends::BackendKind::Perf);
 }
 
+// Same as list_events, with the event tables of the given family instead of the detected one
+pub fn list_events_for_family(family: &ProcessorFamily) -> Vec<SystemCounter> {
+    return list_backend_events(backends::BackendKind::Perf, family);
+}
+
 pub fn find_event_by_name(name: &str) -> Option<SystemCounter> {
     for e in list_events() {
         if e.to_string() == name {
@@ -85,14 +160,23 @@ pub enum CacheLevelKind {
     DTLB,
     ITLB,
     BPU,
+    Node,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+// Access counts every lookup of the cache, hits and misses alike, as perf's
+// PERF_COUNT_HW_CACHE_RESULT_ACCESS does
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum CacheCounterKind {
-    Hit,
+    Access,
     Miss,
 }
 
+#[allow(non_upper_case_globals)]
+impl CacheCounterKind {
+    #[deprecated(note = "counts accesses, not hits, use CacheCounterKind::Access")]
+    pub const Hit: CacheCounterKind = CacheCounterKind::Access;
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum CacheOpKind {
     Read,
@@ -115,6 +199,45 @@ pub enum CounterKind {
     Branches,
     BranchMisses,
     System(SystemCounter),
+    // Raw PMU encoding, as in perf's rNNN
+    Raw(u64),
+    // Kernel tracepoint, e.g. syscalls:sys_enter_read
+    Tracepoint {
+        subsystem: String,
+        name: String,
+    },
+    // Hits of a kernel function, given as name, name+offset or address
+    Kprobe {
+        function: String,
+    },
+    // Hits of a function in a binary, given as symbol, symbol+offset or a
+    // plain file offset. Only the attached process is counted, if any.
+    Uprobe {
+        binary: String,
+        symbol_or_offset: String,
+    },
+}
+
+impl CounterKind {
+    // Software events, uncore PMUs and the top-down events do not take a
+    // general-purpose core PMU counter
+    pub(crate) fn uses_core_counter(&self) -> bool {
+        if topdown::is_topdown_counter(self) {
+            return false;
+        }
+
+        match self {
+            CounterKind::System(counter) => match &counter.kind {
+                SystemCounterKind::Software => false,
+                SystemCounterKind::Pmu { pmu, .. } => pmu.starts_with("cpu"),
+                SystemCounterKind::Hardware | SystemCounterKind::Hybrid(_) => true,
+            },
+            CounterKind::Tracepoint { .. }
+            | CounterKind::Kprobe { .. }
+            | CounterKind::Uprobe { .. } => false,
+            _ => true,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -125,10 +248,50 @@ pub enum SamplingPrecision {
     ExactIP,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventModifiers {
+    pub exclude_user: bool,
+    pub exclude_kernel: bool,
+    pub exclude_hv: bool,
+    pub exclude_idle: bool,
+    pub exclude_host: bool,
+    pub exclude_guest: bool,
+    // Only valid on the first counter of a group
+    pub pinned: bool,
+    pub exclusive: bool,
+}
+
+// Counters without modifiers count user space only, which needs no privileges
+// under the default perf_event_paranoid
+impl Default for EventModifiers {
+    fn default() -> EventModifiers {
+        return EventModifiers {
+            exclude_user: false,
+            exclude_kernel: true,
+            exclude_hv: true,
+            exclude_idle: false,
+            exclude_host: false,
+            exclude_guest: false,
+            pinned: false,
+            exclusive: false,
+        };
+    }
+}
+
+// Hardware counters of the core PMU available to a single group
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CounterCapacity {
+    pub general_counters: usize,
+    // Intel fixed counter 0 counts instructions, 1 counts cycles
+    pub fixed_counters: usize,
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct StandaloneCounter {
     pub precision: SamplingPrecision,
     pub counter: CounterKind,
+    // None leaves privilege levels up to the backend
+    pub modifiers: Option<EventModifiers>,
 }
 
 #[derive(Debug, Clone)]
@@ -136,26 +299,83 @@ pub struct CountersGroup {
     counters: Vec<StandaloneCounter>,
 }
 
+enum ProcessSelection {
+    Current,
+    Pid(i32),
+    // Taken by build, a command is launched only once
+    Command(std::cell::RefCell<Option<std::process::Command>>),
+}
+
 pub struct Builder {
     backend: Box<dyn backends::Backend>,
-    pid: Option<i32>,
+    process: ProcessSelection,
+    cpus: backends::CpuTarget,
     groups: Vec<CountersGroup>,
     period: Option<u32>,
-    callback: Option<Box<dyn Fn() -> ()>>,
+    callback: Option<Rc<dyn Fn(&Sample)>>,
+    family: Option<ProcessorFamily>,
+    split_groups: bool,
+    metrics: Vec<metrics::MetricBinding>,
+    topdown: Option<topdown::TopDownBinding>,
+    memory_samples: bool,
+    physical_addresses: bool,
+    callchain: Option<CallChainMode>,
+    symbolize: bool,
+    branch_stack: Option<BranchFilter>,
 }
 
 pub struct Counters {
     backend_counters: Box<dyn backends::BackendCounters>,
+    callback: Option<Rc<dyn Fn(&Sample)>>,
+    launched: Option<process::LaunchedProcess>,
+    metrics: Vec<metrics::MetricBinding>,
+    topdown: Option<topdown::TopDownBinding>,
+}
+
+enum ValueSelection {
+    Combined,
+    Cpu(u32),
+    CoreType(HybridCoreType),
 }
 
 pub struct CountersIterator<'a> {
     cur: usize,
+    selection: ValueSelection,
     backend_counters: &'a Box<dyn backends::BackendCounters>,
 }
 
+#[derive(Debug, Clone)]
 pub struct CounterValue {
     pub kind: CounterKind,
-    pub value: usize,
+    // None when the value is not bound to a single CPU or is summed over several
+    pub cpu: Option<u32>,
+    // None unless the value only covers one core type of a hybrid CPU
+    pub core_type: Option<HybridCoreType>,
+    pub raw: u64,
+    pub time_enabled: u64,
+    pub time_running: u64,
+}
+
+// Gives the event a counter its mask allows, moving other events along an
+// augmenting path if needed. owners[counter] is the event holding the counter
+fn assign_counter(
+    event: usize,
+    masks: &[u64],
+    owners: &mut [Option<usize>],
+    seen: &mut [bool],
+) -> bool {
+    for counter in 0..owners.len() {
+        if masks[event] & (1 << counter) == 0 || seen[counter] {
+            continue;
+        }
+        seen[counter] = true;
+        if owners[counter].map_or(true, |other| assign_counter(other, masks, owners, seen)) {
+            owners[counter] = Some(event);
+            return true;
+        }
+    }
+
+    return false;
 }
 
 impl CountersGroup {
@@ -167,11 +387,105 @@ impl CountersGroup {
         self.counters.push(StandaloneCounter {
             precision: SamplingPrecision::None,
             counter,
+            modifiers: None,
         });
     }
 
     pub fn add_counter_precise(&mut self, counter: CounterKind, precision: SamplingPrecision) {
-        self.counters.push(StandaloneCounter { precision, counter });
+        self.counters.push(StandaloneCounter {
+            precision,
+            counter,
+            modifiers: None,
+        });
+    }
+
+    pub fn add_counter_with_modifiers(&mut self, counter: CounterKind, modifiers: EventModifiers) {
+        self.counters.push(StandaloneCounter {
+            precision: SamplingPrecision::None,
+            counter,
+            modifiers: Some(modifiers),
+        });
+    }
+
+    pub fn add_counter_precise_with_modifiers(
+        &mut self,
+        counter: CounterKind,
+        precision: SamplingPrecision,
+        modifiers: EventModifiers,
+    ) {
+        self.counters.push(StandaloneCounter {
+            precision,
+            counter,
+            modifiers: Some(modifiers),
+        });
+    }
+
+    // General-purpose counters the group occupies once cycles and
+    // instructions have been moved to the fixed counters
+    pub fn general_counters_needed(&self, capacity: &CounterCapacity) -> usize {
+        return self.general_counter_masks(capacity).len();
+    }
+
+    // The counters each event on a general-purpose counter may use
+    fn general_counter_masks(&self, capacity: &CounterCapacity) -> Vec<u64> {
+        let mut instructions_fixed = capacity.fixed_counters >= 1;
+        let mut cycles_fixed = capacity.fixed_counters >= 2;
+
+        let mut masks = vec![];
+        for counter in self.counters.iter().map(|c| &c.counter) {
+            if !counter.uses_core_counter() {
+                continue;
+            }
+            match counter {
+                CounterKind::Instructions if instructions_fixed => instructions_fixed = false,
+                CounterKind::Cycles if cycles_fixed => cycles_fixed = false,
+                CounterKind::System(SystemCounter {
+                    counters: Some(mask),
+                    ..
+                }) => masks.push(*mask),
+                _ => masks.push(u64::MAX),
+            }
+        }
+
+        return masks;
+    }
+
+    // Whether every event gets a counter, within the ones it is restricted to
+    fn fits(&self, capacity: &CounterCapacity) -> bool {
+        let masks = self.general_counter_masks(capacity);
+        if masks.len() > capacity.general_counters {
+            return false;
+        }
+
+        let mut owners = vec![None; capacity.general_counters.min(64)];
+        for event in 0..masks.len() {
+            let mut seen = vec![false; owners.len()];
+            if !assign_counter(event, &masks, &mut owners, &mut seen) {
+                return false;
+            }
+        }
+
+        return true;
+    }
+
+    // Splits the group into consecutive groups that each fit into the PMU
+    fn split(&self, capacity: &CounterCapacity) -> Vec<CountersGroup> {
+        let mut groups = vec![];
+        let mut current = CountersGroup::new();
+
+        for counter in &self.counters {
+            current.counters.push(counter.clone());
+            if current.counters.len() > 1 && !current.fits(capacity) {
+                let overflow = current.counters.pop().unwrap();
+                groups.push(current);
+                current = CountersGroup {
+                    counters: vec![overflow],
+                };
+            }
+        }
+        groups.push(current);
+
+        return groups;
     }
 
     pub fn create_from_counter(counter: CounterKind) -> CountersGroup {
@@ -179,41 +493,184 @@ impl CountersGroup {
             counters: vec![StandaloneCounter {
                 precision: SamplingPrecision::None,
                 counter,
+                modifiers: None,
             }],
         };
     }
 }
 
+impl HybridCoreType {
+    pub fn pmu_name(&self) -> &'static str {
+        match self {
+            HybridCoreType::Core => "cpu_core",
+            HybridCoreType::Atom => "cpu_atom",
+        }
+    }
+}
+
+impl EventModifiers {
+    pub fn user_only() -> EventModifiers {
+        return EventModifiers {
+            exclude_kernel: true,
+            exclude_hv: true,
+            ..Default::default()
+        };
+    }
+
+    pub fn kernel_only() -> EventModifiers {
+        return EventModifiers {
+            exclude_user: true,
+            exclude_kernel: false,
+            exclude_hv: true,
+            ..Default::default()
+        };
+    }
+}
+
+impl CounterValue {
+    // Raw value extrapolated to the whole time the counter was enabled
+    pub fn scaled(&self) -> u64 {
+        if self.time_running == 0 || self.time_running >= self.time_enabled {
+            return self.raw;
+        }
+
+        let scaled = (self.raw as u128 * self.time_enabled as u128) / self.time_running as u128;
+        return scaled.min(u64::MAX as u128) as u64;
+    }
+
+    // Fraction of the enabled time the counter was actually running on the PMU
+    pub fn confidence(&self) -> f64 {
+        if self.time_enabled == 0 {
+            return 0.0;
+        }
+
+        return self.time_running as f64 / self.time_enabled as f64;
+    }
+
+    pub fn is_multiplexed(&self) -> bool {
+        return self.time_running < self.time_enabled;
+    }
+
+    // The counter never got onto the PMU, so its value carries no information
+    pub fn never_scheduled(&self) -> bool {
+        return self.time_running == 0;
+    }
+}
+
 impl Builder {
     fn default(backend: Box<dyn backends::Backend>) -> Builder {
         return Builder {
             backend,
-            pid: None,
+            process: ProcessSelection::Current,
+            cpus: backends::CpuTarget::Any,
             groups: vec![],
             period: None,
             callback: None,
+            family: None,
+            split_groups: false,
+            metrics: vec![],
+            topdown: None,
+            memory_samples: false,
+            physical_addresses: false,
+            callchain: None,
+            symbolize: false,
+            branch_stack: None,
         };
     }
 
+    // Panics where try_new fails, e.g. when no backend is available on this system
+    #[deprecated(note = "use Builder::try_new, which does not panic without a backend")]
     pub fn new() -> Builder {
-        return Builder::default(create_default_backend().unwrap());
+        return Builder::try_new().unwrap();
     }
 
-    pub fn new_from_backend(backend: backends::BackendKind) -> Result<Builder, String> {
+    // Fails without a backend or with an invalid LIBPMU_PROCESSOR_FAMILY
+    pub fn try_new() -> Result<Builder, Error> {
+        events::try_get_processor_family()?;
+        return Ok(Builder::default(create_default_backend()?));
+    }
+
+    pub fn new_from_backend(backend: backends::BackendKind) -> Result<Builder, Error> {
+        events::try_get_processor_family()?;
         return Ok(Builder::default(create_backend(backend)?));
     }
 
+    // Counts an already running process, counting starts at Counters::start
     pub fn attach_pid(&mut self, pid: i32) {
-        self.pid = Some(pid);
+        self.process = ProcessSelection::Pid(pid);
     }
 
     pub fn attach(&mut self, child: std::process::Child) {
-        self.pid = Some(child.id() as i32);
+        self.process = ProcessSelection::Pid(child.id() as i32);
     }
 
-    pub fn enable_sampling(&mut self, period: u32, callback: Box<dyn Fn() -> ()>) {
+    // The command is spawned by the next build and held right before exec
+    // until Counters::start, so counting begins exactly at program entry
+    pub fn launch(&mut self, command: std::process::Command) {
+        self.process = ProcessSelection::Command(std::cell::RefCell::new(Some(command)));
+    }
+
+    pub fn attach_cpu(&mut self, cpu: u32) {
+        match &mut self.cpus {
+            backends::CpuTarget::List(cpus) => {
+                if !cpus.contains(&cpu) {
+                    cpus.push(cpu);
+                }
+            }
+            _ => self.cpus = backends::CpuTarget::List(vec![cpu]),
+        }
+    }
+
+    pub fn system_wide(&mut self) {
+        self.cpus = backends::CpuTarget::Online;
+    }
+
+    // The callback sees the samples when Counters::poll_samples or Counters::stop
+    // drains them. The ring buffer of a group holds 64 pages of records, samples
+    // that do not fit are dropped and counted by Counters::lost_samples.
+    pub fn enable_sampling(&mut self, period: u32, callback: Box<dyn Fn(&Sample)>) {
         self.period = Some(period);
-        self.callback = Some(callback);
+        self.callback = Some(Rc::from(callback));
+    }
+
+    // Samples are only collected, use Counters::samples to consume them
+    pub fn set_sample_period(&mut self, period: u32) {
+        self.period = Some(period);
+    }
+
+    // Samples also carry the data address, weight and data source of the
+    // access, physical addresses need CAP_PERFMON or perf_event_paranoid <= 1
+    pub fn enable_memory_samples(&mut self, physical_addresses: bool) {
+        self.memory_samples = true;
+        self.physical_addresses |= physical_addresses;
+    }
+
+    // Adds the event memory accesses are sampled with on this CPU, IBS on AMD
+    // and the precise load latency or store events on Intel. The period set
+    // with enable_sampling or set_sample_period counts accesses.
+    pub fn sample_memory(&mut self, kind: MemorySampling) -> Result<(), Error> {
+        let group = self.backend.memory_sampling_group(kind)?;
+        self.groups.push(group);
+        self.memory_samples = true;
+
+        return Ok(());
+    }
+
+    // Samples also carry the call chain that led to the sampled ip
+    pub fn enable_callchains(&mut self, mode: CallChainMode) {
+        self.callchain = Some(mode);
+    }
+
+    // Samples also carry the most recent taken branches the filter selects,
+    // needs the LBR on x86 or BRBE on arm64
+    pub fn enable_branch_stack(&mut self, filter: BranchFilter) {
+        self.branch_stack = Some(filter);
+    }
+
+    // Follows the mappings of the sampled processes so that Counters::symbolize
+    // can resolve sampled addresses to functions
+    pub fn enable_symbolization(&mut self) {
+        self.symbolize = true;
     }
 
     pub fn add_counter(&mut self, counter: CounterKind) {
@@ -221,26 +678,348 @@ impl Builder {
             .push(CountersGroup::create_from_counter(counter));
     }
 
-    pub fn build(&self) -> Result<Counters, String> {
-        let backend_counters = self.backend.create_counters(self.pid, &self.groups)?;
-        return Ok(Counters { backend_counters });
+    pub fn add_counter_with_modifiers(&mut self, counter: CounterKind, modifiers: EventModifiers) {
+        let mut group = CountersGroup::new();
+        group.add_counter_with_modifiers(counter, modifiers);
+        self.groups.push(group);
+    }
+
+    pub fn add_group(&mut self, group: CountersGroup) {
+        self.groups.push(group);
+    }
+
+    // Event tables of this family are used instead of the detected ones,
+    // set it before adding events by name
+    pub fn set_processor_family(&mut self, family: ProcessorFamily) {
+        self.family = Some(family);
+    }
+
+    // Groups that need more counters than the PMU has are split into
+    // smaller groups multiplexed by the kernel instead of failing to build
+    pub fn enable_group_splitting(&mut self) {
+        self.split_groups = true;
+    }
+
+    // None when the backend cannot tell how many counters the PMU has
+    pub fn counter_capacity(&self) -> Option<CounterCapacity> {
+        return self.backend.counter_capacity();
+    }
+
+    fn processor_family(&self) -> ProcessorFamily {
+        return self.family.unwrap_or_else(events::get_processor_family);
+    }
+
+    // Adds events written in perf syntax, e.g. "{cycles,instructions}:u,r01c2"
+    pub fn add_events(&mut self, spec: &str) -> Result<(), Error> {
+        self.groups.extend(parser::parse_events_for_family(
+            spec,
+            &self.processor_family(),
+        )?);
+        return Ok(());
+    }
+
+    // Adds the events the metric is computed from, metrics over the same
+    // events share one group
+    pub fn add_metric(&mut self, kind: MetricKind) -> Result<(), Error> {
+        let family = self.processor_family();
+        let definition = metrics::find_definition(kind, &family)
+            .ok_or(Error::EventNotSupported(format!("metric {}", kind.name())))?;
+
+        let binding = match self
+            .metrics
+            .iter()
+            .find(|m| m.definition.events == definition.events)
+        {
+            Some(existing) => metrics::MetricBinding {
+                definition,
+                first_counter: existing.first_counter,
+                num_counters: existing.num_counters,
+            },
+            None => {
+                let groups = parser::parse_events_for_family(&definition.events, &family)?;
+                let binding = metrics::MetricBinding {
+                    definition,
+                    first_counter: self.groups.iter().map(|g| g.counters.len()).sum(),
+                    num_counters: groups.iter().map(|g| g.counters.len()).sum(),
+                };
+                self.groups.extend(groups);
+                binding
+            }
+        };
+        self.metrics.push(binding);
+
+        return Ok(());
+    }
+
+    // Adds the slots group the top-down breakdown is computed from,
+    // read it with Counters::topdown
+    pub fn add_topdown(&mut self, level: TopDownLevel) -> Result<(), Error> {
+        if self.topdown.is_some() {
+            return Err(Error::InvalidConfiguration(
+                "Top-down analysis was already added".to_string(),
+            ));
+        }
+        match topdown::max_topdown_level(&self.processor_family()) {
+            Some(max) if level <= max => {}
+            _ => {
+                return Err(Error::EventNotSupported(format!(
+                    "top-down level {}",
+                    level as u32 + 1
+                )))
+            }
+        }
+
+        let group = self.backend.topdown_group(level)?;
+        self.topdown = Some(topdown::TopDownBinding {
+            level,
+            first_counter: self.groups.iter().map(|g| g.counters.len()).sum(),
+        });
+        self.groups.push(group);
+
+        return Ok(());
+    }
+
+    pub fn build(&self) -> Result<Counters, Error> {
+        if self.groups.iter().any(|g| g.counters.is_empty()) {
+            return Err(Error::InvalidConfiguration(
+                "Counter groups must not be empty".to_string(),
+            ));
+        }
+
+        if self.cpus == backends::CpuTarget::Online
+            && !matches!(self.process, ProcessSelection::Current)
+        {
+            return Err(Error::InvalidConfiguration(
+                "System-wide counting cannot be combined with an attached process".to_string(),
+            ));
+        }
+
+        let mut groups = self.groups.clone();
+        if let Some(capacity) = self.backend.counter_capacity() {
+            groups = vec![];
+            for group in &self.groups {
+                let needed = group.general_counters_needed(&capacity);
+                if group.fits(&capacity) {
+                    groups.push(group.clone());
+                } else if self.split_groups && capacity.general_counters > 0 {
+                    groups.extend(group.split(&capacity));
+                } else if needed > capacity.general_counters {
+                    return Err(Error::TooManyCounters {
+                        requested: needed,
+                        max: Some(capacity.general_counters),
+                    });
+                } else {
+                    return Err(Error::InvalidConfiguration(
+                        "The events of a group are restricted to too few counters".to_string(),
+                    ));
+                }
+            }
+        }
+
+        let sampling = self.period.map(|period| sampling::SamplingConfig {
+            period: period as u64,
+            memory: self.memory_samples,
+            physical_addresses: self.physical_addresses,
+            callchain: self.callchain,
+            symbolize: self.symbolize,
+            branch_stack: self.branch_stack,
+        });
+
+        let mut launched = None;
+        let process = match &self.process {
+            ProcessSelection::Current => backends::ProcessTarget::Current,
+            ProcessSelection::Pid(pid) => backends::ProcessTarget::Attached(*pid),
+            ProcessSelection::Command(command) => {
+                let command = command
+                    .borrow_mut()
+                    .take()
+                    .ok_or(Error::InvalidConfiguration(
+                        "The command was already launched by a previous build".to_string(),
+                    ))?;
+                let child = process::LaunchedProcess::spawn(command)?;
+                let pid = child.pid();
+                launched = Some(child);
+                backends::ProcessTarget::Launched(pid)
+            }
+        };
+
+        let backend_counters = self.backend.create_counters(
+            &process,
+            &self.cpus,
+            &groups,
+            sampling.as_ref(),
+            &self.processor_family(),
+        )?;
+        return Ok(Counters {
+            backend_counters,
+            callback: self.callback.clone(),
+            launched,
+            metrics: self.metrics.clone(),
+            topdown: self.topdown.clone(),
+        });
     }
 }
 
 impl Counters {
-    pub fn start(&mut self) {
-        self.backend_counters.start();
+    pub fn start(&mut self) -> Result<(), Error> {
+        self.backend_counters.start()?;
+
+        if let Some(launched) = &mut self.launched {
+            launched.release()?;
+        }
+
+        return Ok(());
+    }
+
+    // Waits for the process spawned with Builder::launch to exit
+    pub fn wait(&mut self) -> Result<std::process::ExitStatus, Error> {
+        match &mut self.launched {
+            Some(launched) => launched.wait(),
+            None => Err(Error::InvalidConfiguration(
+                "No process was launched".to_string(),
+            )),
+        }
+    }
+    pub fn stop(&mut self) -> Result<(), Error> {
+        self.backend_counters.stop()?;
+        self.poll_samples();
+
+        return Ok(());
+    }
+
+    // Passes the samples collected so far to the sampling callback. Long runs
+    // have to call it periodically, the ring buffers are otherwise only drained
+    // by stop.
+    pub fn poll_samples(&mut self) {
+        if let Some(callback) = &self.callback {
+            while let Some(sample) = self.backend_counters.next_sample() {
+                callback(&sample);
+            }
+        }
+    }
+
+    // Samples the kernel dropped because the ring buffer was full, as far as
+    // the records drained by samples or poll_samples tell
+    pub fn lost_samples(&self) -> u64 {
+        return self.backend_counters.lost_samples();
+    }
+
+    // Times the kernel throttled sampling for exceeding perf_event_max_sample_rate
+    pub fn throttled(&self) -> u64 {
+        return self.backend_counters.throttled();
     }
-    pub fn stop(&mut self) {
-        self.backend_counters.stop();
+
+    pub fn samples<'a>(&'a mut self) -> SamplesIterator<'a> {
+        return SamplesIterator {
+            backend_counters: &mut self.backend_counters,
+        };
+    }
+
+    // Function containing the address in the given process, None unless
+    // symbolization was enabled in the Builder or when there is no symbol for it
+    pub fn symbolize(&mut self, pid: u32, ip: u64) -> Option<Symbol> {
+        return self.backend_counters.symbolize(pid, ip);
+    }
+
+    // One entry per frame of the sample call chain, or just its ip without one
+    pub fn symbolize_callchain(&mut self, sample: &Sample) -> Vec<Option<Symbol>> {
+        if sample.callchain.is_empty() {
+            return vec![self.symbolize(sample.pid, sample.ip)];
+        }
+
+        let mut symbols = vec![];
+        for (idx, ip) in sample.callchain.iter().enumerate() {
+            // Kernel frames are never in a mapped file
+            if idx < sample.kernel_frames {
+                symbols.push(None);
+                continue;
+            }
+            // Return addresses point after the call, the line of the call itself
+            // is the one wanted
+            let address = if idx == 0 || idx == sample.kernel_frames {
+                *ip
+            } else {
+                ip.saturating_sub(1)
+            };
+            let symbol = self.symbolize(sample.pid, address).map(|mut symbol| {
+                symbol.offset += ip - address;
+                symbol
+            });
+            symbols.push(symbol);
+        }
+
+        return symbols;
     }
 
     pub fn iter<'a>(&'a self) -> CountersIterator<'a> {
         return CountersIterator {
             cur: 0,
+            selection: ValueSelection::Combined,
+            backend_counters: &self.backend_counters,
+        };
+    }
+
+    pub fn iter_cpu<'a>(&'a self, cpu: u32) -> CountersIterator<'a> {
+        return CountersIterator {
+            cur: 0,
+            selection: ValueSelection::Cpu(cpu),
             backend_counters: &self.backend_counters,
         };
     }
+
+    // Values counted on one core type only, counters that were not opened on
+    // it yield a zero value that was never scheduled
+    pub fn iter_core_type<'a>(&'a self, core_type: HybridCoreType) -> CountersIterator<'a> {
+        return CountersIterator {
+            cur: 0,
+            selection: ValueSelection::CoreType(core_type),
+            backend_counters: &self.backend_counters,
+        };
+    }
+
+    // Core types counters were opened on, empty on non-hybrid CPUs
+    pub fn core_types(&self) -> Vec<HybridCoreType> {
+        return self.backend_counters.core_types();
+    }
+
+    // CPUs counters were opened on, empty unless attach_cpu or system_wide was used
+    pub fn cpus(&self) -> Vec<u32> {
+        return self.backend_counters.cpus();
+    }
+
+    // Metrics added with Builder::add_metric, computed from the scaled counter values
+    pub fn metrics(&self) -> Vec<MetricValue> {
+        let mut values = vec![];
+
+        for binding in &self.metrics {
+            let first = binding.first_counter;
+            let counters: Option<Vec<f64>> = (first..first + binding.num_counters)
+                .map(|id| self.backend_counters.peek(id).map(|v| v.scaled() as f64))
+                .collect();
+
+            if let Some(counters) = counters {
+                values.push(MetricValue {
+                    kind: binding.definition.kind,
+                    value: (binding.definition.formula)(&counters),
+                });
+            }
+        }
+
+        return values;
+    }
+
+    // Breakdown of the group added with Builder::add_topdown, None when
+    // there is none or it was not read yet
+    pub fn topdown(&self) -> Option<TopDown> {
+        let binding = self.topdown.as_ref()?;
+        let first = binding.first_counter;
+        // All events share one group so the raw values need no scaling
+        let values: Vec<u64> = (first..first + binding.level.event_names().len())
+            .map(|id| self.backend_counters.peek(id).map(|v| v.raw))
+            .collect::<Option<Vec<u64>>>()?;
+
+        return TopDown::from_values(binding.level, &values);
+    }
 }
 
 impl Iterator for CountersIterator<'_> {
@@ -249,15 +1028,26 @@ impl Iterator for CountersIterator<'_> {
     fn next(&mut self) -> Option<Self::Item> {
         let current = self.cur;
         self.cur += 1;
-        return self.backend_counters.peek(current);
+        match self.selection {
+            ValueSelection::Combined => self.backend_counters.peek(current),
+            ValueSelection::Cpu(cpu) => self.backend_counters.peek_cpu(current, cpu),
+            ValueSelection::CoreType(core_type) => {
+                self.backend_counters.peek_core_type(current, core_type)
+            }
+        }
     }
 }
 
 impl ToString for SystemCounter {
     fn to_string(&self) -> String {
-        let prefix = match self.kind {
+        let prefix = match &self.kind {
             SystemCounterKind::Software => "SW",
             SystemCounterKind::Hardware => "HW",
+            SystemCounterKind::Hybrid(core_type) => {
+                return format!("{}/{}/", core_type.pmu_name(), self.name)
+            }
+            // Same naming as perf, e.g. msr/tsc/
+            SystemCounterKind::Pmu { pmu, .. } => return format!("{}/{}/", pmu, self.name),
         };
 
         return format!("{}:{}", prefix, self.name).into();
@@ -273,6 +1063,13 @@ impl ToString for CounterKind {
             CounterKind::BranchMisses => "branch_misses".into(),
             CounterKind::Cache(cache) => cache.to_string(),
             CounterKind::System(counter) => counter.to_string(),
+            CounterKind::Raw(config) => format!("r{:x}", config),
+            CounterKind::Tracepoint { subsystem, name } => format!("{}:{}", subsystem, name),
+            CounterKind::Kprobe { function } => format!("kprobe:{}", function),
+            CounterKind::Uprobe {
+                binary,
+                symbol_or_offset,
+            } => format!("uprobe:{}:{}", binary, symbol_or_offset),
         }
     }
 }
@@ -289,6 +1086,7 @@ impl ToString for CacheLevelKind {
             CacheLevelKind::DTLB => "dTLB".into(),
             CacheLevelKind::ITLB => "iTLB".into(),
             CacheLevelKind::BPU => "BPU".into(),
+            CacheLevelKind::Node => "node".into(),
         }
     }
 }
@@ -296,7 +1094,7 @@ impl ToString for CacheLevelKind {
 impl ToString for CacheCounterKind {
     fn to_string(&self) -> String {
         match self {
-            CacheCounterKind::Hit => "hit".into(),
+            CacheCounterKind::Access => "access".into(),
             CacheCounterKind::Miss => "miss".into(),
         }
     }
@@ -325,3 +1123,180 @@ impl PartialEq for SystemCounter {
 }
 
 impl Eq for SystemCounter {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Only reports its capacity, opening counters always fails
+    struct CapacityBackend(CounterCapacity);
+
+    impl backends::Backend for CapacityBackend {
+        fn create_counters(
+            &self,
+            _process: &backends::ProcessTarget,
+            _cpus: &backends::CpuTarget,
+            _groups: &[CountersGroup],
+            _sampling: Option<&sampling::SamplingConfig>,
+            _family: &ProcessorFamily,
+        ) -> Result<Box<dyn backends::BackendCounters>, Error> {
+            return Err(Error::BackendUnavailable("test backend".to_string()));
+        }
+
+        fn counter_capacity(&self) -> Option<CounterCapacity> {
+            return Some(self.0);
+        }
+
+        fn topdown_group(&self, level: TopDownLevel) -> Result<CountersGroup, Error> {
+            return Err(Error::EventNotSupported(format!("{:?}", level)));
+        }
+
+        fn memory_sampling_group(&self, kind: MemorySampling) -> Result<CountersGroup, Error> {
+            return Err(Error::EventNotSupported(format!("{:?}", kind)));
+        }
+    }
+
+    const INTEL: CounterCapacity = CounterCapacity {
+        general_counters: 4,
+        fixed_counters: 3,
+    };
+
+    fn group(counters: &[CounterKind]) -> CountersGroup {
+        let mut group = CountersGroup::new();
+        for counter in counters {
+            group.add_counter(counter.clone());
+        }
+        return group;
+    }
+
+    fn kinds(counters: &[CounterKind]) -> Vec<String> {
+        return counters.iter().map(|c| format!("{:?}", c)).collect();
+    }
+
+    fn group_kinds(group: &CountersGroup) -> Vec<String> {
+        let counters: Vec<CounterKind> = group.counters.iter().map(|c| c.counter.clone()).collect();
+        return kinds(&counters);
+    }
+
+    #[test]
+    fn fixed_counters_are_not_general() {
+        let both = group(&[
+            CounterKind::Cycles,
+            CounterKind::Instructions,
+            CounterKind::Branches,
+        ]);
+        assert_eq!(both.general_counters_needed(&INTEL), 1);
+
+        // Fixed counter 0 counts instructions, cycles need fixed counter 1
+        let one_fixed = CounterCapacity {
+            general_counters: 4,
+            fixed_counters: 1,
+        };
+        assert_eq!(both.general_counters_needed(&one_fixed), 2);
+
+        let no_fixed = CounterCapacity {
+            general_counters: 6,
+            fixed_counters: 0,
+        };
+        assert_eq!(both.general_counters_needed(&no_fixed), 3);
+
+        // Each fixed counter only takes one event
+        let twice = group(&[CounterKind::Cycles, CounterKind::Cycles]);
+        assert_eq!(twice.general_counters_needed(&INTEL), 1);
+    }
+
+    #[test]
+    fn split_keeps_the_order() {
+        let counters = [
+            CounterKind::Cycles,
+            CounterKind::Branches,
+            CounterKind::BranchMisses,
+            CounterKind::Raw(0x1),
+            CounterKind::Raw(0x2),
+            CounterKind::Raw(0x3),
+            CounterKind::Instructions,
+        ];
+        let groups = group(&counters).split(&INTEL);
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(group_kinds(&groups[0]), kinds(&counters[..5]));
+        assert_eq!(group_kinds(&groups[1]), kinds(&counters[5..]));
+        for group in &groups {
+            assert!(group.general_counters_needed(&INTEL) <= INTEL.general_counters);
+        }
+    }
+
+    fn restricted(name: &'static str, counters: u64) -> CounterKind {
+        return CounterKind::System(SystemCounter {
+            kind: SystemCounterKind::Hardware,
+            name: Cow::Borrowed(name),
+            desc: Cow::Borrowed(""),
+            precise: None,
+            counters: Some(counters),
+            encoding: 0,
+            config1: 0,
+            config2: 0,
+        });
+    }
+
+    #[test]
+    fn counter_restrictions_are_kept() {
+        // Either one can take counter 0 or 1, the third one only counter 0
+        let counters = [
+            restricted("A", 0b11),
+            restricted("B", 0b1),
+            CounterKind::Branches,
+        ];
+        assert!(group(&counters).fits(&INTEL));
+
+        let counters = [
+            CounterKind::Cycles,
+            restricted("A", 0b1),
+            CounterKind::Branches,
+            restricted("B", 0b1),
+        ];
+        let both = group(&counters);
+        assert_eq!(both.general_counters_needed(&INTEL), 3);
+        assert!(!both.fits(&INTEL));
+
+        let groups = both.split(&INTEL);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(group_kinds(&groups[0]), kinds(&counters[..3]));
+        assert_eq!(group_kinds(&groups[1]), kinds(&counters[3..]));
+
+        // Counters beyond the general-purpose ones do not exist
+        assert!(!group(&[restricted("C", 0b10000)]).fits(&INTEL));
+
+        let mut builder = Builder::default(Box::new(CapacityBackend(INTEL)));
+        builder.add_group(both);
+        assert!(matches!(
+            builder.build(),
+            Err(Error::InvalidConfiguration(_))
+        ));
+    }
+
+    #[test]
+    fn oversized_group_fails_to_build() {
+        let mut builder = Builder::default(Box::new(CapacityBackend(INTEL)));
+        builder.add_group(group(&[
+            CounterKind::Cycles,
+            CounterKind::Branches,
+            CounterKind::BranchMisses,
+            CounterKind::Raw(0x1),
+            CounterKind::Raw(0x2),
+            CounterKind::Raw(0x3),
+        ]));
+
+        assert!(matches!(
+            builder.build(),
+            Err(Error::TooManyCounters {
+                requested: 5,
+                max: Some(4)
+            })
+        ));
+
+        // Split groups get as far as the backend
+        builder.enable_group_splitting();
+        assert!(matches!(builder.build(), Err(Error::BackendUnavailable(_))));
+    }
+}
@@ -0,0 +1,135 @@
+// A file mapped into a process, as listed in /proc/<pid>/maps
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MemoryMap {
+    pub start: u64,
+    pub end: u64,
+    // Offset of start in the file
+    pub offset: u64,
+    pub executable: bool,
+    pub path: String,
+}
+
+impl MemoryMap {
+    pub fn contains(&self, addr: u64) -> bool {
+        return addr >= self.start && addr < self.end;
+    }
+
+    pub fn file_offset(&self, addr: u64) -> u64 {
+        return addr - self.start + self.offset;
+    }
+}
+
+// Anonymous mappings and pseudo files like [stack] or [vdso] are skipped
+pub(crate) fn parse_maps(maps: &str) -> Vec<MemoryMap> {
+    let mut result = vec![];
+
+    for line in maps.lines() {
+        // start-end perms offset dev inode path, the path may contain spaces
+        let mut fields = line.splitn(6, ' ');
+        let (range, perms, offset) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(range), Some(perms), Some(offset)) => (range, perms, offset),
+            _ => continue,
+        };
+        let path = fields.nth(2).unwrap_or("").trim_start();
+        if !path.starts_with('/') {
+            continue;
+        }
+
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start, end),
+            None => continue,
+        };
+        let parse = |value: &str| u64::from_str_radix(value, 16).ok();
+        if let (Some(start), Some(end), Some(offset)) = (parse(start), parse(end), parse(offset)) {
+            result.push(MemoryMap {
+                start,
+                end,
+                offset,
+                executable: perms.contains('x'),
+                path: path.to_string(),
+            });
+        }
+    }
+
+    return result;
+}
+
+pub(crate) fn read_maps(pid: u32) -> Vec<MemoryMap> {
+    return match std::fs::read_to_string(format!("/proc/{}/maps", pid)) {
+        Ok(maps) => parse_maps(&maps),
+        Err(_) => vec![],
+    };
+}
+
+pub(crate) fn find_map(maps: &[MemoryMap], addr: u64) -> Option<&MemoryMap> {
+    return maps.iter().find(|map| map.contains(addr));
+}
+
+// (file offset, file size, virtual address) of the loadable segments of an ELF file
+pub(crate) fn load_segments(file: &object::File) -> Vec<(u64, u64, u64)> {
+    use object::{Object, ObjectSegment};
+
+    return file
+        .segments()
+        .map(|segment| {
+            let (offset, size) = segment.file_range();
+            (offset, size, segment.address())
+        })
+        .collect();
+}
+
+// Address in the ELF file of a file offset the process mapped
+pub(crate) fn file_address(segments: &[(u64, u64, u64)], offset: u64) -> Option<u64> {
+    return segments
+        .iter()
+        .find(|(start, size, _)| offset >= *start && offset < start + size)
+        .map(|(start, _, address)| offset - start + address);
+}
+
+// File offset of an address in the ELF file, the inverse of file_address
+pub(crate) fn address_file_offset(segments: &[(u64, u64, u64)], address: u64) -> Option<u64> {
+    return segments
+        .iter()
+        .find(|(_, size, start)| address >= *start && address < start + size)
+        .map(|(offset, _, start)| address - start + offset);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_file_mappings() {
+        let maps = "\
+55d0c0a00000-55d0c0a21000 r-xp 00002000 08:01 1234                       /usr/bin/my app
+55d0c0c21000-55d0c0c22000 rw-p 00021000 08:01 1234                       /usr/bin/my app
+7f0000000000-7f0000021000 rw-p 00000000 00:00 0
+7ffd00000000-7ffd00021000 rw-p 00000000 00:00 0                          [stack]
+not a mapping
+";
+        let result = parse_maps(maps);
+        assert_eq!(
+            result,
+            vec![
+                MemoryMap {
+                    start: 0x55d0c0a00000,
+                    end: 0x55d0c0a21000,
+                    offset: 0x2000,
+                    executable: true,
+                    path: "/usr/bin/my app".to_string(),
+                },
+                MemoryMap {
+                    start: 0x55d0c0c21000,
+                    end: 0x55d0c0c22000,
+                    offset: 0x21000,
+                    executable: false,
+                    path: "/usr/bin/my app".to_string(),
+                },
+            ]
+        );
+
+        let map = find_map(&result, 0x55d0c0a00100).unwrap();
+        assert_eq!(map.file_offset(0x55d0c0a00100), 0x2100);
+        assert!(find_map(&result, 0x55d0c0a21000).is_none());
+    }
+}
@@ -0,0 +1,209 @@
+// Memory accesses sampled with Builder::sample_memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemorySampling {
+    // Loads that took at least the threshold in core cycles to complete,
+    // 0 keeps the PMU default
+    Loads { latency_threshold: u32 },
+    // Not available with AMD IBS, which cannot tell stores from other ops
+    Stores,
+    // Instruction fetches with their i-cache and iTLB results, AMD IBS fetch only
+    Fetches,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryOp {
+    Load,
+    Store,
+    Prefetch,
+    Execute,
+    Unknown,
+}
+
+// Where the access was served from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryLevel {
+    L1,
+    // Line fill buffer, a miss already in flight to the same line
+    LineFillBuffer,
+    L2,
+    L3,
+    L4,
+    // A cache of unspecified level, e.g. of another core
+    AnyCache,
+    Ram,
+    PersistentMemory,
+    Cxl,
+    Io,
+    Uncached,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnoopResult {
+    None,
+    Hit,
+    Miss,
+    // Hit a modified line in another cache
+    HitModified,
+    // Forwarded from another cache without a snoop
+    Forwarded,
+    // Served by a peer cache
+    Peer,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlbAccess {
+    // None when the PMU did not tell hits from misses
+    pub hit: Option<bool>,
+    pub l1: bool,
+    pub l2: bool,
+    // Translated by the hardware page walker
+    pub walker: bool,
+    // Resolved by the OS page fault handler
+    pub os: bool,
+}
+
+// A decoded perf_mem_data_src of a memory sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryDataSource {
+    pub op: MemoryOp,
+    pub level: MemoryLevel,
+    // None when the PMU did not tell hits from misses
+    pub hit: Option<bool>,
+    // Served by another NUMA node
+    pub remote: bool,
+    // Nodes between the requester and the node that served the access,
+    // 0 when it did not leave the local node
+    pub hops: u8,
+    pub snoop: SnoopResult,
+    // Part of a locked instruction
+    pub locked: bool,
+    pub tlb: TlbAccess,
+    pub raw: u64,
+}
+
+// Field positions of union perf_mem_data_src
+const OP_SHIFT: u32 = 0;
+const LVL_SHIFT: u32 = 5;
+const SNOOP_SHIFT: u32 = 19;
+const LOCK_SHIFT: u32 = 24;
+const DTLB_SHIFT: u32 = 26;
+const LVL_NUM_SHIFT: u32 = 33;
+const REMOTE_SHIFT: u32 = 37;
+const SNOOPX_SHIFT: u32 = 38;
+const HOPS_SHIFT: u32 = 43;
+
+fn field(raw: u64, shift: u32, width: u32) -> u64 {
+    return (raw >> shift) & ((1 << width) - 1);
+}
+
+impl MemoryDataSource {
+    pub fn decode(raw: u64) -> MemoryDataSource {
+        let op = field(raw, OP_SHIFT, 5);
+        let lvl = field(raw, LVL_SHIFT, 14);
+        let snoop = field(raw, SNOOP_SHIFT, 5);
+        let lock = field(raw, LOCK_SHIFT, 2);
+        let dtlb = field(raw, DTLB_SHIFT, 7);
+        let lvl_num = field(raw, LVL_NUM_SHIFT, 4);
+        let remote = field(raw, REMOTE_SHIFT, 1) != 0;
+        let snoopx = field(raw, SNOOPX_SHIFT, 2);
+        let hops = field(raw, HOPS_SHIFT, 3);
+
+        let op = if op & 0x2 != 0 {
+            MemoryOp::Load
+        } else if op & 0x4 != 0 {
+            MemoryOp::Store
+        } else if op & 0x8 != 0 {
+            MemoryOp::Prefetch
+        } else if op & 0x10 != 0 {
+            MemoryOp::Execute
+        } else {
+            MemoryOp::Unknown
+        };
+
+        // Newer kernels report the level as a number, the bits of mem_lvl
+        // are kept for older tools and are the only source on older kernels
+        let mut remote = remote;
+        let level = match lvl_num {
+            0x1 => MemoryLevel::L1,
+            0x2 => MemoryLevel::L2,
+            0x3 => MemoryLevel::L3,
+            0x4 => MemoryLevel::L4,
+            0x8 => MemoryLevel::Uncached,
+            0x9 => MemoryLevel::Cxl,
+            0xa => MemoryLevel::Io,
+            0xb => MemoryLevel::AnyCache,
+            0xc => MemoryLevel::LineFillBuffer,
+            0xd => MemoryLevel::Ram,
+            0xe => MemoryLevel::PersistentMemory,
+            _ if lvl & 0x8 != 0 => MemoryLevel::L1,
+            _ if lvl & 0x10 != 0 => MemoryLevel::LineFillBuffer,
+            _ if lvl & 0x20 != 0 => MemoryLevel::L2,
+            _ if lvl & 0x40 != 0 => MemoryLevel::L3,
+            _ if lvl & 0x80 != 0 => MemoryLevel::Ram,
+            _ if lvl & 0x300 != 0 => {
+                remote = true;
+                MemoryLevel::Ram
+            }
+            _ if lvl & 0xc00 != 0 => {
+                remote = true;
+                MemoryLevel::AnyCache
+            }
+            _ if lvl & 0x1000 != 0 => MemoryLevel::Io,
+            _ if lvl & 0x2000 != 0 => MemoryLevel::Uncached,
+            _ => MemoryLevel::Unknown,
+        };
+
+        let hit = if lvl & 0x2 != 0 {
+            Some(true)
+        } else if lvl & 0x4 != 0 {
+            Some(false)
+        } else {
+            None
+        };
+
+        let snoop = if snoop & 0x10 != 0 {
+            SnoopResult::HitModified
+        } else if snoop & 0x4 != 0 {
+            SnoopResult::Hit
+        } else if snoop & 0x8 != 0 {
+            SnoopResult::Miss
+        } else if snoopx & 0x1 != 0 {
+            SnoopResult::Forwarded
+        } else if snoopx & 0x2 != 0 {
+            SnoopResult::Peer
+        } else if snoop & 0x2 != 0 {
+            SnoopResult::None
+        } else {
+            SnoopResult::Unknown
+        };
+
+        let tlb = TlbAccess {
+            hit: if dtlb & 0x2 != 0 {
+                Some(true)
+            } else if dtlb & 0x4 != 0 {
+                Some(false)
+            } else {
+                None
+            },
+            l1: dtlb & 0x8 != 0,
+            l2: dtlb & 0x10 != 0,
+            walker: dtlb & 0x20 != 0,
+            os: dtlb & 0x40 != 0,
+        };
+
+        return MemoryDataSource {
+            op,
+            level,
+            hit,
+            remote,
+            // PERF_MEM_HOPS_0 is the first remote core on the local node
+            hops: if hops == 0 { 0 } else { (hops - 1) as u8 },
+            snoop,
+            locked: lock & 0x2 != 0,
+            tlb,
+            raw,
+        };
+    }
+}
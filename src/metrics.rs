@@ -0,0 +1,310 @@
+use crate::ProcessorFamily;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    // Instructions per cycle
+    Ipc,
+    // Mispredicted branches per retired branch
+    BranchMissRate,
+    // Last level cache read misses per read access
+    CacheMissRatio,
+    UopsPerCycle,
+    // Top-down level 1, fractions of the issue slots
+    FrontendBound,
+    BadSpeculation,
+    Retiring,
+    BackendBound,
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricValue {
+    pub kind: MetricKind,
+    // NaN when a denominator of the formula was zero
+    pub value: f64,
+}
+
+// Events a metric is computed from, in perf syntax, and the formula over
+// their scaled values in the same order
+#[derive(Clone)]
+pub(crate) struct MetricDefinition {
+    pub kind: MetricKind,
+    pub events: String,
+    pub formula: fn(&[f64]) -> f64,
+}
+
+// A metric added to a Builder, bound to the counters it reads
+#[derive(Clone)]
+pub(crate) struct MetricBinding {
+    pub definition: MetricDefinition,
+    pub first_counter: usize,
+    pub num_counters: usize,
+}
+
+const ALL_METRICS: [MetricKind; 8] = [
+    MetricKind::Ipc,
+    MetricKind::BranchMissRate,
+    MetricKind::CacheMissRatio,
+    MetricKind::UopsPerCycle,
+    MetricKind::FrontendBound,
+    MetricKind::BadSpeculation,
+    MetricKind::Retiring,
+    MetricKind::BackendBound,
+];
+
+// Micro-op counters in order of preference, the first one the family has is used
+const UOPS_EVENTS: [&str; 6] = [
+    "UOPS_ISSUED.ANY",
+    "UOPS_RETIRED.SLOTS",
+    "ex_ret_ops",
+    "ex_ret_cops",
+    "RETIRED_UOPS",
+    "OP_SPEC",
+];
+
+// cycles, IDQ_UOPS_NOT_DELIVERED.CORE, UOPS_ISSUED.ANY, UOPS_RETIRED.RETIRE_SLOTS
+// and INT_MISC.RECOVERY_CYCLES, cycles goes first so it can take a fixed counter
+const TOPDOWN_EVENTS: &str = "{cycles,IDQ_UOPS_NOT_DELIVERED.CORE,UOPS_ISSUED.ANY,UOPS_RETIRED.RETIRE_SLOTS,INT_MISC.RECOVERY_CYCLES}";
+// With SMT on IDQ_UOPS_NOT_DELIVERED.CORE counts the slots of the whole core,
+// so cycles and recovery cycles are counted for both threads as well. Five
+// general-purpose events do not fit into the four counters of a thread, they
+// are multiplexed one by one instead.
+const TOPDOWN_EVENTS_SMT: &str = "CPU_CLK_UNHALTED.THREAD_P_ANY,IDQ_UOPS_NOT_DELIVERED.CORE,UOPS_ISSUED.ANY,UOPS_RETIRED.RETIRE_SLOTS,INT_MISC.RECOVERY_CYCLES_ANY";
+// Haswell through Comet Lake allocate 4 uops per cycle
+const TOPDOWN_WIDTH: f64 = 4.0;
+
+// Formulas over TOPDOWN_EVENTS and over TOPDOWN_EVENTS_SMT, where the core
+// cycles are split evenly between both threads
+const TOPDOWN_FORMULAS: [(MetricKind, fn(&[f64]) -> f64, fn(&[f64]) -> f64); 4] = [
+    (
+        MetricKind::FrontendBound,
+        |v| topdown_frontend_bound(v, 1.0),
+        |v| topdown_frontend_bound(v, 2.0),
+    ),
+    (
+        MetricKind::BadSpeculation,
+        |v| topdown_bad_speculation(v, 1.0),
+        |v| topdown_bad_speculation(v, 2.0),
+    ),
+    (
+        MetricKind::Retiring,
+        |v| topdown_retiring(v, 1.0),
+        |v| topdown_retiring(v, 2.0),
+    ),
+    (
+        MetricKind::BackendBound,
+        |v| topdown_backend_bound(v, 1.0),
+        |v| topdown_backend_bound(v, 2.0),
+    ),
+];
+
+impl MetricKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MetricKind::Ipc => "ipc",
+            MetricKind::BranchMissRate => "branch_miss_rate",
+            MetricKind::CacheMissRatio => "cache_miss_ratio",
+            MetricKind::UopsPerCycle => "uops_per_cycle",
+            MetricKind::FrontendBound => "frontend_bound",
+            MetricKind::BadSpeculation => "bad_speculation",
+            MetricKind::Retiring => "retiring",
+            MetricKind::BackendBound => "backend_bound",
+        }
+    }
+}
+
+fn ratio(numerator: f64, denominator: f64) -> f64 {
+    if denominator == 0.0 {
+        return f64::NAN;
+    }
+
+    return numerator / denominator;
+}
+
+// Cycles are counted for the given number of threads sharing the slots
+fn topdown_slots(values: &[f64], threads: f64) -> f64 {
+    return TOPDOWN_WIDTH * values[0] / threads;
+}
+
+fn topdown_frontend_bound(values: &[f64], threads: f64) -> f64 {
+    return ratio(values[1], topdown_slots(values, threads));
+}
+
+fn topdown_bad_speculation(values: &[f64], threads: f64) -> f64 {
+    let wasted = values[2] - values[3] + TOPDOWN_WIDTH * values[4] / threads;
+    return ratio(wasted.max(0.0), topdown_slots(values, threads));
+}
+
+fn topdown_retiring(values: &[f64], threads: f64) -> f64 {
+    return ratio(values[3], topdown_slots(values, threads));
+}
+
+fn topdown_backend_bound(values: &[f64], threads: f64) -> f64 {
+    let other = topdown_frontend_bound(values, threads)
+        + topdown_bad_speculation(values, threads)
+        + topdown_retiring(values, threads);
+    return (1.0 - other).max(0.0);
+}
+
+fn has_topdown_events(family: &ProcessorFamily) -> bool {
+    return matches!(
+        family,
+        ProcessorFamily::IntelHaswell
+            | ProcessorFamily::IntelBroadwell
+            | ProcessorFamily::IntelSkylake
+            | ProcessorFamily::IntelKabyLake
+            | ProcessorFamily::IntelCometLake
+    );
+}
+
+fn smt_active() -> bool {
+    if let Ok(active) = std::fs::read_to_string("/sys/devices/system/cpu/smt/active") {
+        return active.trim() == "1";
+    }
+
+    return crate::get_cpu_info().topology.threads_per_core() > 1;
+}
+
+pub(crate) fn find_definition(
+    kind: MetricKind,
+    family: &ProcessorFamily,
+) -> Option<MetricDefinition> {
+    return definition(kind, family, smt_active());
+}
+
+fn definition(kind: MetricKind, family: &ProcessorFamily, smt: bool) -> Option<MetricDefinition> {
+    let (events, formula): (String, fn(&[f64]) -> f64) = match kind {
+        MetricKind::Ipc => ("{instructions,cycles}".to_string(), |v| ratio(v[0], v[1])),
+        MetricKind::BranchMissRate => ("{branch-misses,branches}".to_string(), |v| {
+            ratio(v[0], v[1])
+        }),
+        MetricKind::CacheMissRatio => ("{LLC-load-misses,LLC-loads}".to_string(), |v| {
+            ratio(v[0], v[1])
+        }),
+        MetricKind::UopsPerCycle => {
+            let events = crate::list_events_for_family(family);
+            let uops = UOPS_EVENTS
+                .iter()
+                .find(|name| events.iter().any(|e| e.name == **name))?;
+            (format!("{{{},cycles}}", uops), |v| ratio(v[0], v[1]))
+        }
+        MetricKind::FrontendBound
+        | MetricKind::BadSpeculation
+        | MetricKind::Retiring
+        | MetricKind::BackendBound
+            if has_topdown_events(family) =>
+        {
+            let (_, formula, smt_formula) = TOPDOWN_FORMULAS.iter().find(|f| f.0 == kind)?;
+            match smt {
+                true => (TOPDOWN_EVENTS_SMT.to_string(), *smt_formula),
+                false => (TOPDOWN_EVENTS.to_string(), *formula),
+            }
+        }
+        _ => return None,
+    };
+
+    return Some(MetricDefinition {
+        kind,
+        events,
+        formula,
+    });
+}
+
+// Metrics that can be computed on the given family
+pub fn list_metrics_for_family(family: &ProcessorFamily) -> Vec<MetricKind> {
+    return ALL_METRICS
+        .iter()
+        .filter(|kind| find_definition(**kind, family).is_some())
+        .cloned()
+        .collect();
+}
+
+pub fn list_metrics() -> Vec<MetricKind> {
+    return list_metrics_for_family(&crate::get_processor_family());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOPDOWN_FAMILIES: [ProcessorFamily; 5] = [
+        ProcessorFamily::IntelHaswell,
+        ProcessorFamily::IntelBroadwell,
+        ProcessorFamily::IntelSkylake,
+        ProcessorFamily::IntelKabyLake,
+        ProcessorFamily::IntelCometLake,
+    ];
+
+    // cycles, IDQ_UOPS_NOT_DELIVERED.CORE, UOPS_ISSUED.ANY,
+    // UOPS_RETIRED.RETIRE_SLOTS and INT_MISC.RECOVERY_CYCLES of one thread
+    const THREAD: [f64; 5] = [1000.0, 800.0, 2000.0, 1800.0, 20.0];
+
+    fn value(kind: MetricKind, smt: bool, values: &[f64]) -> f64 {
+        let definition = definition(kind, &ProcessorFamily::IntelSkylake, smt).unwrap();
+        return (definition.formula)(values);
+    }
+
+    #[test]
+    fn picks_events_by_family() {
+        let ipc = definition(MetricKind::Ipc, &ProcessorFamily::AmdZen3, false).unwrap();
+        assert_eq!(ipc.events, "{instructions,cycles}");
+
+        let uops = |family| definition(MetricKind::UopsPerCycle, &family, false).unwrap();
+        assert_eq!(
+            uops(ProcessorFamily::IntelSkylake).events,
+            "{UOPS_ISSUED.ANY,cycles}"
+        );
+        assert_eq!(
+            uops(ProcessorFamily::AmdZen2).events,
+            "{ex_ret_cops,cycles}"
+        );
+        assert_eq!(uops(ProcessorFamily::AmdZen3).events, "{ex_ret_ops,cycles}");
+        assert!(definition(MetricKind::UopsPerCycle, &ProcessorFamily::Unknown, false).is_none());
+
+        for kind in TOPDOWN_FORMULAS.map(|f| f.0) {
+            assert!(definition(kind, &ProcessorFamily::AmdZen3, false).is_none());
+            assert!(definition(kind, &ProcessorFamily::IntelIceLakeClient, true).is_none());
+            for family in TOPDOWN_FAMILIES {
+                assert_eq!(
+                    definition(kind, &family, false).unwrap().events,
+                    TOPDOWN_EVENTS
+                );
+                assert_eq!(
+                    definition(kind, &family, true).unwrap().events,
+                    TOPDOWN_EVENTS_SMT
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn topdown_events_exist() {
+        for family in TOPDOWN_FAMILIES {
+            for events in [TOPDOWN_EVENTS, TOPDOWN_EVENTS_SMT] {
+                let groups = crate::parser::parse_events_for_family(events, &family).unwrap();
+                assert_eq!(groups.iter().map(|g| g.counters.len()).sum::<usize>(), 5);
+            }
+        }
+    }
+
+    #[test]
+    fn topdown_formulas() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+
+        // 4000 slots
+        assert!(close(value(MetricKind::FrontendBound, false, &THREAD), 0.2));
+        assert!(close(
+            value(MetricKind::BadSpeculation, false, &THREAD),
+            0.07
+        ));
+        assert!(close(value(MetricKind::Retiring, false, &THREAD), 0.45));
+        assert!(close(value(MetricKind::BackendBound, false, &THREAD), 0.28));
+
+        // The same thread with its sibling as busy, cycles are counted for both
+        let core = [2000.0, 800.0, 2000.0, 1800.0, 40.0];
+        for (kind, _, _) in TOPDOWN_FORMULAS {
+            assert!(close(value(kind, true, &core), value(kind, false, &THREAD)));
+        }
+
+        assert!(value(MetricKind::Retiring, false, &[0.0; 5]).is_nan());
+    }
+}
@@ -0,0 +1,651 @@
+use crate::{
+    CacheCounter, CacheCounterKind, CacheLevelKind, CacheOpKind, CounterKind, CountersGroup, Error,
+    ProcessorFamily, SamplingPrecision, StandaloneCounter,
+};
+
+// Splits on commas that are neither inside a {} group nor inside pmu/terms/
+fn split_top_level(spec: &str) -> Result<Vec<&str>, Error> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut in_terms = false;
+    let mut start = 0;
+
+    for (idx, c) in spec.char_indices() {
+        match c {
+            '{' if !in_terms => depth += 1,
+            '}' if !in_terms => {
+                if depth == 0 {
+                    return Err(Error::InvalidConfiguration(format!(
+                        "Unbalanced }} in {}",
+                        spec
+                    )));
+                }
+                depth -= 1;
+            }
+            '/' => in_terms = !in_terms,
+            ',' if depth == 0 && !in_terms => {
+                parts.push(spec[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+
+    if depth != 0 || in_terms {
+        return Err(Error::InvalidConfiguration(format!(
+            "Unterminated event {}",
+            spec
+        )));
+    }
+    parts.push(spec[start..].trim());
+
+    return Ok(parts);
+}
+
+fn apply_modifiers(counter: &mut StandaloneCounter, modifiers: &str) -> Result<(), Error> {
+    let mut user = false;
+    let mut kernel = false;
+    let mut hv = false;
+    let mut precise = 0;
+    let mut flags = counter.modifiers.clone().unwrap_or_default();
+    let mut has_flags = false;
+
+    for c in modifiers.chars() {
+        match c {
+            'u' => user = true,
+            'k' => kernel = true,
+            'h' => hv = true,
+            'p' => precise += 1,
+            'P' => precise = 3,
+            'G' => flags.exclude_host = true,
+            'H' => flags.exclude_guest = true,
+            'I' => flags.exclude_idle = true,
+            'D' => flags.pinned = true,
+            'e' => flags.exclusive = true,
+            _ => {
+                return Err(Error::InvalidConfiguration(format!(
+                    "Unknown event modifier {}",
+                    c
+                )))
+            }
+        }
+        has_flags |= matches!(c, 'G' | 'H' | 'I' | 'D' | 'e');
+    }
+
+    // Same as perf, naming any privilege level excludes the ones not named
+    if user || kernel || hv {
+        flags.exclude_user = !user;
+        flags.exclude_kernel = !kernel;
+        flags.exclude_hv = !hv;
+        has_flags = true;
+    }
+    if has_flags {
+        counter.modifiers = Some(flags);
+    }
+
+    counter.precision = match precise {
+        0 => counter.precision.clone(),
+        1 => SamplingPrecision::ConstantSkid,
+        2 => SamplingPrecision::RequestNoSkid,
+        3 => SamplingPrecision::ExactIP,
+        _ => {
+            return Err(Error::InvalidConfiguration(format!(
+                "Too many precise modifiers in {}",
+                modifiers
+            )))
+        }
+    };
+
+    return Ok(());
+}
+
+fn parse_number(value: &str) -> Result<u64, Error> {
+    let parsed = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse::<u64>(),
+    };
+
+    return parsed.map_err(|_| Error::InvalidConfiguration(format!("Invalid number {}", value)));
+}
+
+fn find_event(name: &str, family: &ProcessorFamily) -> Option<crate::SystemCounter> {
+    return crate::list_events_for_family(family)
+        .into_iter()
+        .find(|event| event.to_string() == name);
+}
+
+fn parse_pmu_terms(pmu: &str, terms: &str, family: &ProcessorFamily) -> Result<CounterKind, Error> {
+    // Table events of one core type of a hybrid CPU, e.g. "cpu_atom/INST_RETIRED.ANY_P/"
+    if let Some(event) = find_event(&format!("{}/{}/", pmu, terms), family) {
+        return Ok(CounterKind::System(event));
+    }
+
+    // Kernel advertised formats and aliases take precedence when available
+    #[cfg(target_os = "linux")]
+    if let Some(found) = crate::backends::find_pmu(pmu) {
+        let configs = found.encode(terms)?;
+        return Ok(CounterKind::System(
+            found.to_system_counter(terms, "", configs),
+        ));
+    }
+
+    return parse_core_pmu_terms(pmu, terms);
+}
+
+// Terms of the core PMU, laid out like the x86 PERFEVTSEL register
+fn parse_core_pmu_terms(pmu: &str, terms: &str) -> Result<CounterKind, Error> {
+    if pmu != "cpu" {
+        return Err(Error::EventNotSupported(format!("{}/{}/", pmu, terms)));
+    }
+
+    let mut config: u64 = 0;
+    for term in terms.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+        let (name, value) = match term.split_once('=') {
+            Some((name, value)) => (name.trim(), parse_number(value.trim())?),
+            None => (term, 1),
+        };
+
+        match name {
+            "config" => config = value,
+            // AMD extends the event select with bits 32-35
+            "event" => config |= (value & 0xff) | (((value >> 8) & 0xf) << 32),
+            "umask" => config |= (value & 0xff) << 8,
+            "edge" => config |= (value & 0x1) << 18,
+            "any" => config |= (value & 0x1) << 21,
+            "inv" => config |= (value & 0x1) << 23,
+            "cmask" => config |= (value & 0xff) << 24,
+            _ => {
+                return Err(Error::InvalidConfiguration(format!(
+                    "Unknown term {} for PMU {}",
+                    name, pmu
+                )))
+            }
+        }
+    }
+
+    return Ok(CounterKind::Raw(config));
+}
+
+fn parse_cache_name(name: &str) -> Option<CacheCounter> {
+    let lower = name.to_lowercase();
+
+    // Our own naming, as produced by CacheCounter::to_string
+    if let Some(rest) = lower.strip_prefix("cache_") {
+        let parts: Vec<&str> = rest.split('_').collect();
+        if parts.len() != 3 {
+            return None;
+        }
+        let level = match parts[0] {
+            "l1" => CacheLevelKind::L1,
+            "l1i" => CacheLevelKind::L1I,
+            "l1d" => CacheLevelKind::L1D,
+            "l2" => CacheLevelKind::L2,
+            "l3" => CacheLevelKind::L3,
+            "last" => CacheLevelKind::Last,
+            "dtlb" => CacheLevelKind::DTLB,
+            "itlb" => CacheLevelKind::ITLB,
+            "bpu" => CacheLevelKind::BPU,
+            "node" => CacheLevelKind::Node,
+            _ => return None,
+        };
+        let op = match parts[1] {
+            "read" => CacheOpKind::Read,
+            "write" => CacheOpKind::Write,
+            "prefetch" => CacheOpKind::Prefetch,
+            _ => return None,
+        };
+        let kind = match parts[2] {
+            // hit is the name accesses had before
+            "access" | "hit" => CacheCounterKind::Access,
+            "miss" => CacheCounterKind::Miss,
+            _ => return None,
+        };
+        return Some(CacheCounter { kind, level, op });
+    }
+
+    // perf naming, <cache>-<op>[-<result>], where no result means accesses
+    let levels = [
+        ("l1-dcache", CacheLevelKind::L1D),
+        ("l1-icache", CacheLevelKind::L1I),
+        ("llc", CacheLevelKind::Last),
+        ("dtlb", CacheLevelKind::DTLB),
+        ("itlb", CacheLevelKind::ITLB),
+        ("branch", CacheLevelKind::BPU),
+        ("node", CacheLevelKind::Node),
+    ];
+    let (level, rest) = levels.into_iter().find_map(|(prefix, level)| {
+        return Some((level, lower.strip_prefix(prefix)?.strip_prefix('-')?));
+    })?;
+
+    let (op, result) = match rest.split_once('-') {
+        Some((op, result)) => (op, Some(result)),
+        None => (rest, None),
+    };
+    let op = match op {
+        "load" | "loads" | "read" => CacheOpKind::Read,
+        "store" | "stores" | "write" => CacheOpKind::Write,
+        "prefetch" | "prefetches" => CacheOpKind::Prefetch,
+        _ => return None,
+    };
+    let kind = match result {
+        None | Some("refs") | Some("access") | Some("accesses") => CacheCounterKind::Access,
+        Some("miss") | Some("misses") => CacheCounterKind::Miss,
+        _ => return None,
+    };
+
+    return Some(CacheCounter { kind, level, op });
+}
+
+fn software_event(name: &str) -> Option<CounterKind> {
+    let event = match name {
+        "cpu-clock" => "cpu_clock",
+        "task-clock" => "task_clock",
+        "page-faults" | "faults" => "page_faults",
+        "context-switches" | "cs" => "context_switches",
+        "cpu-migrations" | "migrations" => "cpu_migrations",
+        "minor-faults" => "page_faults_min",
+        "major-faults" => "page_faults_maj",
+        "alignment-faults" => "alignment_faults",
+        "emulation-faults" => "emulation_faults",
+        "dummy" => "dummy",
+        _ => return None,
+    };
+
+    return crate::find_event_by_name(&format!("SW:{}", event)).map(CounterKind::System);
+}
+
+fn resolve_name(name: &str, family: &ProcessorFamily) -> Result<CounterKind, Error> {
+    match name {
+        "cycles" | "cpu-cycles" => return Ok(CounterKind::Cycles),
+        "instructions" => return Ok(CounterKind::Instructions),
+        "branches" | "branch-instructions" => return Ok(CounterKind::Branches),
+        "branch-misses" => return Ok(CounterKind::BranchMisses),
+        _ => {}
+    }
+
+    if let Some(counter) = software_event(name) {
+        return Ok(counter);
+    }
+
+    if let Some(hex) = name.strip_prefix('r') {
+        if !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(CounterKind::Raw(parse_number(&format!("0x{}", hex))?));
+        }
+    }
+
+    if let Some(cache) = parse_cache_name(name) {
+        return Ok(CounterKind::Cache(cache));
+    }
+
+    // Either an exact "HW:NAME"/"SW:name" or a bare name from the event tables
+    for event in crate::list_events_for_family(family) {
+        if event.to_string() == name || event.name.eq_ignore_ascii_case(name) {
+            return Ok(CounterKind::System(event));
+        }
+    }
+
+    return Err(Error::EventNotSupported(name.to_string()));
+}
+
+// Only a tracepoint that is not there is false, tracefs is usually only
+// readable by root
+fn tracepoint_exists(subsystem: &str, name: &str) -> Result<bool, Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(target_os = "linux")] {
+            return match crate::backends::tracepoint_id(subsystem, name) {
+                Ok(_) => Ok(true),
+                Err(Error::EventNotSupported(_)) => Ok(false),
+                Err(err) => Err(err),
+            };
+        } else {
+            return Ok(false);
+        }
+    }
+}
+
+// parse_event splits subsystem:name[:modifiers] at the first colon, rest is
+// name[:modifiers]. Returns (name, modifiers).
+fn split_tracepoint<'a>(subsystem: &str, rest: &'a str) -> Option<(&'a str, &'a str)> {
+    let (name, modifiers) = rest.split_once(':').unwrap_or((rest, ""));
+    let name = name.trim();
+    if subsystem.is_empty() || name.is_empty() {
+        return None;
+    }
+
+    return Some((name, modifiers));
+}
+
+fn tracepoint_event<'a>(
+    subsystem: &str,
+    rest: &'a str,
+) -> Result<Option<(CounterKind, &'a str)>, Error> {
+    let (name, modifiers) = match split_tracepoint(subsystem, rest) {
+        Some(split) => split,
+        None => return Ok(None),
+    };
+    if !tracepoint_exists(subsystem, name)? {
+        return Ok(None);
+    }
+
+    return Ok(Some((
+        CounterKind::Tracepoint {
+            subsystem: subsystem.to_string(),
+            name: name.to_string(),
+        },
+        modifiers,
+    )));
+}
+
+fn parse_event(spec: &str, family: &ProcessorFamily) -> Result<StandaloneCounter, Error> {
+    let (counter, modifiers) = match spec.split_once('/') {
+        Some((pmu, rest)) => {
+            let (terms, modifiers) =
+                rest.split_once('/')
+                    .ok_or(Error::InvalidConfiguration(format!(
+                        "Unterminated event {}",
+                        spec
+                    )))?;
+            let modifiers = modifiers.strip_prefix(':').unwrap_or(modifiers);
+            (parse_pmu_terms(pmu.trim(), terms, family)?, modifiers)
+        }
+        None => {
+            // The HW:/SW: prefixes of our own event names are not modifiers
+            let prefix_len = if spec.starts_with("HW:") || spec.starts_with("SW:") {
+                3
+            } else {
+                0
+            };
+            let (name, modifiers) = match spec[prefix_len..].find(':') {
+                Some(idx) => (&spec[..prefix_len + idx], &spec[prefix_len + idx + 1..]),
+                None => (spec, ""),
+            };
+            match resolve_name(name.trim(), family) {
+                Ok(counter) => (counter, modifiers),
+                // Tracepoints are written subsystem:name[:modifiers]
+                Err(err) => tracepoint_event(name.trim(), modifiers)?.ok_or(err)?,
+            }
+        }
+    };
+
+    let mut standalone = StandaloneCounter {
+        precision: SamplingPrecision::None,
+        counter,
+        modifiers: None,
+    };
+    apply_modifiers(&mut standalone, modifiers.trim())?;
+
+    return Ok(standalone);
+}
+
+fn parse_group(spec: &str, family: &ProcessorFamily) -> Result<CountersGroup, Error> {
+    let close = spec.rfind('}').ok_or(Error::InvalidConfiguration(format!(
+        "Unterminated group {}",
+        spec
+    )))?;
+    let members = &spec[1..close];
+    let modifiers = spec[close + 1..].trim();
+    let modifiers = modifiers.strip_prefix(':').unwrap_or(modifiers);
+
+    let mut group = CountersGroup::new();
+    for member in split_top_level(members)? {
+        if member.starts_with('{') {
+            return Err(Error::InvalidConfiguration(format!(
+                "Nested groups are not supported: {}",
+                spec
+            )));
+        }
+
+        let mut counter = parse_event(member, family)?;
+        // Group modifiers only fill in what the member did not set itself
+        let mut group_counter = StandaloneCounter {
+            precision: SamplingPrecision::None,
+            counter: counter.counter.clone(),
+            modifiers: None,
+        };
+        apply_modifiers(&mut group_counter, modifiers)?;
+        if counter.modifiers.is_none() {
+            counter.modifiers = group_counter.modifiers;
+        }
+        if matches!(counter.precision, SamplingPrecision::None) {
+            counter.precision = group_counter.precision;
+        }
+
+        group.counters.push(counter);
+    }
+
+    return Ok(group);
+}
+
+// Parses a list of events written the same way as for `perf stat -e`,
+// every standalone event becomes a group of its own
+pub fn parse_events(spec: &str) -> Result<Vec<CountersGroup>, Error> {
+    return parse_events_for_family(spec, &crate::get_processor_family());
+}
+
+pub(crate) fn parse_events_for_family(
+    spec: &str,
+    family: &ProcessorFamily,
+) -> Result<Vec<CountersGroup>, Error> {
+    let mut groups = vec![];
+
+    for part in split_top_level(spec)? {
+        if part.is_empty() {
+            return Err(Error::InvalidConfiguration(format!(
+                "Empty event in {}",
+                spec
+            )));
+        }
+
+        if part.starts_with('{') {
+            groups.push(parse_group(part, family)?);
+        } else {
+            groups.push(CountersGroup {
+                counters: vec![parse_event(part, family)?],
+            });
+        }
+    }
+
+    return Ok(groups);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(spec: &str) -> Result<Vec<CountersGroup>, Error> {
+        return parse_events_for_family(spec, &ProcessorFamily::IntelSkylake);
+    }
+
+    fn names(spec: &str) -> Vec<Vec<String>> {
+        return parse(spec)
+            .unwrap()
+            .iter()
+            .map(|g| g.counters.iter().map(|c| c.counter.to_string()).collect())
+            .collect();
+    }
+
+    fn single(spec: &str) -> StandaloneCounter {
+        return parse(spec).unwrap().remove(0).counters.remove(0);
+    }
+
+    #[test]
+    fn parses_names() {
+        let cases: &[(&str, &[&[&str]])] = &[
+            ("cycles", &[&["cycles"]]),
+            ("cpu-cycles,instructions", &[&["cycles"], &["instructions"]]),
+            (
+                "branch-instructions, branch-misses",
+                &[&["branches"], &["branch_misses"]],
+            ),
+            (
+                "task-clock,cs",
+                &[&["SW:task_clock"], &["SW:context_switches"]],
+            ),
+            ("r1c2", &[&["r1c2"]]),
+            ("rC0", &[&["rc0"]]),
+            ("L1-dcache-loads", &[&["cache_l1d_read_access"]]),
+            ("L1-dcache-load-misses", &[&["cache_l1d_read_miss"]]),
+            ("LLC-stores", &[&["cache_last_write_access"]]),
+            ("dTLB-prefetch-misses", &[&["cache_dTLB_prefetch_miss"]]),
+            ("branch-loads", &[&["cache_BPU_read_access"]]),
+            ("cache_l2_write_miss", &[&["cache_l2_write_miss"]]),
+            ("cache_l1d_read_hit", &[&["cache_l1d_read_access"]]),
+            ("HW:INST_RETIRED.ANY_P", &[&["HW:INST_RETIRED.ANY_P"]]),
+            ("inst_retired.any_p", &[&["HW:INST_RETIRED.ANY_P"]]),
+            ("SW:task_clock", &[&["SW:task_clock"]]),
+        ];
+
+        for (spec, expected) in cases {
+            assert_eq!(names(spec), *expected, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn parses_groups() {
+        assert_eq!(
+            names("{cycles,instructions},branches,{r1c2}"),
+            vec![
+                vec!["cycles", "instructions"],
+                vec!["branches"],
+                vec!["r1c2"]
+            ]
+        );
+        // Commas inside pmu/terms/ do not split events
+        let groups = parse("{cpu/event=0x3c,umask=0x1/,cycles}").unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].counters.len(), 2);
+    }
+
+    #[test]
+    fn splits_tracepoints() {
+        assert_eq!(
+            split_tracepoint("sched", "sched_switch"),
+            Some(("sched_switch", ""))
+        );
+        assert_eq!(
+            split_tracepoint("sched", "sched_switch:u"),
+            Some(("sched_switch", "u"))
+        );
+        assert_eq!(
+            split_tracepoint("sched", " sched_switch :k"),
+            Some(("sched_switch", "k"))
+        );
+        assert_eq!(split_tracepoint("sched", ""), None);
+        assert_eq!(split_tracepoint("sched", ":u"), None);
+        assert_eq!(split_tracepoint("", "sched_switch"), None);
+        // Neither a known event nor a tracepoint
+        assert!(parse("no_such_subsystem:no_such_event").is_err());
+    }
+
+    #[test]
+    fn parses_pmu_terms() {
+        let encoding = |spec: &str| match single(spec).counter {
+            CounterKind::Raw(config) => config,
+            // The kernel cpu PMU when it is there, its format matches PERFEVTSEL
+            CounterKind::System(counter) => counter.encoding,
+            other => panic!("{} parsed as {}", spec, other.to_string()),
+        };
+
+        assert_eq!(encoding("cpu/event=0x3c,umask=0x1/"), 0x13c);
+        assert_eq!(encoding("cpu/event=0xc0,cmask=2,inv,edge/"), 0x2_84_00c0);
+        assert_eq!(encoding("cpu/config=0x1234/"), 0x1234);
+        assert_eq!(
+            parse_core_pmu_terms("cpu", "event=0x1d4,umask=3")
+                .unwrap()
+                .to_string(),
+            "r1000003d4"
+        );
+        assert!(parse_core_pmu_terms("cpu", "bogus=1").is_err());
+        assert!(parse_core_pmu_terms("other", "event=1").is_err());
+    }
+
+    #[test]
+    fn applies_modifiers() {
+        let counter = single("cycles:u");
+        let modifiers = counter.modifiers.unwrap();
+        assert!(!modifiers.exclude_user && modifiers.exclude_kernel && modifiers.exclude_hv);
+        assert!(matches!(counter.precision, SamplingPrecision::None));
+
+        let counter = single("cycles:kpp");
+        let modifiers = counter.modifiers.unwrap();
+        assert!(modifiers.exclude_user && !modifiers.exclude_kernel);
+        assert!(matches!(
+            counter.precision,
+            SamplingPrecision::RequestNoSkid
+        ));
+
+        assert!(matches!(
+            single("cycles:P").precision,
+            SamplingPrecision::ExactIP
+        ));
+        assert!(single("cycles:D").modifiers.unwrap().pinned);
+        assert!(single("cycles:GI").modifiers.unwrap().exclude_idle);
+        assert!(single("cycles").modifiers.is_none());
+        assert!(
+            single("cpu/event=0x3c/:u")
+                .modifiers
+                .unwrap()
+                .exclude_kernel
+        );
+        // The HW:/SW: prefix is part of the name
+        assert!(single("SW:task_clock:u").modifiers.unwrap().exclude_kernel);
+    }
+
+    #[test]
+    fn modifiers_start_from_the_default() {
+        // Only naming a privilege level changes the ones counted
+        let modifiers = single("cycles:G").modifiers.unwrap();
+        assert_eq!(
+            modifiers,
+            crate::EventModifiers {
+                exclude_host: true,
+                ..Default::default()
+            }
+        );
+        assert!(modifiers.exclude_kernel && modifiers.exclude_hv && !modifiers.exclude_user);
+        assert_eq!(
+            crate::EventModifiers::default(),
+            crate::EventModifiers::user_only()
+        );
+    }
+
+    #[test]
+    fn group_modifiers_fill_in_members() {
+        let group = parse("{cycles:k,instructions:p}:up").unwrap().remove(0);
+        let cycles = &group.counters[0];
+        let instructions = &group.counters[1];
+
+        // Members keep what they set themselves
+        assert!(cycles.modifiers.as_ref().unwrap().exclude_user);
+        assert!(!instructions.modifiers.as_ref().unwrap().exclude_user);
+        assert!(instructions.modifiers.as_ref().unwrap().exclude_kernel);
+        assert!(matches!(cycles.precision, SamplingPrecision::ConstantSkid));
+        assert!(matches!(
+            instructions.precision,
+            SamplingPrecision::ConstantSkid
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_specs() {
+        let cases = [
+            "",
+            "cycles,,instructions",
+            "{cycles",
+            "cycles}",
+            "{{cycles}}",
+            "{cycles,{instructions}}",
+            "cycles:x",
+            "cycles:pppp",
+            "no-such-event",
+            "cpu/event=1",
+            "cpu/bogus=1/",
+            "cpu/event=zz/",
+            "rxyz",
+        ];
+
+        for spec in cases {
+            assert!(parse(spec).is_err(), "{}", spec);
+        }
+    }
+}
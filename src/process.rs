@@ -0,0 +1,172 @@
+use crate::Error;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus};
+use std::thread::JoinHandle;
+
+// A child spawned from a Command that blocks right before exec until it is
+// released. Everything set on the Command applies, including its stdio.
+pub(crate) struct LaunchedProcess {
+    pid: i32,
+    release_fd: Option<OwnedFd>,
+    // Command::spawn only returns once the child has called exec, so it runs
+    // on its own thread until the child is released
+    spawner: Option<JoinHandle<std::io::Result<Child>>>,
+    child: Option<Child>,
+}
+
+fn pipe() -> Result<(OwnedFd, OwnedFd), Error> {
+    let mut fds = [0; 2];
+    cfg_if::cfg_if! {
+        if #[cfg(target_os = "linux")] {
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+                return Err(Error::last_os_error());
+            }
+        } else {
+            if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+                return Err(Error::last_os_error());
+            }
+            for fd in fds {
+                unsafe {
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                }
+            }
+        }
+    }
+
+    return unsafe { Ok((OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1]))) };
+}
+
+fn retry_eintr(mut f: impl FnMut() -> isize) -> isize {
+    loop {
+        let res = f();
+        if res >= 0 || std::io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            return res;
+        }
+    }
+}
+
+// Command::spawn returns once the child has called exec or failed to
+fn join_spawner(spawner: JoinHandle<std::io::Result<Child>>) -> Result<Child, Error> {
+    return match spawner.join() {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(Error::InvalidConfiguration(
+            "Failed to launch the command".to_string(),
+        )),
+    };
+}
+
+impl LaunchedProcess {
+    pub fn spawn(mut command: Command) -> Result<LaunchedProcess, Error> {
+        let (release_read, release_write) = pipe()?;
+        let (pid_read, pid_write) = pipe()?;
+
+        let release_write_fd = release_write.as_raw_fd();
+        unsafe {
+            // Runs in the child after fork, only async-signal-safe calls are allowed
+            command.pre_exec(move || {
+                // Otherwise the child would keep its own release pipe open
+                libc::close(release_write_fd);
+
+                let pid = libc::getpid();
+                let res = retry_eintr(|| {
+                    libc::write(
+                        pid_write.as_raw_fd(),
+                        &pid as *const i32 as *const libc::c_void,
+                        4,
+                    )
+                });
+                if res != 4 {
+                    return Err(std::io::Error::last_os_error());
+                }
+
+                let mut byte: u8 = 0;
+                let res = retry_eintr(|| {
+                    libc::read(
+                        release_read.as_raw_fd(),
+                        &mut byte as *mut u8 as *mut libc::c_void,
+                        1,
+                    )
+                });
+                return match res {
+                    1 => Ok(()),
+                    // The parent went away without starting the counters
+                    0 => Err(std::io::Error::from_raw_os_error(libc::ECANCELED)),
+                    _ => Err(std::io::Error::last_os_error()),
+                };
+            });
+        }
+
+        // The pipe ends held by the command are closed once spawn returns
+        let spawner = std::thread::spawn(move || command.spawn());
+
+        let mut pid: i32 = 0;
+        let res = retry_eintr(|| unsafe {
+            libc::read(
+                pid_read.as_raw_fd(),
+                &mut pid as *mut i32 as *mut libc::c_void,
+                4,
+            )
+        });
+        if res != 4 {
+            // A forked child fails its exec once the release pipe is closed,
+            // spawn then reaps it
+            drop(release_write);
+            join_spawner(spawner)?;
+            return Err(Error::InvalidConfiguration(
+                "Failed to launch the command".to_string(),
+            ));
+        }
+
+        return Ok(LaunchedProcess {
+            pid,
+            release_fd: Some(release_write),
+            spawner: Some(spawner),
+            child: None,
+        });
+    }
+
+    pub fn pid(&self) -> i32 {
+        return self.pid;
+    }
+
+    // Lets the child exec, fails if the exec itself failed
+    pub fn release(&mut self) -> Result<(), Error> {
+        if let Some(fd) = self.release_fd.take() {
+            let byte: u8 = 1;
+            let res = unsafe {
+                libc::write(fd.as_raw_fd(), &byte as *const u8 as *const libc::c_void, 1)
+            };
+            if res != 1 {
+                return Err(Error::last_os_error());
+            }
+        }
+        if let Some(spawner) = self.spawner.take() {
+            self.child = Some(join_spawner(spawner)?);
+        }
+
+        return Ok(());
+    }
+
+    pub fn wait(&mut self) -> Result<ExitStatus, Error> {
+        self.release()?;
+
+        return match &mut self.child {
+            Some(child) => Ok(child.wait()?),
+            None => Err(Error::InvalidConfiguration(
+                "The launched command failed to exec".to_string(),
+            )),
+        };
+    }
+}
+
+impl Drop for LaunchedProcess {
+    fn drop(&mut self) {
+        // A child that was never released fails its exec as soon as the pipe
+        // is closed, and is reaped by spawn
+        self.release_fd.take();
+        if let Some(spawner) = self.spawner.take() {
+            let _ = spawner.join();
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use crate::{CounterValue, MemoryDataSource};
+
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub ip: u64,
+    pub pid: u32,
+    pub tid: u32,
+    pub time: u64,
+    pub cpu: u32,
+    pub period: u64,
+    // Values of every counter in the sampled group at the time of the sample
+    pub values: Vec<CounterValue>,
+    // Only filled in when memory samples are enabled in the Builder,
+    // weight is usually the access latency in core cycles
+    pub addr: u64,
+    pub phys_addr: u64,
+    pub weight: u64,
+    pub data_src: Option<MemoryDataSource>,
+    // The sampled ip followed by the return addresses of its callers, empty
+    // unless call chains are enabled in the Builder
+    pub callchain: Vec<u64>,
+    // The first kernel_frames entries of callchain are kernel addresses
+    pub kernel_frames: usize,
+    // Most recent branch first, empty unless branch stacks are enabled in the Builder
+    pub branches: Vec<BranchEntry>,
+}
+
+// A taken branch recorded by the LBR on x86 or BRBE on arm64
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BranchEntry {
+    pub from: u64,
+    pub to: u64,
+    // Neither is set when the hardware does not report predictions
+    pub mispredicted: bool,
+    pub predicted: bool,
+    // Taken inside a hardware transaction
+    pub in_tx: bool,
+    // Core cycles since the previous entry, 0 when not reported
+    pub cycles: u16,
+}
+
+// Branches recorded into the branch stack of every sample. At least one
+// branch type has to be set, any includes all of the others.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BranchFilter {
+    pub any: bool,
+    pub call: bool,
+    pub ret: bool,
+    pub cond: bool,
+    pub ind_jmp: bool,
+    // Privilege levels of the recorded branches, the ones of the sampled
+    // event apply when neither is set
+    pub user: bool,
+    pub kernel: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallChainMode {
+    // Walks frame pointers, user code has to keep them, e.g. with
+    // -C force-frame-pointers=yes or -fno-omit-frame-pointer
+    FramePointer,
+    // Copies stack_size bytes of the user stack with every sample and unwinds
+    // them with the .eh_frame of the sampled binaries, x86_64 and aarch64 only
+    Dwarf { stack_size: u32 },
+    // User call stacks recorded by the LBR, Intel Haswell and newer
+    Lbr,
+}
+
+// Function an address belongs to, as resolved by Counters::symbolize
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    // Demangled unless the demangle feature is disabled
+    pub name: String,
+    // Distance of the address from the start of the function
+    pub offset: u64,
+    // Path of the binary or shared library the function is in
+    pub module: String,
+    // Need the line-info feature and debug info for the module
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SamplingConfig {
+    pub period: u64,
+    // Data address, weight and data source of every sample
+    pub memory: bool,
+    pub physical_addresses: bool,
+    pub callchain: Option<CallChainMode>,
+    // Track mmap, comm and task records for the symbolizer
+    pub symbolize: bool,
+    pub branch_stack: Option<BranchFilter>,
+}
+
+impl BranchFilter {
+    pub fn any() -> BranchFilter {
+        return BranchFilter {
+            any: true,
+            ..Default::default()
+        };
+    }
+
+    pub fn calls_and_returns() -> BranchFilter {
+        return BranchFilter {
+            call: true,
+            ret: true,
+            ..Default::default()
+        };
+    }
+
+    pub fn user_only(mut self) -> BranchFilter {
+        self.user = true;
+        self.kernel = false;
+        return self;
+    }
+
+    pub fn kernel_only(mut self) -> BranchFilter {
+        self.user = false;
+        self.kernel = true;
+        return self;
+    }
+
+    pub(crate) fn has_branch_types(&self) -> bool {
+        return self.any || self.call || self.ret || self.cond || self.ind_jmp;
+    }
+}
+
+// Same format as perf report, e.g. main+0x1f (src/main.rs:12)
+impl ToString for Symbol {
+    fn to_string(&self) -> String {
+        let location = match (&self.file, self.line) {
+            (Some(file), Some(line)) => format!(" ({}:{})", file, line),
+            (Some(file), None) => format!(" ({})", file),
+            _ => String::new(),
+        };
+
+        return format!("{}+0x{:x}{}", self.name, self.offset, location);
+    }
+}
+
+pub struct SamplesIterator<'a> {
+    pub(crate) backend_counters: &'a mut Box<dyn crate::backends::BackendCounters>,
+}
+
+impl Iterator for SamplesIterator<'_> {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        return self.backend_counters.next_sample();
+    }
+}
@@ -0,0 +1,295 @@
+use crate::maps::{file_address, find_map, load_segments, read_maps, MemoryMap};
+use crate::Symbol;
+use object::{Object, ObjectSymbol, SymbolKind};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+// A function symbol of an ELF file, name is still mangled
+struct FunctionSymbol {
+    address: u64,
+    // 0 when the symbol table does not know, the function then extends
+    // up to the next symbol
+    size: u64,
+    name: String,
+}
+
+// The parts of an ELF file needed to symbolize addresses in it
+struct Module {
+    // Sorted by address
+    symbols: Vec<FunctionSymbol>,
+    // (file offset, file size, virtual address) of the loadable segments
+    segments: Vec<(u64, u64, u64)>,
+    #[cfg(feature = "line-info")]
+    lines: Option<addr2line::Context<gimli::EndianRcSlice<gimli::RunTimeEndian>>>,
+}
+
+// What is known about a process from /proc and the side-band records
+struct Process {
+    comm: String,
+    maps: Vec<MemoryMap>,
+    // The maps are kept after exit, samples of the process may still be pending
+    exited: bool,
+    // Pages found in no mapping even after re-reading /proc, forgotten on the
+    // next change to the maps. Mappings are page aligned.
+    unmapped: HashSet<u64>,
+}
+
+const PAGE_SIZE: u64 = 4096;
+
+// Resolves sampled addresses to functions in the binaries the processes mapped.
+// Counters keep one up to date with the mmap, comm, fork and exit records of
+// the sampled processes, a standalone one only knows what /proc tells it.
+pub struct Symbolizer {
+    processes: HashMap<u32, Process>,
+    modules: HashMap<String, Option<Rc<Module>>>,
+}
+
+pub(crate) fn demangle(name: &str) -> String {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "demangle")] {
+            if let Ok(demangled) = rustc_demangle::try_demangle(name) {
+                // The alternate format drops the hash suffix
+                return format!("{:#}", demangled);
+            }
+            if name.starts_with("_Z") {
+                let demangled = cpp_demangle::Symbol::new(name)
+                    .ok()
+                    .and_then(|symbol| symbol.demangle(&Default::default()).ok());
+                if let Some(demangled) = demangled {
+                    return demangled;
+                }
+            }
+        }
+    }
+
+    return name.to_string();
+}
+
+// Debug info split off the binary, found by build id or .gnu_debuglink the way gdb does
+fn read_debug_file(path: &str, file: &object::File) -> Option<Vec<u8>> {
+    let mut candidates = vec![];
+
+    if let Ok(Some(build_id)) = file.build_id() {
+        if build_id.len() > 1 {
+            let hex: String = build_id.iter().map(|b| format!("{:02x}", b)).collect();
+            candidates.push(format!(
+                "/usr/lib/debug/.build-id/{}/{}.debug",
+                &hex[..2],
+                &hex[2..]
+            ));
+        }
+    }
+    if let Ok(Some((name, _))) = file.gnu_debuglink() {
+        let name = String::from_utf8_lossy(name);
+        let dir = std::path::Path::new(path)
+            .parent()
+            .map(|dir| dir.to_string_lossy().to_string())
+            .unwrap_or_default();
+        candidates.push(format!("{}/{}", dir, name));
+        candidates.push(format!("{}/.debug/{}", dir, name));
+        candidates.push(format!("/usr/lib/debug{}/{}", dir, name));
+    }
+
+    return candidates
+        .iter()
+        .filter(|candidate| candidate.as_str() != path)
+        .find_map(|candidate| std::fs::read(candidate).ok());
+}
+
+fn function_symbols(file: &object::File) -> Vec<FunctionSymbol> {
+    return file
+        .symbols()
+        .chain(file.dynamic_symbols())
+        .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.address() != 0)
+        .filter_map(|symbol| {
+            Some(FunctionSymbol {
+                address: symbol.address(),
+                size: symbol.size(),
+                name: symbol.name().ok()?.to_string(),
+            })
+        })
+        .collect();
+}
+
+impl Module {
+    fn load(path: &str) -> Option<Module> {
+        let data = std::fs::read(path).ok()?;
+        let file = object::File::parse(&*data).ok()?;
+        let debug_data = read_debug_file(path, &file);
+        let debug_file = debug_data
+            .as_ref()
+            .and_then(|data| object::File::parse(&**data).ok());
+
+        let mut symbols = function_symbols(&file);
+        if let Some(debug_file) = &debug_file {
+            symbols.extend(function_symbols(debug_file));
+        }
+        // Prefer the sized entry when .symtab and .dynsym both list a function
+        symbols.sort_by(|a, b| a.address.cmp(&b.address).then(b.size.cmp(&a.size)));
+        symbols.dedup_by_key(|symbol| symbol.address);
+
+        return Some(Module {
+            symbols,
+            segments: load_segments(&file),
+            #[cfg(feature = "line-info")]
+            lines: addr2line::Context::new(debug_file.as_ref().unwrap_or(&file)).ok(),
+        });
+    }
+
+    fn find_symbol(&self, address: u64) -> Option<&FunctionSymbol> {
+        let index = self
+            .symbols
+            .partition_point(|symbol| symbol.address <= address);
+        let symbol = &self.symbols[index.checked_sub(1)?];
+        if symbol.size != 0 && address >= symbol.address + symbol.size {
+            return None;
+        }
+
+        return Some(symbol);
+    }
+
+    fn find_location(&self, address: u64) -> (Option<String>, Option<u32>) {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "line-info")] {
+                if let Some(Ok(Some(location))) = self.lines.as_ref().map(|lines| lines.find_location(address)) {
+                    return (location.file.map(|file| file.to_string()), location.line);
+                }
+            } else {
+                let _ = address;
+            }
+        }
+
+        return (None, None);
+    }
+}
+
+impl Process {
+    fn read(pid: u32) -> Process {
+        let comm = std::fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default();
+
+        return Process {
+            comm: comm.trim_end().to_string(),
+            maps: read_maps(pid),
+            exited: false,
+            unmapped: HashSet::new(),
+        };
+    }
+}
+
+impl Symbolizer {
+    pub fn new() -> Symbolizer {
+        return Symbolizer {
+            processes: HashMap::new(),
+            modules: HashMap::new(),
+        };
+    }
+
+    // Takes the current mappings of a running process from /proc/<pid>/maps
+    pub fn add_process(&mut self, pid: u32) {
+        self.processes.insert(pid, Process::read(pid));
+    }
+
+    // Command name of the process, as of its last exec
+    pub fn comm(&self, pid: u32) -> Option<&str> {
+        return self
+            .processes
+            .get(&pid)
+            .map(|process| process.comm.as_str());
+    }
+
+    fn find_map(&mut self, pid: u32, addr: u64) -> Option<MemoryMap> {
+        let process = self
+            .processes
+            .entry(pid)
+            .or_insert_with(|| Process::read(pid));
+        if let Some(map) = find_map(&process.maps, addr) {
+            return Some(map.clone());
+        }
+        if process.exited || process.unmapped.contains(&(addr / PAGE_SIZE)) {
+            return None;
+        }
+
+        // A mapping created before tracking started, or by a process never seen before
+        let maps = read_maps(pid);
+        if !maps.is_empty() {
+            process.maps = maps;
+        }
+        let map = find_map(&process.maps, addr).cloned();
+        if map.is_none() {
+            process.unmapped.insert(addr / PAGE_SIZE);
+        }
+
+        return map;
+    }
+
+    fn module(&mut self, path: &str) -> Option<Rc<Module>> {
+        return self
+            .modules
+            .entry(path.to_string())
+            .or_insert_with(|| Module::load(path).map(Rc::new))
+            .clone();
+    }
+
+    // None for addresses outside of mapped files or in functions without symbols
+    pub fn symbolize(&mut self, pid: u32, ip: u64) -> Option<Symbol> {
+        let map = self.find_map(pid, ip)?;
+        let module = self.module(&map.path)?;
+        let address = file_address(&module.segments, map.file_offset(ip))?;
+        let symbol = module.find_symbol(address)?;
+        let (file, line) = module.find_location(address);
+
+        return Some(Symbol {
+            name: demangle(&symbol.name),
+            offset: address - symbol.address,
+            module: map.path.clone(),
+            file,
+            line,
+        });
+    }
+
+    pub(crate) fn mmap(&mut self, pid: u32, map: MemoryMap) {
+        let process = self
+            .processes
+            .entry(pid)
+            .or_insert_with(|| Process::read(pid));
+        // The new mapping replaces whatever it overlaps
+        process
+            .maps
+            .retain(|other| other.end <= map.start || other.start >= map.end);
+        process.maps.push(map);
+        process.unmapped.clear();
+    }
+
+    pub(crate) fn set_comm(&mut self, pid: u32, comm: &str, exec: bool) {
+        let process = self
+            .processes
+            .entry(pid)
+            .or_insert_with(|| Process::read(pid));
+        process.comm = comm.to_string();
+        // The mappings of the new image follow as mmap records
+        if exec {
+            process.maps.clear();
+            process.unmapped.clear();
+        }
+    }
+
+    pub(crate) fn fork(&mut self, pid: u32, parent: u32) {
+        let parent = self
+            .processes
+            .entry(parent)
+            .or_insert_with(|| Process::read(parent));
+        let child = Process {
+            comm: parent.comm.clone(),
+            maps: parent.maps.clone(),
+            exited: false,
+            unmapped: HashSet::new(),
+        };
+        self.processes.insert(pid, child);
+    }
+
+    pub(crate) fn exit(&mut self, pid: u32) {
+        if let Some(process) = self.processes.get_mut(&pid) {
+            process.exited = true;
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{CounterKind, ProcessorFamily, SystemCounterKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TopDownLevel {
+    Level1,
+    Level2,
+}
+
+// Level 2 splits every level 1 category in two, as fractions of all issue slots
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopDownLevel2 {
+    pub heavy_operations: f64,
+    pub light_operations: f64,
+    pub branch_mispredicts: f64,
+    pub machine_clears: f64,
+    pub fetch_latency: f64,
+    pub fetch_bandwidth: f64,
+    pub memory_bound: f64,
+    pub core_bound: f64,
+}
+
+// Fractions of the issue slots, the level 1 categories add up to 1.
+// All of them are NaN when no slots were counted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopDown {
+    pub retiring: f64,
+    pub bad_speculation: f64,
+    pub frontend_bound: f64,
+    pub backend_bound: f64,
+    // None unless level 2 was requested
+    pub level2: Option<TopDownLevel2>,
+}
+
+// A top-down group added to a Builder, bound to the counters it reads
+#[derive(Debug, Clone)]
+pub(crate) struct TopDownBinding {
+    pub level: TopDownLevel,
+    pub first_counter: usize,
+}
+
+// Kernel event names in the order the group has to be opened in,
+// slots leads and the metrics follow
+const LEVEL1_EVENTS: [&str; 5] = [
+    "slots",
+    "topdown-retiring",
+    "topdown-bad-spec",
+    "topdown-fe-bound",
+    "topdown-be-bound",
+];
+const LEVEL2_EVENTS: [&str; 4] = [
+    "topdown-heavy-ops",
+    "topdown-br-mispredict",
+    "topdown-fetch-lat",
+    "topdown-mem-bound",
+];
+
+impl TopDownLevel {
+    pub(crate) fn event_names(&self) -> Vec<&'static str> {
+        let mut names = LEVEL1_EVENTS.to_vec();
+        if *self == TopDownLevel::Level2 {
+            names.extend(LEVEL2_EVENTS);
+        }
+
+        return names;
+    }
+}
+
+// Deepest level the PERF_METRICS MSR of the family reports, None when the
+// family has no PERF_METRICS at all
+pub fn max_topdown_level(family: &ProcessorFamily) -> Option<TopDownLevel> {
+    match family {
+        ProcessorFamily::IntelIceLakeClient
+        | ProcessorFamily::IntelIceLakeServer
+        | ProcessorFamily::IntelTigerLake
+        | ProcessorFamily::IntelRocketLake => Some(TopDownLevel::Level1),
+        // Only the P-cores, the E-cores have no slots
+        ProcessorFamily::IntelAlderLake | ProcessorFamily::IntelRaptorLake => {
+            Some(TopDownLevel::Level2)
+        }
+        _ => None,
+    }
+}
+
+// Name of slots or a topdown-* metric event of a core PMU
+fn topdown_event_name(counter: &CounterKind) -> Option<&str> {
+    match counter {
+        CounterKind::System(counter) => match &counter.kind {
+            SystemCounterKind::Pmu { pmu, .. } if pmu.starts_with("cpu") => {
+                let name: &str = &counter.name;
+                if name == "slots" || name.starts_with("topdown-") {
+                    return Some(name);
+                }
+                None
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// slots counts on a fixed counter and the metrics are read from PERF_METRICS,
+// none of them takes a general-purpose counter
+pub(crate) fn is_topdown_counter(counter: &CounterKind) -> bool {
+    return topdown_event_name(counter).is_some();
+}
+
+pub(crate) fn is_topdown_metric(counter: &CounterKind) -> bool {
+    return topdown_event_name(counter).map_or(false, |name| name != "slots");
+}
+
+impl TopDown {
+    // Computes the breakdown from the raw values of the group in the order it
+    // is opened in: slots, the four level 1 metrics and, for level 2, the
+    // heavy-ops, br-mispredict, fetch-lat and mem-bound metrics. None unless
+    // there is exactly one value per event of the level.
+    pub fn from_values(level: TopDownLevel, values: &[u64]) -> Option<TopDown> {
+        if values.len() != level.event_names().len() {
+            return None;
+        }
+
+        let values: Vec<f64> = values.iter().map(|v| *v as f64).collect();
+        // The kernel reports every metric in slots, their sum is used instead of
+        // slots itself so rounding of the 8-bit fractions does not leak into the result
+        let total: f64 = values[1..5].iter().sum();
+        let fraction = |value: f64| -> f64 {
+            if total == 0.0 {
+                return f64::NAN;
+            }
+            return value / total;
+        };
+
+        let retiring = fraction(values[1]);
+        let bad_speculation = fraction(values[2]);
+        let frontend_bound = fraction(values[3]);
+        let backend_bound = fraction(values[4]);
+
+        let level2 = match level {
+            TopDownLevel::Level1 => None,
+            TopDownLevel::Level2 => {
+                let heavy_operations = fraction(values[5]).min(retiring);
+                let branch_mispredicts = fraction(values[6]).min(bad_speculation);
+                let fetch_latency = fraction(values[7]).min(frontend_bound);
+                let memory_bound = fraction(values[8]).min(backend_bound);
+
+                Some(TopDownLevel2 {
+                    heavy_operations,
+                    light_operations: retiring - heavy_operations,
+                    branch_mispredicts,
+                    machine_clears: bad_speculation - branch_mispredicts,
+                    fetch_latency,
+                    fetch_bandwidth: frontend_bound - fetch_latency,
+                    memory_bound,
+                    core_bound: backend_bound - memory_bound,
+                })
+            }
+        };
+
+        return Some(TopDown {
+            retiring,
+            bad_speculation,
+            frontend_bound,
+            backend_bound,
+            level2,
+        });
+    }
+}
@@ -0,0 +1,403 @@
+use crate::maps::{file_address, find_map, load_segments, read_maps, MemoryMap};
+use gimli::{
+    BaseAddresses, CfaRule, EhFrame, EhFrameHdr, NativeEndian, Register, RegisterRule,
+    UnwindContext, UnwindSection,
+};
+use object::{Object, ObjectSection};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "x86_64")] {
+        // DWARF register numbers of rbx, rbp, rsp and r12-r15 and their
+        // perf_event_x86_regs indices
+        const SAMPLED_REGISTERS: &[(u16, u32)] =
+            &[(3, 1), (6, 6), (7, 7), (12, 20), (13, 21), (14, 22), (15, 23)];
+        const PERF_REG_IP: u32 = 8;
+        const SP_REGISTER: u16 = 7;
+        const RA_REGISTER: u16 = 16;
+        pub(crate) const DWARF_UNWINDING: bool = true;
+    } else if #[cfg(target_arch = "aarch64")] {
+        // x19-x30 and sp, DWARF and perf_event_arm64_regs share the numbering
+        const SAMPLED_REGISTERS: &[(u16, u32)] = &[
+            (19, 19), (20, 20), (21, 21), (22, 22), (23, 23), (24, 24), (25, 25),
+            (26, 26), (27, 27), (28, 28), (29, 29), (30, 30), (31, 31),
+        ];
+        const PERF_REG_IP: u32 = 32;
+        const SP_REGISTER: u16 = 31;
+        const RA_REGISTER: u16 = 30;
+        pub(crate) const DWARF_UNWINDING: bool = true;
+    } else {
+        const SAMPLED_REGISTERS: &[(u16, u32)] = &[];
+        const PERF_REG_IP: u32 = 0;
+        const SP_REGISTER: u16 = 0;
+        const RA_REGISTER: u16 = 0;
+        pub(crate) const DWARF_UNWINDING: bool = false;
+    }
+}
+
+const MAX_REGISTERS: usize = 33;
+const MAX_FRAMES: usize = 256;
+
+// Copy of the user registers and the top of the user stack taken with a sample
+pub(crate) struct UserStack {
+    // Values in the order of the bits set in sample_regs_user
+    pub regs: Vec<u64>,
+    pub data: Vec<u8>,
+}
+
+// The parts of an ELF file needed to unwind through it
+struct Module {
+    eh_frame: Vec<u8>,
+    eh_frame_address: u64,
+    eh_frame_hdr: Vec<u8>,
+    eh_frame_hdr_address: u64,
+    text_address: u64,
+    // (file offset, file size, virtual address) of the loadable segments
+    segments: Vec<(u64, u64, u64)>,
+}
+
+#[derive(Clone)]
+struct Registers {
+    ip: u64,
+    values: [Option<u64>; MAX_REGISTERS],
+}
+
+impl Registers {
+    // None for registers the unwinder does not track, whatever the CFI asks for
+    fn get(&self, register: u16) -> Option<u64> {
+        return *self.values.get(register as usize)?;
+    }
+}
+
+// Unwinds sampled user stacks with the .eh_frame of the mapped binaries
+pub(crate) struct Unwinder {
+    maps: HashMap<u32, Vec<MemoryMap>>,
+    modules: HashMap<String, Option<Rc<Module>>>,
+}
+
+// Value of sample_regs_user for the registers the unwinder needs
+pub(crate) fn sampled_registers_mask() -> u64 {
+    let mut mask = 1u64 << PERF_REG_IP;
+    for (_, perf) in SAMPLED_REGISTERS {
+        mask |= 1 << perf;
+    }
+
+    return mask;
+}
+
+impl Module {
+    fn load(path: &str) -> Option<Module> {
+        let data = std::fs::read(path).ok()?;
+        let file = object::File::parse(&*data).ok()?;
+
+        let section = |name: &str| -> (Vec<u8>, u64) {
+            return match file.section_by_name(name) {
+                Some(section) => (
+                    section.data().map(|d| d.to_vec()).unwrap_or_default(),
+                    section.address(),
+                ),
+                None => (vec![], 0),
+            };
+        };
+        let (eh_frame, eh_frame_address) = section(".eh_frame");
+        let (eh_frame_hdr, eh_frame_hdr_address) = section(".eh_frame_hdr");
+        if eh_frame.is_empty() {
+            return None;
+        }
+
+        return Some(Module {
+            eh_frame,
+            eh_frame_address,
+            eh_frame_hdr,
+            eh_frame_hdr_address,
+            text_address: section(".text").1,
+            segments: load_segments(&file),
+        });
+    }
+
+    // Registers of the caller, None when there is no unwind info for the address
+    fn step(&self, address: u64, regs: &Registers, stack: &Stack) -> Option<Registers> {
+        let bases = BaseAddresses::default()
+            .set_eh_frame_hdr(self.eh_frame_hdr_address)
+            .set_eh_frame(self.eh_frame_address)
+            .set_text(self.text_address);
+        let eh_frame = EhFrame::new(&self.eh_frame, NativeEndian);
+        let mut ctx = UnwindContext::new();
+
+        let table = EhFrameHdr::new(&self.eh_frame_hdr, NativeEndian)
+            .parse(&bases, 8)
+            .ok();
+        let row = match table.as_ref().and_then(|hdr| hdr.table()) {
+            Some(table) => table.unwind_info_for_address(
+                &eh_frame,
+                &bases,
+                &mut ctx,
+                address,
+                EhFrame::cie_from_offset,
+            ),
+            None => eh_frame.unwind_info_for_address(
+                &bases,
+                &mut ctx,
+                address,
+                EhFrame::cie_from_offset,
+            ),
+        }
+        .ok()?;
+
+        let cfa = match row.cfa() {
+            CfaRule::RegisterAndOffset { register, offset } => {
+                regs.get(register.0)?.wrapping_add(*offset as u64)
+            }
+            CfaRule::Expression(_) => return None,
+        };
+
+        let mut caller = Registers {
+            ip: 0,
+            values: [None; MAX_REGISTERS],
+        };
+        let tracked = SAMPLED_REGISTERS
+            .iter()
+            .map(|(dwarf, _)| *dwarf)
+            .chain([RA_REGISTER]);
+        for register in tracked {
+            let value = match row.register(Register(register)) {
+                RegisterRule::Undefined => None,
+                RegisterRule::SameValue => regs.get(register),
+                RegisterRule::Offset(offset) => stack.read(cfa.wrapping_add(offset as u64)),
+                RegisterRule::ValOffset(offset) => Some(cfa.wrapping_add(offset as u64)),
+                RegisterRule::Register(other) => regs.get(other.0),
+                _ => None,
+            };
+            caller.values[register as usize] = value;
+        }
+        // The stack pointer of the caller is the CFA by definition
+        caller.values[SP_REGISTER as usize] = Some(cfa);
+        caller.ip = caller.get(RA_REGISTER)?;
+
+        return Some(caller);
+    }
+}
+
+struct Stack<'a> {
+    base: u64,
+    data: &'a [u8],
+}
+
+impl Stack<'_> {
+    fn read(&self, addr: u64) -> Option<u64> {
+        let offset = usize::try_from(addr.checked_sub(self.base)?).ok()?;
+        let bytes = self.data.get(offset..offset.checked_add(8)?)?;
+        return Some(u64::from_ne_bytes(bytes.try_into().unwrap()));
+    }
+}
+
+impl Unwinder {
+    pub fn new() -> Unwinder {
+        return Unwinder {
+            maps: HashMap::new(),
+            modules: HashMap::new(),
+        };
+    }
+
+    fn find_map(&mut self, pid: u32, addr: u64) -> Option<MemoryMap> {
+        let cached = self.maps.get(&pid).and_then(|maps| find_map(maps, addr));
+        if let Some(map) = cached {
+            return Some(map.clone());
+        }
+
+        // The process may have mapped more files since the last lookup
+        let maps = read_maps(pid);
+        let map = find_map(&maps, addr).cloned();
+        self.maps.insert(pid, maps);
+
+        return map;
+    }
+
+    fn module(&mut self, path: &str) -> Option<Rc<Module>> {
+        return self
+            .modules
+            .entry(path.to_string())
+            .or_insert_with(|| Module::load(path).map(Rc::new))
+            .clone();
+    }
+
+    // The sampled user ip followed by the return addresses found on the stack
+    pub fn unwind(&mut self, pid: u32, user_stack: &UserStack) -> Vec<u64> {
+        let mut perf_registers: Vec<u32> =
+            SAMPLED_REGISTERS.iter().map(|(_, perf)| *perf).collect();
+        perf_registers.push(PERF_REG_IP);
+        perf_registers.sort();
+        if user_stack.regs.len() != perf_registers.len() {
+            return vec![];
+        }
+
+        let mut regs = Registers {
+            ip: 0,
+            values: [None; MAX_REGISTERS],
+        };
+        for (perf, value) in perf_registers.iter().zip(&user_stack.regs) {
+            if *perf == PERF_REG_IP {
+                regs.ip = *value;
+            } else if let Some((dwarf, _)) = SAMPLED_REGISTERS.iter().find(|(_, p)| p == perf) {
+                regs.values[*dwarf as usize] = Some(*value);
+            }
+        }
+
+        let stack = match regs.get(SP_REGISTER) {
+            Some(sp) => Stack {
+                base: sp,
+                data: &user_stack.data,
+            },
+            None => return vec![],
+        };
+
+        let mut frames = vec![regs.ip];
+        while frames.len() < MAX_FRAMES {
+            // Return addresses point after the call, look up the call itself
+            let address = if frames.len() == 1 {
+                regs.ip
+            } else {
+                regs.ip - 1
+            };
+            let map = match self.find_map(pid, address) {
+                Some(map) => map,
+                None => break,
+            };
+            let module = match self.module(&map.path) {
+                Some(module) => module,
+                None => break,
+            };
+            let file_address = match file_address(&module.segments, map.file_offset(address)) {
+                Some(file_address) => file_address,
+                None => break,
+            };
+
+            let caller = match module.step(file_address, &regs, &stack) {
+                Some(caller) => caller,
+                None => break,
+            };
+            // The stack grows down, a caller frame below the current one means garbage
+            if caller.ip == 0 || caller.get(SP_REGISTER) <= regs.get(SP_REGISTER) {
+                break;
+            }
+
+            frames.push(caller.ip);
+            regs = caller;
+        }
+
+        return frames;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // .eh_frame with one CIE for the state at function entry, CFA = rsp + 8
+    // and the return address at CFA - 8, and one FDE per (start, end, instructions)
+    #[cfg(target_arch = "x86_64")]
+    fn eh_frame(fdes: &[(u64, u64, &[u8])]) -> Vec<u8> {
+        let mut cie = vec![0, 0, 0, 0, 1, 0, 0x01, 0x78, 0x10];
+        cie.extend([0x0c, 0x07, 0x08, 0x90, 0x01]);
+        let mut data = (cie.len() as u32).to_ne_bytes().to_vec();
+        data.extend(cie);
+
+        for (start, end, instructions) in fdes {
+            let mut fde = vec![];
+            // Distance from the CIE pointer back to the CIE
+            fde.extend((data.len() as u32 + 4).to_ne_bytes());
+            fde.extend(start.to_ne_bytes());
+            fde.extend((end - start).to_ne_bytes());
+            fde.extend(*instructions);
+            data.extend((fde.len() as u32).to_ne_bytes());
+            data.extend(fde);
+        }
+        data.extend(0u32.to_ne_bytes());
+
+        return data;
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn module(eh_frame: Vec<u8>) -> Module {
+        return Module {
+            eh_frame,
+            eh_frame_address: 0,
+            eh_frame_hdr: vec![],
+            eh_frame_hdr_address: 0,
+            text_address: 0,
+            segments: vec![],
+        };
+    }
+
+    fn registers(ip: u64, sp: u64) -> Registers {
+        let mut regs = Registers {
+            ip,
+            values: [None; MAX_REGISTERS],
+        };
+        regs.values[SP_REGISTER as usize] = Some(sp);
+        return regs;
+    }
+
+    #[test]
+    fn stack_reads_stay_in_bounds() {
+        let data: Vec<u8> = (0..16).collect();
+        let stack = Stack {
+            base: 0x1000,
+            data: &data,
+        };
+        assert_eq!(
+            stack.read(0x1008),
+            Some(u64::from_ne_bytes(data[8..].try_into().unwrap()))
+        );
+        assert_eq!(stack.read(0x1009), None);
+        assert_eq!(stack.read(0xfff), None);
+        assert_eq!(stack.read(u64::MAX), None);
+    }
+
+    #[test]
+    fn untracked_registers_read_as_none() {
+        let regs = registers(0, 0x1000);
+        assert_eq!(regs.get(SP_REGISTER), Some(0x1000));
+        assert_eq!(regs.get(MAX_REGISTERS as u16), None);
+        assert_eq!(regs.get(u16::MAX), None);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn steps_to_the_caller() {
+        // DW_CFA_def_cfa r40 + 8, a register the unwinder does not track
+        let module = module(eh_frame(&[
+            (0x1000, 0x1100, &[]),
+            (0x2000, 0x2100, &[0x0c, 40, 0x08]),
+        ]));
+
+        let sp = 0x7fff_0000;
+        let mut data = 0x4242u64.to_ne_bytes().to_vec();
+        data.extend([0; 8]);
+        let stack = Stack {
+            base: sp,
+            data: &data,
+        };
+
+        let caller = module.step(0x1010, &registers(0x1010, sp), &stack).unwrap();
+        assert_eq!(caller.ip, 0x4242);
+        assert_eq!(caller.get(SP_REGISTER), Some(sp + 8));
+
+        assert!(module
+            .step(0x2010, &registers(0x2010, sp), &stack)
+            .is_none());
+        // No FDE covers the address
+        assert!(module
+            .step(0x3000, &registers(0x3000, sp), &stack)
+            .is_none());
+        // The return address is not on the copied stack
+        let short = Stack {
+            base: sp,
+            data: &data[..4],
+        };
+        assert!(module
+            .step(0x1010, &registers(0x1010, sp), &short)
+            .is_none());
+    }
+}
@@ -0,0 +1,17 @@
+// Counters cannot be opened under a strict perf_event_paranoid or in most
+// containers, tests that need them return early there
+macro_rules! or_skip {
+    ($result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(pmu::Error::PermissionDenied(paranoid)) => {
+                eprintln!(
+                    "skipped, permission denied (perf_event_paranoid {:?})",
+                    paranoid
+                );
+                return;
+            }
+            Err(err) => panic!("{}", err),
+        }
+    };
+}
@@ -0,0 +1,25 @@
+#![cfg(target_os = "linux")]
+
+#[macro_use]
+mod common;
+
+#[test]
+fn iter_cpu_yields_counters_that_never_ran_there() {
+    let mut builder = or_skip!(pmu::Builder::try_new());
+    builder.add_events("task-clock,context-switches").unwrap();
+
+    // Counters follow the current thread, none is bound to CPU 0
+    let mut counters = or_skip!(builder.build());
+    counters.start().unwrap();
+    counters.stop().unwrap();
+
+    assert_eq!(counters.iter().count(), 2);
+
+    let values: Vec<pmu::CounterValue> = counters.iter_cpu(0).collect();
+    assert_eq!(values.len(), 2);
+    for value in values {
+        assert_eq!(value.cpu, Some(0));
+        assert_eq!(value.raw, 0);
+        assert!(value.never_scheduled());
+    }
+}
@@ -0,0 +1,126 @@
+use pmu::{CpuInfo, ProcessorFamily};
+
+fn brand_leaves(brand: &str) -> Vec<[u32; 4]> {
+    let mut bytes = brand.as_bytes().to_vec();
+    bytes.resize(48, 0);
+
+    return bytes
+        .chunks(16)
+        .map(|leaf| {
+            let mut registers = [0u32; 4];
+            for (idx, register) in registers.iter_mut().enumerate() {
+                *register = u32::from_le_bytes(leaf[idx * 4..idx * 4 + 4].try_into().unwrap());
+            }
+            registers
+        })
+        .collect();
+}
+
+fn vendor_leaf(max_leaf: u32, vendor: &str) -> [u32; 4] {
+    let word = |idx: usize| {
+        u32::from_le_bytes(vendor.as_bytes()[idx * 4..idx * 4 + 4].try_into().unwrap())
+    };
+
+    return [max_leaf, word(0), word(2), word(1)];
+}
+
+#[test]
+fn decodes_intel_cpuid() {
+    let brand = brand_leaves("Intel(R) Core(TM) i7-6700K CPU @ 4.00GHz");
+    let cpuid = |leaf: u32, _subleaf: u32| -> [u32; 4] {
+        return match leaf {
+            0x0 => vendor_leaf(0x16, "GenuineIntel"),
+            0x1 => [0x000506e3, 0, 0, 0],
+            // Version 4, 4 counters of 48 bits, 3 fixed counters
+            0xa => [0x07300404, 0, 0, 0x603],
+            0x80000000 => [0x80000008, 0, 0, 0],
+            0x80000002..=0x80000004 => brand[(leaf - 0x80000002) as usize],
+            _ => [0; 4],
+        };
+    };
+
+    let info = CpuInfo::from_x86_cpuid(&cpuid);
+    assert_eq!(info.vendor, "GenuineIntel");
+    assert_eq!((info.family, info.model, info.stepping), (6, 0x5e, 3));
+    assert_eq!(info.brand, "Intel(R) Core(TM) i7-6700K CPU @ 4.00GHz");
+    assert_eq!(info.processor_family, ProcessorFamily::IntelSkylake);
+    assert_eq!(info.general_counters, Some(4));
+    assert_eq!(info.fixed_counters, Some(3));
+    assert_eq!(info.counter_width, Some(48));
+}
+
+#[test]
+fn decodes_amd_cpuid() {
+    let cpuid = |leaf: u32, _subleaf: u32| -> [u32; 4] {
+        return match leaf {
+            0x0 => vendor_leaf(0x10, "AuthenticAMD"),
+            0x1 => [0x00a20f10, 0, 0, 0],
+            0x80000000 => [0x80000021, 0, 0, 0],
+            // PerfCtrExtCore
+            0x80000001 => [0, 0, 1 << 23, 0],
+            _ => [0; 4],
+        };
+    };
+
+    let info = CpuInfo::from_x86_cpuid(&cpuid);
+    assert_eq!(info.vendor, "AuthenticAMD");
+    assert_eq!((info.family, info.model, info.stepping), (0x19, 0x21, 0));
+    assert_eq!(info.processor_family, ProcessorFamily::AmdZen3);
+    assert_eq!(info.general_counters, Some(6));
+    assert_eq!(info.fixed_counters, Some(0));
+}
+
+#[test]
+fn decodes_cpuinfo() {
+    let info = CpuInfo::from_cpuinfo(
+        "processor\t: 0\nvendor_id\t: GenuineIntel\ncpu family\t: 6\nmodel\t\t: 151\nmodel name\t: 12th Gen Intel(R) Core(TM) i9-12900K\nstepping\t: 2\n",
+    );
+    assert_eq!(info.vendor, "GenuineIntel");
+    assert_eq!((info.family, info.model, info.stepping), (6, 151, 2));
+    assert_eq!(info.brand, "12th Gen Intel(R) Core(TM) i9-12900K");
+    assert_eq!(info.processor_family, ProcessorFamily::IntelAlderLake);
+
+    let info = CpuInfo::from_cpuinfo(
+        "processor\t: 0\nCPU implementer\t: 0x41\nCPU architecture: 8\nCPU variant\t: 0x3\nCPU part\t: 0xd0c\nCPU revision\t: 1\n",
+    );
+    assert_eq!(info.vendor, "ARM");
+    assert_eq!((info.family, info.model, info.stepping), (8, 0xd0c, 0x31));
+    assert_eq!(info.processor_family, ProcessorFamily::ArmNeoverseN1);
+    assert_eq!(info.general_counters, None);
+
+    let info = CpuInfo::from_cpuinfo(
+        "processor\t: 0\nuarch\t\t: sifive,u74-mc\nmvendorid\t: 0x489\nmarchid\t\t: 0x8000000000000007\nmimpid\t\t: 0x4210427\n",
+    );
+    assert_eq!(info.vendor, "SiFive");
+    assert_eq!((info.model, info.stepping), (0x8000000000000007, 0x4210427));
+    assert_eq!(info.brand, "sifive,u74-mc");
+    assert_eq!(info.processor_family, ProcessorFamily::SiFiveU7);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn reads_sysfs_topology() {
+    let root = std::env::temp_dir().join(format!("libpmu-topology-{}", std::process::id()));
+    // Two sockets with two cores of two threads each
+    for cpu in 0..8 {
+        let topology = root.join(format!("cpu{}", cpu)).join("topology");
+        std::fs::create_dir_all(&topology).unwrap();
+        std::fs::write(
+            topology.join("physical_package_id"),
+            format!("{}\n", cpu / 4),
+        )
+        .unwrap();
+        std::fs::write(topology.join("core_id"), format!("{}\n", (cpu / 2) % 2)).unwrap();
+    }
+    std::fs::write(root.join("online"), "0-7\n").unwrap();
+
+    let topology = pmu::CpuTopology::from_sysfs(&root);
+    std::fs::remove_dir_all(&root).unwrap();
+
+    let topology = topology.unwrap();
+    assert_eq!(
+        (topology.logical_cpus, topology.cores, topology.sockets),
+        (8, 4, 2)
+    );
+    assert_eq!(topology.threads_per_core(), 2);
+}
@@ -0,0 +1,33 @@
+use pmu::ProcessorFamily;
+
+// The override is process-wide, so it is only touched by this one test
+#[test]
+fn environment_overrides_detection() {
+    let detected = pmu::get_processor_family();
+
+    std::env::set_var("LIBPMU_PROCESSOR_FAMILY", "AmdZen2");
+    assert_eq!(pmu::get_processor_family(), ProcessorFamily::AmdZen2);
+    assert_eq!(
+        pmu::try_get_processor_family().unwrap(),
+        ProcessorFamily::AmdZen2
+    );
+    // Reports keep the hardware that actually ran
+    let info = pmu::get_cpu_info();
+    assert_eq!(info.family_override, Some(ProcessorFamily::AmdZen2));
+    assert_eq!(info.processor_family, detected);
+
+    std::env::set_var("LIBPMU_PROCESSOR_FAMILY", "NotAFamily");
+    assert!(matches!(
+        pmu::try_get_processor_family(),
+        Err(pmu::Error::InvalidConfiguration(_))
+    ));
+    assert!(matches!(
+        pmu::Builder::try_new(),
+        Err(pmu::Error::InvalidConfiguration(_))
+    ));
+    assert_eq!(pmu::get_processor_family(), detected);
+    assert_eq!(pmu::get_cpu_info().family_override, None);
+
+    std::env::remove_var("LIBPMU_PROCESSOR_FAMILY");
+    assert_eq!(pmu::get_processor_family(), detected);
+}
@@ -0,0 +1,71 @@
+#![cfg(target_os = "linux")]
+
+#[macro_use]
+mod common;
+
+use std::process::{Command, Stdio};
+
+#[test]
+fn counts_launched_command_from_exec() {
+    let held = std::time::Duration::from_millis(200);
+
+    let mut builder = or_skip!(pmu::Builder::try_new());
+    builder.add_events("task-clock").unwrap();
+    builder.launch(Command::new("true"));
+
+    // The child is held on its release pipe before exec, time that would be
+    // enabled if counting started at fork
+    let mut counters = or_skip!(builder.build());
+    std::thread::sleep(held);
+    counters.start().unwrap();
+    assert!(counters.wait().unwrap().success());
+    counters.stop().unwrap();
+
+    let value = counters.iter().next().unwrap();
+    assert!(value.raw > 0);
+    assert!(
+        value.time_enabled < held.as_nanos() as u64,
+        "{}",
+        value.time_enabled
+    );
+}
+
+#[test]
+fn launched_command_keeps_its_stdio() {
+    let path = std::env::temp_dir().join(format!("libpmu-launch-{}", std::process::id()));
+    let mut command = Command::new("sh");
+    command
+        .args(["-c", "echo launched"])
+        .stdin(Stdio::null())
+        .stdout(std::fs::File::create(&path).unwrap());
+
+    let mut builder = or_skip!(pmu::Builder::try_new());
+    builder.add_events("task-clock").unwrap();
+    builder.launch(command);
+    let mut counters = or_skip!(builder.build());
+    counters.start().unwrap();
+    assert!(counters.wait().unwrap().success());
+
+    let output = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output, "launched\n");
+}
+
+#[test]
+fn failed_exec_is_reported_by_start() {
+    let mut builder = or_skip!(pmu::Builder::try_new());
+    builder.add_events("task-clock").unwrap();
+    builder.launch(Command::new("/nonexistent/libpmu-test"));
+    let mut counters = or_skip!(builder.build());
+    assert!(counters.start().is_err());
+    assert!(builder.build().is_err());
+}
+
+#[test]
+fn unreleased_command_is_reaped_on_drop() {
+    let mut builder = or_skip!(pmu::Builder::try_new());
+    builder.add_events("task-clock").unwrap();
+    builder.launch(Command::new("true"));
+    // Returns only once the held child has been reaped
+    drop(or_skip!(builder.build()));
+}
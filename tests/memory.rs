@@ -0,0 +1,55 @@
+use pmu::{MemoryDataSource, MemoryLevel, MemoryOp, SnoopResult};
+
+// Packs the fields of perf_mem_data_src
+fn data_src(op: u64, lvl: u64, snoop: u64, lock: u64, dtlb: u64, lvl_num: u64, remote: u64) -> u64 {
+    return op
+        | (lvl << 5)
+        | (snoop << 19)
+        | (lock << 24)
+        | (dtlb << 26)
+        | (lvl_num << 33)
+        | (remote << 37);
+}
+
+#[test]
+fn decodes_l1_hit() {
+    // LOAD, HIT|L1, SNOOP_NONE, DTLB HIT|L1, LVLNUM_L1
+    let source = MemoryDataSource::decode(data_src(0x2, 0x2 | 0x8, 0x2, 0x1, 0x2 | 0x8, 0x1, 0));
+    assert_eq!(source.op, MemoryOp::Load);
+    assert_eq!(source.level, MemoryLevel::L1);
+    assert_eq!(source.hit, Some(true));
+    assert_eq!(source.snoop, SnoopResult::None);
+    assert!(!source.locked);
+    assert!(!source.remote);
+    assert_eq!(source.tlb.hit, Some(true));
+    assert!(source.tlb.l1);
+}
+
+#[test]
+fn decodes_remote_ram() {
+    // Only the legacy REM_RAM1 bit, as reported by older kernels
+    let source = MemoryDataSource::decode(data_src(0x2, 0x2 | 0x100, 0x8, 0x2, 0x4 | 0x20, 0, 0));
+    assert_eq!(source.level, MemoryLevel::Ram);
+    assert!(source.remote);
+    assert_eq!(source.snoop, SnoopResult::Miss);
+    assert!(source.locked);
+    assert_eq!(source.tlb.hit, Some(false));
+    assert!(source.tlb.walker);
+
+    // LVLNUM_RAM with the remote bit
+    let source = MemoryDataSource::decode(data_src(0x4, 0x2, 0x10, 0, 0, 0xd, 1));
+    assert_eq!(source.op, MemoryOp::Store);
+    assert_eq!(source.level, MemoryLevel::Ram);
+    assert!(source.remote);
+    assert_eq!(source.snoop, SnoopResult::HitModified);
+}
+
+#[test]
+fn decodes_unknown_source() {
+    let source = MemoryDataSource::decode(data_src(0x1, 0x1, 0x1, 0x1, 0x1, 0xf, 0));
+    assert_eq!(source.op, MemoryOp::Unknown);
+    assert_eq!(source.level, MemoryLevel::Unknown);
+    assert_eq!(source.hit, None);
+    assert_eq!(source.snoop, SnoopResult::Unknown);
+    assert_eq!(source.tlb.hit, None);
+}
@@ -0,0 +1,235 @@
+use pmu::ProcessorFamily;
+
+// CPUID leaf 1 EAX from its display family and model, the way the kernel
+// shows them in /proc/cpuinfo
+fn x86_signature(family: u32, model: u32) -> u32 {
+    let (base_family, extended_family) = if family >= 0xf {
+        (0xf, family - 0xf)
+    } else {
+        (family, 0)
+    };
+
+    return (extended_family << 20)
+        | ((model >> 4) << 16)
+        | (base_family << 8)
+        | ((model & 0xf) << 4);
+}
+
+const X86_CASES: &[(u32, u32, ProcessorFamily)] = &[
+    // AMD Family 17h
+    (0x17, 0x01, ProcessorFamily::AmdZen1),
+    (0x17, 0x11, ProcessorFamily::AmdZen1),
+    (0x17, 0x20, ProcessorFamily::AmdZen1),
+    (0x17, 0x31, ProcessorFamily::AmdZen2),
+    (0x17, 0x47, ProcessorFamily::AmdZen2),
+    (0x17, 0x60, ProcessorFamily::AmdZen2),
+    (0x17, 0x71, ProcessorFamily::AmdZen2),
+    (0x17, 0x90, ProcessorFamily::AmdZen2),
+    (0x17, 0x50, ProcessorFamily::Unknown),
+    // AMD Family 19h
+    (0x19, 0x01, ProcessorFamily::AmdZen3),
+    (0x19, 0x21, ProcessorFamily::AmdZen3),
+    (0x19, 0x44, ProcessorFamily::AmdZen3),
+    (0x19, 0x50, ProcessorFamily::AmdZen3),
+    (0x19, 0x11, ProcessorFamily::AmdZen4),
+    (0x19, 0x61, ProcessorFamily::AmdZen4),
+    (0x19, 0x74, ProcessorFamily::AmdZen4),
+    (0x19, 0x30, ProcessorFamily::Unknown),
+    (0x1a, 0x24, ProcessorFamily::Unknown),
+    // Intel Family 6
+    (0x6, 0x3c, ProcessorFamily::IntelHaswell),
+    (0x6, 0x45, ProcessorFamily::IntelHaswell),
+    (0x6, 0x46, ProcessorFamily::IntelHaswell),
+    (0x6, 0x3d, ProcessorFamily::IntelBroadwell),
+    (0x6, 0x47, ProcessorFamily::IntelBroadwell),
+    (0x6, 0x5e, ProcessorFamily::IntelSkylake),
+    (0x6, 0x4e, ProcessorFamily::IntelSkylake),
+    (0x6, 0x8e, ProcessorFamily::IntelKabyLake),
+    (0x6, 0x9e, ProcessorFamily::IntelKabyLake),
+    (0x6, 0xa5, ProcessorFamily::IntelCometLake),
+    (0x6, 0x7e, ProcessorFamily::IntelIceLakeClient),
+    (0x6, 0x6c, ProcessorFamily::IntelIceLakeServer),
+    (0x6, 0x6a, ProcessorFamily::IntelIceLakeServer),
+    (0x6, 0x8c, ProcessorFamily::IntelTigerLake),
+    (0x6, 0x8d, ProcessorFamily::IntelTigerLake),
+    (0x6, 0xa7, ProcessorFamily::IntelRocketLake),
+    (0x6, 0x97, ProcessorFamily::IntelAlderLake),
+    (0x6, 0x9a, ProcessorFamily::IntelAlderLake),
+    (0x6, 0xb7, ProcessorFamily::IntelRaptorLake),
+    (0x6, 0xba, ProcessorFamily::IntelRaptorLake),
+    (0x6, 0x1a, ProcessorFamily::Unknown),
+    (0x5, 0x02, ProcessorFamily::Unknown),
+];
+
+const MIDR_CASES: &[(u64, ProcessorFamily)] = &[
+    (0x410fd083, ProcessorFamily::ArmCortexA72),
+    (0x410fd092, ProcessorFamily::ArmCortexA73),
+    (0x412fd0a1, ProcessorFamily::ArmCortexA75),
+    (0x414fd0b1, ProcessorFamily::ArmCortexA76),
+    (0x411fd0d0, ProcessorFamily::ArmCortexA77),
+    (0x411fd410, ProcessorFamily::ArmCortexA78),
+    (0x413fd0c1, ProcessorFamily::ArmNeoverseN1),
+    (0x410fd490, ProcessorFamily::ArmNeoverseN2),
+    (0x411fd401, ProcessorFamily::ArmNeoverseV1),
+    (0x410fd4f0, ProcessorFamily::ArmNeoverseV2),
+    (0xc00fac30, ProcessorFamily::AmpereOne),
+    (0xc00fac41, ProcessorFamily::AmpereOne),
+    (0x410fd034, ProcessorFamily::Unknown),
+    (0x510f8000, ProcessorFamily::Unknown),
+];
+
+const CPUINFO_CASES: &[(&str, ProcessorFamily)] = &[
+    (
+        "processor\t: 0\nvendor_id\t: GenuineIntel\ncpu family\t: 6\nmodel\t\t: 151\nstepping\t: 2\n",
+        ProcessorFamily::IntelAlderLake,
+    ),
+    (
+        "processor\t: 0\nvendor_id\t: AuthenticAMD\ncpu family\t: 25\nmodel\t\t: 33\n",
+        ProcessorFamily::AmdZen3,
+    ),
+    (
+        "processor\t: 0\nvendor_id\t: AuthenticAMD\ncpu family\t: 23\nmodel\t\t: 49\n",
+        ProcessorFamily::AmdZen2,
+    ),
+    (
+        "processor\t: 0\nBogoMIPS\t: 50.00\nCPU implementer\t: 0x41\nCPU architecture: 8\nCPU variant\t: 0x3\nCPU part\t: 0xd0c\nCPU revision\t: 1\n",
+        ProcessorFamily::ArmNeoverseN1,
+    ),
+    (
+        "processor\t: 0\nhart\t\t: 0\nisa\t\t: rv64imafdc\nmmu\t\t: sv39\nuarch\t\t: sifive,u74-mc\nmvendorid\t: 0x489\nmarchid\t\t: 0x8000000000000007\n",
+        ProcessorFamily::SiFiveU7,
+    ),
+    (
+        "processor\t: 0\nmarchid\t\t: 0x0\n",
+        ProcessorFamily::Unknown,
+    ),
+    // Only the first processor is decoded
+    (
+        "\nprocessor\t: 0\ncpu family\t: 6\nmodel\t\t: 94\n\nprocessor\t: 1\ncpu family\t: 6\nmodel\t\t: 151\n",
+        ProcessorFamily::IntelSkylake,
+    ),
+    ("", ProcessorFamily::Unknown),
+];
+
+#[test]
+fn decodes_x86_cpuid() {
+    for (family, model, expected) in X86_CASES {
+        let eax = x86_signature(*family, *model);
+        assert_eq!(
+            ProcessorFamily::from_x86_cpuid(eax),
+            *expected,
+            "family {:#x} model {:#x} (eax {:#x})",
+            family,
+            model,
+            eax
+        );
+    }
+}
+
+#[test]
+fn ignores_x86_stepping() {
+    let eax = x86_signature(0x6, 0x5e);
+    for stepping in 0..0x10 {
+        assert_eq!(
+            ProcessorFamily::from_x86_cpuid(eax | stepping),
+            ProcessorFamily::IntelSkylake
+        );
+    }
+}
+
+#[test]
+fn decodes_midr() {
+    for (midr, expected) in MIDR_CASES {
+        assert_eq!(
+            ProcessorFamily::from_midr(*midr),
+            *expected,
+            "midr {:#x}",
+            midr
+        );
+    }
+}
+
+#[test]
+fn decodes_riscv_marchid() {
+    assert_eq!(
+        ProcessorFamily::from_riscv_marchid(0x8000000000000007),
+        ProcessorFamily::SiFiveU7
+    );
+    assert_eq!(
+        ProcessorFamily::from_riscv_marchid(0x8000000000000008),
+        ProcessorFamily::Unknown
+    );
+}
+
+#[test]
+fn decodes_cpuinfo() {
+    for (cpuinfo, expected) in CPUINFO_CASES {
+        assert_eq!(
+            ProcessorFamily::from_cpuinfo(cpuinfo),
+            *expected,
+            "{:?}",
+            cpuinfo
+        );
+    }
+}
+
+#[test]
+fn every_family_is_decodable() {
+    for family in ProcessorFamily::all() {
+        let decoded = X86_CASES.iter().any(|(_, _, expected)| *expected == family)
+            || MIDR_CASES.iter().any(|(_, expected)| *expected == family)
+            || CPUINFO_CASES
+                .iter()
+                .any(|(_, expected)| *expected == family);
+        assert!(decoded, "{} is not covered", family.name());
+    }
+}
+
+#[test]
+fn names_round_trip() {
+    for family in ProcessorFamily::all() {
+        assert_eq!(ProcessorFamily::from_name(family.name()), Some(family));
+        assert_eq!(
+            ProcessorFamily::from_name(&family.name().to_lowercase()),
+            Some(family)
+        );
+    }
+    assert_eq!(ProcessorFamily::from_name("IntelPentium4"), None);
+}
+
+#[test]
+fn family_selects_event_tables() {
+    let skylake = pmu::list_events_for_family(&ProcessorFamily::IntelSkylake);
+    assert!(skylake.iter().any(|e| e.name == "INST_RETIRED.ANY_P"));
+    assert!(!skylake.iter().any(|e| e.name == "ex_ret_instr"));
+
+    let zen3 = pmu::list_events_for_family(&ProcessorFamily::AmdZen3);
+    assert!(zen3.iter().any(|e| e.name == "ex_ret_instr"));
+}
+
+#[test]
+fn imported_events_keep_counter_restrictions() {
+    let skylake = pmu::list_events_for_family(&ProcessorFamily::IntelSkylake);
+    let event = skylake
+        .iter()
+        .find(|e| e.name == "INST_RETIRED.ANY_P")
+        .unwrap();
+    assert_eq!(event.counters, Some(0xf));
+
+    // The Zen 2 tables do not list counters
+    let zen2 = pmu::list_events_for_family(&ProcessorFamily::AmdZen2);
+    let event = zen2.iter().find(|e| e.name == "ex_ret_instr").unwrap();
+    assert_eq!(event.counters, None);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn builder_uses_family_override() {
+    let mut builder = pmu::Builder::try_new().unwrap();
+    builder.set_processor_family(ProcessorFamily::AmdZen3);
+    assert!(builder.add_events("ex_ret_instr:u").is_ok());
+
+    let mut builder = pmu::Builder::try_new().unwrap();
+    builder.set_processor_family(ProcessorFamily::IntelSkylake);
+    assert!(builder.add_events("ex_ret_instr:u").is_err());
+}
@@ -0,0 +1,29 @@
+#[cfg(target_os = "linux")]
+#[inline(never)]
+fn symbolized_function() -> u64 {
+    return std::hint::black_box(42);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn resolves_own_functions() {
+    let mut symbolizer = pmu::Symbolizer::new();
+    let pid = std::process::id();
+    let address = symbolized_function as *const () as u64;
+
+    let symbol = symbolizer.symbolize(pid, address + 1).unwrap();
+    assert!(
+        symbol.name.ends_with("symbolized_function"),
+        "{}",
+        symbol.name
+    );
+    assert_eq!(symbol.offset, 1);
+    assert_eq!(
+        symbol.module,
+        std::env::current_exe().unwrap().to_string_lossy()
+    );
+    assert!(symbol.to_string().contains("symbolized_function+0x1"));
+    assert!(symbolizer.comm(pid).is_some());
+
+    assert!(symbolizer.symbolize(pid, 0).is_none());
+}
@@ -0,0 +1,80 @@
+use pmu::{ProcessorFamily, TopDown, TopDownLevel};
+
+fn assert_close(actual: f64, expected: f64) {
+    assert!(
+        (actual - expected).abs() < 1e-9,
+        "{} != {}",
+        actual,
+        expected
+    );
+}
+
+#[test]
+fn computes_level1_fractions() {
+    // slots, retiring, bad-spec, fe-bound, be-bound
+    let topdown = TopDown::from_values(TopDownLevel::Level1, &[1000, 400, 100, 200, 300]).unwrap();
+    assert_close(topdown.retiring, 0.4);
+    assert_close(topdown.bad_speculation, 0.1);
+    assert_close(topdown.frontend_bound, 0.2);
+    assert_close(topdown.backend_bound, 0.3);
+    assert!(topdown.level2.is_none());
+
+    let topdown = TopDown::from_values(TopDownLevel::Level1, &[0, 0, 0, 0, 0]).unwrap();
+    assert!(topdown.retiring.is_nan());
+}
+
+#[test]
+fn computes_level2_fractions() {
+    // Level 1 followed by heavy-ops, br-mispredict, fetch-lat and mem-bound
+    let topdown = TopDown::from_values(
+        TopDownLevel::Level2,
+        &[1000, 400, 100, 200, 300, 150, 80, 120, 250],
+    )
+    .unwrap();
+    let level2 = topdown.level2.unwrap();
+    assert_close(level2.heavy_operations, 0.15);
+    assert_close(level2.light_operations, 0.25);
+    assert_close(level2.branch_mispredicts, 0.08);
+    assert_close(level2.machine_clears, 0.02);
+    assert_close(level2.fetch_latency, 0.12);
+    assert_close(level2.fetch_bandwidth, 0.08);
+    assert_close(level2.memory_bound, 0.25);
+    assert_close(level2.core_bound, 0.05);
+}
+
+#[test]
+fn rejects_values_of_another_level() {
+    assert!(TopDown::from_values(TopDownLevel::Level1, &[1000, 400, 100, 200]).is_none());
+    assert!(TopDown::from_values(TopDownLevel::Level1, &[]).is_none());
+    assert!(TopDown::from_values(TopDownLevel::Level2, &[1000, 400, 100, 200, 300]).is_none());
+    assert!(TopDown::from_values(
+        TopDownLevel::Level1,
+        &[1000, 400, 100, 200, 300, 150, 80, 120, 250]
+    )
+    .is_none());
+}
+
+#[test]
+fn levels_follow_family() {
+    assert_eq!(pmu::max_topdown_level(&ProcessorFamily::IntelSkylake), None);
+    assert_eq!(
+        pmu::max_topdown_level(&ProcessorFamily::IntelIceLakeClient),
+        Some(TopDownLevel::Level1)
+    );
+    assert_eq!(
+        pmu::max_topdown_level(&ProcessorFamily::IntelAlderLake),
+        Some(TopDownLevel::Level2)
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn builder_rejects_unsupported_levels() {
+    let mut builder = pmu::Builder::try_new().unwrap();
+    builder.set_processor_family(ProcessorFamily::IntelSkylake);
+    assert!(builder.add_topdown(TopDownLevel::Level1).is_err());
+
+    let mut builder = pmu::Builder::try_new().unwrap();
+    builder.set_processor_family(ProcessorFamily::IntelTigerLake);
+    assert!(builder.add_topdown(TopDownLevel::Level2).is_err());
+}